@@ -0,0 +1,118 @@
+//! Derive macros for Kay, see `kay::Portable`
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Ident};
+
+/// Implement `kay::Portable` by encoding all fields one after the other.
+/// Enums are prefixed with the index of their variant as a single byte.
+/// All fields need to be `Portable` themselves.
+#[proc_macro_derive(Portable)]
+pub fn derive_portable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::kay::Portable));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let (encode, decode, min_encoded_size) = match input.data {
+        Data::Struct(ref data) => {
+            let (pattern, encode_fields) = destructure(quote!(#name), &data.fields);
+            let construct = construct(quote!(#name), &data.fields);
+            let field_types = data.fields.iter().map(|field| &field.ty);
+            (
+                quote! { match *self { #pattern => { #encode_fields } } },
+                quote! { Ok(#construct) },
+                quote! { 0 #(+ <#field_types as ::kay::Portable>::MIN_ENCODED_SIZE)* },
+            )
+        }
+        Data::Enum(ref data) => {
+            if data.variants.len() > 256 {
+                return syn::Error::new_spanned(name, "Portable enums can have at most 256 variants")
+                    .to_compile_error()
+                    .into();
+            }
+            let mut encode_arms = Vec::new();
+            let mut decode_arms = Vec::new();
+            for (index, variant) in data.variants.iter().enumerate() {
+                let index = index as u8;
+                let variant_name = &variant.ident;
+                let (pattern, encode_fields) = destructure(quote!(#name::#variant_name), &variant.fields);
+                let construct = construct(quote!(#name::#variant_name), &variant.fields);
+                encode_arms.push(quote! { #pattern => { out.push(#index); #encode_fields } });
+                decode_arms.push(quote! { #index => Ok(#construct) });
+            }
+            (
+                quote! { match *self { #(#encode_arms)* } },
+                quote! {
+                    match <u8 as ::kay::Portable>::decode(reader)? {
+                        #(#decode_arms,)*
+                        _ => Err(::kay::WireError::Invalid(concat!(stringify!(#name), " variant"))),
+                    }
+                },
+                // at least the variant index
+                quote! { 1 },
+            )
+        }
+        Data::Union(_) => {
+            return syn::Error::new_spanned(name, "Unions can't be Portable")
+                .to_compile_error()
+                .into();
+        }
+    };
+
+    let expanded = quote! {
+        impl #impl_generics ::kay::Portable for #name #ty_generics #where_clause {
+            const MIN_ENCODED_SIZE: usize = #min_encoded_size;
+
+            #[allow(unused_variables)]
+            fn encode(&self, out: &mut Vec<u8>) {
+                #encode
+            }
+
+            #[allow(unused_variables)]
+            fn decode(reader: &mut ::kay::WireReader) -> Result<Self, ::kay::WireError> {
+                #decode
+            }
+        }
+    };
+    expanded.into()
+}
+
+/// A pattern binding all fields by reference, and the statements encoding them
+fn destructure(path: TokenStream2, fields: &Fields) -> (TokenStream2, TokenStream2) {
+    let bindings: Vec<Ident> = (0..fields.len())
+        .map(|index| Ident::new(&format!("field_{}", index), Span::call_site()))
+        .collect();
+    let pattern = match fields {
+        Fields::Named(ref named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote! { #path { #(#names: ref #bindings),* } }
+        }
+        Fields::Unnamed(_) => quote! { #path ( #(ref #bindings),* ) },
+        Fields::Unit => quote! { #path },
+    };
+    let encode_fields = quote! { #(::kay::Portable::encode(#bindings, out);)* };
+    (pattern, encode_fields)
+}
+
+/// An expression decoding all fields in order
+fn construct(path: TokenStream2, fields: &Fields) -> TokenStream2 {
+    match fields {
+        Fields::Named(ref named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote! { #path { #(#names: ::kay::Portable::decode(reader)?),* } }
+        }
+        Fields::Unnamed(ref unnamed) => {
+            let decode_fields = unnamed.unnamed.iter().map(|_| quote! { ::kay::Portable::decode(reader)? });
+            quote! { #path ( #(#decode_fields),* ) }
+        }
+        Fields::Unit => quote! { #path },
+    }
+}
@@ -21,6 +21,9 @@ pub trait Actor: Compact + StorageAware + 'static {
     /// Get the ID referring to this actor instace
     fn id(&self) -> Self::ID;
     /// Set the ID of this actor instance - only to be used by the system itself
+    ///
+    /// # Safety
+    /// Changing the ID of a live instance breaks all references to it
     unsafe fn set_id(&mut self, id: RawID);
 
     /// Convert the ID of this instance into an actor trait ID.
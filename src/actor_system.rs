@@ -1,89 +1,316 @@
 use crate::actor::{Actor, ActorOrActorTrait};
-use crate::class::{Class, ActorVTable};
-use crate::id::{MachineID, RawID};
+use byteorder::{ByteOrder, LittleEndian};
+use compact::COption;
+use crate::class::{allocate_id_in, Class, ActorVTable, InstanceStore, MessageHandler, PanicPolicy, SlotMap};
+use crate::class::outbox;
+use crate::dead_letter::{DeadLetter, DeadLetterReason};
+use crate::handshake::TypeMismatch;
+use crate::id::{MachineID, RawID, TypedID};
+use crate::loop_detector::{LoopDetector, MessageLoop};
 use crate::messaging::{Fate, Message, Packet};
+use crate::monitor::{Down, DownReason, Monitor};
+use crate::network_observer::NetworkObserverID;
 use crate::networking::Networking;
-use crate::type_registry::{ShortTypeId, TypeRegistry};
+use crate::panic_report::PanicReport;
+use crate::processing::{ProcessingBudget, ProcessingReport};
+use crate::reconnect::ConnectionState;
+use crate::request::{Gather, Gathered, GatheredParts, PendingRequests, Reduced, Request, RequestID, RequestTimeout, Response};
+use crate::supervision::{RestartFn, RestartIntensityExceeded, Supervision};
+use crate::system_handle::{OutsideOfActorSystem, Registries, SharedState, SystemHandle};
+use crate::thread_safe_storage::ThreadSafeStorage;
+use crate::timer_wheel::TimerWheel;
+use crate::type_id_map::{DenseTypeIdMap, TypeIdCounts};
+use crate::type_registry::{ManifestError, ShortTypeId, TypeRegistry};
+use crate::wire::{aligned_copy, InvalidInput, WireCodec};
+use crate::worker_pool::WorkerPool;
 use crate::tuning::Tuning;
 
+use std::any::Any;
 use std::collections::HashMap;
-use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::rc::Rc;
-
-const MAX_RECIPIENT_TYPES: usize = 64;
-pub const MAX_MESSAGE_TYPES: usize = 256;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 /// Contains the state of a whole actor system
 /// and can be used for managing and progressing the actor system
 /// as well as for interacting with it from the outside
 pub struct ActorSystem {
-    /// Did a panic happen inside a message handler?
+    /// Did a panic happen outside of the message handlers of a class?
     /// If so, only messages marked as `Critical` are still sent.
+    /// Panics inside message handlers are handled according to the
+    /// `PanicPolicy` of their class instead (see `set_panic_policy`).
     pub panic_happened: bool,
     actor_registry: TypeRegistry,
     message_registry: TypeRegistry,
-    classes: [Option<Class>; MAX_RECIPIENT_TYPES],
-    trait_implementors: [Option<Vec<ShortTypeId>>; MAX_RECIPIENT_TYPES],
-    message_statistics: [usize; MAX_MESSAGE_TYPES],
+    classes: DenseTypeIdMap<Class>,
+    trait_implementors: DenseTypeIdMap<Vec<ShortTypeId>>,
+    message_statistics: TypeIdCounts,
     networking: Networking,
-    storage: Rc<dyn chunky::ChunkStorage>,
+    storage: Arc<dyn ThreadSafeStorage>,
+    shared: Arc<SharedState>,
+    published_n_types: (usize, usize),
+    requests: Arc<Mutex<PendingRequests>>,
+    timers: TimerWheel,
+    ticks: Vec<TickSubscription>,
+    panic_reports: Vec<PanicReport>,
+    panic_observer: Option<RawID>,
+    /// Monitors of actors on other machines, as (watcher, target)
+    remote_monitors: Vec<(RawID, RawID)>,
+    dead_letter_counts: TypeIdCounts,
+    dead_letter_handler: Option<RawID>,
+    /// The class whose handlers are currently sending messages, if any
+    current_sender: Option<ShortTypeId>,
+    loop_detector: LoopDetector,
+    message_loops: Vec<MessageLoop>,
+    phases: Vec<Phase>,
+    /// All classes in the order they are processed in
+    class_order: Rc<Vec<ShortTypeId>>,
+    /// The last networking turn for which ticks, scheduled messages
+    /// and request timeouts were dealt with
+    started_turn: Option<usize>,
+    /// Only exists with more than one `Tuning::n_worker_threads`
+    worker_pool: Option<WorkerPool>,
     tuning: Tuning
 }
 
+const ACTOR_MANIFEST_HEADER: &str = "[actors]";
+const MESSAGE_MANIFEST_HEADER: &str = "[messages]";
+
+/// A named group of actor classes that are processed together
+struct Phase {
+    name: String,
+    class_ids: Vec<ShortTypeId>,
+}
+
+/// A message that is dispatched to all local instances of a class every few turns
+struct TickSubscription {
+    class_id: ShortTypeId,
+    every_n_turns: usize,
+    next_turn: usize,
+    critical: bool,
+    dispatch: Box<dyn Fn(&mut Class, &mut TypeIdCounts, &mut World)>,
+}
+
 impl ActorSystem {
     /// Create a new actor system that lives in memory only
     pub fn new(networking: Networking, tuning: Tuning) -> ActorSystem {
-        Self::new_with_storage(networking, Rc::new(chunky::HeapStorage), tuning)
+        Self::new_with_storage(networking, Arc::new(chunky::HeapStorage), tuning)
     }
 
     /// Create a new actor system that lives in memory and is persisted to disk using Mmapping
     #[cfg(feature = "server")]
     pub fn new_mmap_persisted<P: AsRef<::std::path::Path>>(networking: Networking, directory: &P, tuning: Tuning) -> ActorSystem {
-        Self::new_with_storage(networking, Rc::new(chunky::MmapStorage::new(directory.as_ref().to_owned())), tuning)
+        Self::new_with_storage(networking, Arc::new(chunky::MmapStorage::new(directory.as_ref().to_owned())), tuning)
     }
 
-    /// Create a new actor system backed by any `chunky::ChunkStorage`
-    pub fn new_with_storage(networking: Networking, storage: Rc<dyn chunky::ChunkStorage>, tuning: Tuning) -> ActorSystem {
+    /// Create a new actor system backed by any `ThreadSafeStorage`
+    pub fn new_with_storage(networking: Networking, storage: Arc<dyn ThreadSafeStorage>, tuning: Tuning) -> ActorSystem {
         ActorSystem {
             panic_happened: false,
-            trait_implementors: unsafe { make_array!(MAX_RECIPIENT_TYPES, |_| None) },
+            trait_implementors: DenseTypeIdMap::new(),
             actor_registry: TypeRegistry::new(),
             message_registry: TypeRegistry::new(),
-            classes: unsafe { make_array!(MAX_RECIPIENT_TYPES, |_| None) },
-            message_statistics: [0; MAX_MESSAGE_TYPES],
+            classes: DenseTypeIdMap::new(),
+            message_statistics: TypeIdCounts::new(),
+            shared: Arc::new(SharedState::new(networking.machine_id, &tuning)),
+            published_n_types: (0, 0),
+            requests: Arc::new(Mutex::new(PendingRequests::new(networking.machine_id))),
+            timers: TimerWheel::new(tuning.timer_wheel_slots),
+            ticks: Vec::new(),
+            panic_reports: Vec::new(),
+            panic_observer: None,
+            remote_monitors: Vec::new(),
+            dead_letter_counts: TypeIdCounts::new(),
+            dead_letter_handler: None,
+            current_sender: None,
+            loop_detector: LoopDetector::new(tuning.message_loop_threshold),
+            message_loops: Vec::new(),
+            phases: Vec::new(),
+            class_order: Rc::new(Vec::new()),
+            started_turn: None,
+            worker_pool: if tuning.n_worker_threads > 1 {
+                Some(WorkerPool::new(tuning.n_worker_threads))
+            } else {
+                None
+            },
             networking,
             storage,
             tuning
         }
     }
 
-    /// Register a new actor class with the system (assigning it a type ID)
+    /// Register a new actor class with the system (assigning it a type ID).
+    /// Panics if no type ID can be assigned to it (see `RegistrationError`).
     pub fn register<A: Actor>(&mut self) {
         // allow use of actor id before it is added
         let actor_id = self.actor_registry.get_or_register::<A>();
         // ...but still make sure it is only added once
-        assert!(self.classes[actor_id.as_usize()].is_none());
+        assert!(!self.classes.contains(actor_id));
         // Store pointer to the actor
-        let class = Class::new(ActorVTable::new_for_actor_type::<A>(), Rc::clone(&self.storage), &self.tuning);
-        self.classes[actor_id.as_usize()] = Some(class);
+        let mut class = Class::new(ActorVTable::new_for_actor_type::<A>(), &self.storage, &self.tuning);
+        // every class can be monitored
+        let monitor_id = self.register_message::<Monitor>();
+        self.register_message::<Down>();
+        class.v_table.message_handlers.insert(monitor_id, MessageHandler::OnMonitor);
+        // ...and fails when an instance it supervises fails too often,
+        // unless it gets its own handler for that
+        let escalation_id = self.register_message::<RestartIntensityExceeded>();
+        class.v_table.message_handlers.insert(escalation_id, MessageHandler::OnEscalation);
+        self.classes.insert(actor_id, class);
+        self.update_class_order();
+    }
+
+    /// Define named phases that actor classes can be assigned to (see `assign_to_phase`),
+    /// in the order they are processed in. Replaces previously defined phases,
+    /// together with the assignments to them.
+    pub fn set_phases(&mut self, phase_names: &[&str]) {
+        self.phases = phase_names
+            .iter()
+            .map(|name| Phase {
+                name: (*name).to_owned(),
+                class_ids: Vec::new(),
+            })
+            .collect();
+        self.update_class_order();
+    }
+
+    /// Assign a registered actor class to a phase defined with `set_phases`.
+    /// Within a phase, classes are processed in the order they were assigned in.
+    /// Classes that aren't assigned to any phase are processed after all phases,
+    /// in the order they were registered in.
+    pub fn assign_to_phase<A: Actor>(&mut self, phase_name: &str) {
+        let actor_id = self.actor_registry.get::<A>();
+        assert!(self.classes.contains(actor_id), "Actor not added yet");
+
+        for phase in &mut self.phases {
+            phase.class_ids.retain(|class_id| *class_id != actor_id);
+        }
+
+        self.phase_mut(phase_name).class_ids.push(actor_id);
+        self.update_class_order();
+    }
+
+    fn phase_mut(&mut self, phase_name: &str) -> &mut Phase {
+        self.phases
+            .iter_mut()
+            .find(|phase| phase.name == phase_name)
+            .unwrap_or_else(|| panic!("Phase {} doesn't exist", phase_name))
+    }
+
+    fn update_class_order(&mut self) {
+        let mut class_order: Vec<ShortTypeId> = self
+            .phases
+            .iter()
+            .flat_map(|phase| phase.class_ids.iter().cloned())
+            .collect();
+
+        for (class_id, _) in self.classes.iter() {
+            if !class_order.contains(&class_id) {
+                class_order.push(class_id);
+            }
+        }
+
+        if self.worker_pool.is_some() {
+            // a loop can go through every class once
+            self.loop_detector.max_gap = class_order.len();
+        }
+        self.class_order = Rc::new(class_order);
     }
 
     /// Register a dummy actor class without allocating any resources or dispatchers.
-    /// This can be used to get consistent type ID assignment between different interacting
-    /// versions of an actor system, where some actor classes might only ever exist in some versions.
+    /// Type IDs are derived from type names, so this is only needed to resolve
+    /// hash collisions the same way in different versions of an actor system,
+    /// where some actor classes might only ever exist in some versions.
+    /// Pinning IDs with a type manifest (see `pin_type_ids`) is the more robust way to do that.
     pub fn register_dummy<D: 'static>(&mut self) {
         let _actor_id = self.actor_registry.get_or_register::<D>();
     }
 
+    /// Give an actor class or actor trait an explicit type ID,
+    /// instead of one derived from its type name. Has to happen before it is registered.
+    pub fn pin_actor_id<A: ActorOrActorTrait>(&mut self, id: u16) -> Result<(), ManifestError> {
+        let name = ::std::intrinsics::type_name::<A>();
+        self.actor_registry.pin(name, ShortTypeId::new(id).expect("Type IDs can't be 0"))
+    }
+
+    /// Give a message type an explicit type ID,
+    /// instead of one derived from its type name. Has to happen before it is registered.
+    pub fn pin_message_id<M: Message>(&mut self, id: u16) -> Result<(), ManifestError> {
+        let name = ::std::intrinsics::type_name::<M>();
+        self.message_registry.pin(name, ShortTypeId::new(id).expect("Type IDs can't be 0"))
+    }
+
+    /// Get a manifest of the type IDs of all registered actor and message types.
+    /// Loading it with `pin_type_ids` in another build of the same application
+    /// (before registering anything) guarantees that both use the same type IDs.
+    ///
+    /// Builds made with different compiler versions or for different targets
+    /// (like server and browser) need a shared manifest to agree on type IDs, since
+    /// the type names the IDs are otherwise derived from might differ between them.
+    pub fn type_manifest(&self) -> String {
+        format!(
+            "{}\n{}{}\n{}",
+            ACTOR_MANIFEST_HEADER,
+            self.actor_registry.manifest(),
+            MESSAGE_MANIFEST_HEADER,
+            self.message_registry.manifest()
+        )
+    }
+
+    /// Pin type IDs to the ones listed in a manifest created by `type_manifest`.
+    /// Fails without pinning anything if the manifest is malformed, lists two types
+    /// with the same ID, or contradicts type IDs that were already assigned.
+    pub fn pin_type_ids(&mut self, manifest: &str) -> Result<(), ManifestError> {
+        let malformed = || ManifestError::Malformed {
+            line: manifest.lines().next().unwrap_or("").to_owned(),
+        };
+        let actors_start = manifest.find(ACTOR_MANIFEST_HEADER).ok_or_else(malformed)?;
+        let messages_start = manifest.find(MESSAGE_MANIFEST_HEADER).ok_or_else(malformed)?;
+        if messages_start < actors_start {
+            return Err(malformed());
+        }
+
+        let actors = &manifest[actors_start + ACTOR_MANIFEST_HEADER.len()..messages_start];
+        let messages = &manifest[messages_start + MESSAGE_MANIFEST_HEADER.len()..];
+
+        let mut actor_registry = self.actor_registry.clone();
+        actor_registry.pin_from_manifest(actors)?;
+        self.message_registry.pin_from_manifest(messages)?;
+        self.actor_registry = actor_registry;
+        Ok(())
+    }
+
+    /// Write the manifest of all registered types (see `type_manifest`) to a file
+    #[cfg(feature = "server")]
+    pub fn write_type_manifest<P: AsRef<::std::path::Path>>(&self, path: P) -> ::std::io::Result<()> {
+        ::std::fs::write(path, self.type_manifest())
+    }
+
+    /// Pin type IDs to the ones listed in a manifest file (see `pin_type_ids`)
+    #[cfg(feature = "server")]
+    pub fn load_type_manifest<P: AsRef<::std::path::Path>>(&mut self, path: P) -> ::std::io::Result<()> {
+        let manifest = ::std::fs::read_to_string(path)?;
+        self.pin_type_ids(&manifest)
+            .map_err(|err| ::std::io::Error::new(::std::io::ErrorKind::InvalidData, err.to_string()))
+    }
+
     /// Register a new actor trait with the system
     pub fn register_trait<T: ActorOrActorTrait>(&mut self) {
         let trait_id = self.actor_registry.get_or_register::<T>();
-        self.trait_implementors[trait_id.as_usize()].get_or_insert_with(Vec::new);
+        self.trait_implementors.get_or_insert_with(trait_id, Vec::new);
+    }
+
+    /// Register a message type, making it sendable over the network
+    fn register_message<M: Message>(&mut self) -> ShortTypeId {
+        let message_id = self.message_registry.get_or_register::<M>();
+        self.networking.register_message::<M>(message_id);
+        message_id
     }
 
     /// Register a message that an actor trait handles
     pub fn register_trait_message<M: Message>(&mut self) {
-        self.message_registry.get_or_register::<M>();
+        self.register_message::<M>();
     }
 
     /// Register an actor class as an implementor of an actor trait,
@@ -91,35 +318,258 @@ impl ActorSystem {
     pub fn register_implementor<A: Actor, T: ActorOrActorTrait>(&mut self) {
         let trait_id = self.actor_registry.get_or_register::<T>();
         let actor_id = self.actor_registry.get::<A>();
-        self.trait_implementors[trait_id.as_usize()]
-            .get_or_insert_with(Vec::new)
+        self.trait_implementors
+            .get_or_insert_with(trait_id, Vec::new)
             .push(actor_id);
     }
 
     /// Add a message handler to a registered actor class
-    pub fn add_handler<A: Actor, M: Message, F: Fn(&M, &mut A, &mut World) -> Fate + 'static>(
+    pub fn add_handler<A: Actor, M: Message, F: Fn(&M, &mut A, &mut World) -> Fate + Send + 'static>(
         &mut self,
         handler: F,
         critical: bool,
     ) {
         let actor_id = self.actor_registry.get::<A>();
-        let message_id = self.message_registry.get_or_register::<M>();
-        let class = self.classes[actor_id.as_usize()].as_mut().expect("Actor not added yet");
+        let message_id = self.register_message::<M>();
+        let class = self.classes.get_mut(actor_id).expect("Actor not added yet");
         class.add_handler(message_id, handler, critical);
     }
 
     /// Add an actor spawner to a registered actor class
-    pub fn add_spawner<A: Actor, M: Message, F: Fn(&M, &mut World) -> A + 'static>(
+    pub fn add_spawner<A: Actor, M: Message, F: Fn(&M, &mut World) -> A + Send + 'static>(
         &mut self,
         constructor: F,
         critical: bool,
     ) {
         let actor_id = self.actor_registry.get::<A>();
-        let message_id = self.message_registry.get_or_register::<M>();
-        let class = self.classes[actor_id.as_usize()].as_mut().expect("Actor not added yet");
+        let message_id = self.register_message::<M>();
+        let class = self.classes.get_mut(actor_id).expect("Actor not added yet");
         class.add_spawner(message_id, constructor, critical);
     }
 
+    /// Add a responder to a registered actor class, which answers requests
+    /// made with `World::request` by returning a value
+    pub fn add_responder<A: Actor, Q: Message, R: Message, F: Fn(&Q, &mut A, &mut World) -> R + Send + 'static>(
+        &mut self,
+        responder: F,
+        critical: bool,
+    ) {
+        self.register_message::<Response<R>>();
+        self.add_handler::<A, Request<Q>, _>(
+            move |request, instance, world| {
+                let value = responder(&request.query, instance, world);
+                world.send(
+                    request.requester,
+                    Response {
+                        request_id: request.request_id,
+                        value: COption(Some(value)),
+                    },
+                );
+                Fate::Live
+            },
+            critical,
+        );
+    }
+
+    /// Enable a registered actor class to make requests using `World::request`
+    /// and to handle their responses
+    pub fn add_requester<A: Actor, Q: Message, R: Message>(&mut self, critical: bool) {
+        self.register_message::<Request<Q>>();
+        self.add_handler::<A, Response<R>, _>(
+            |response, instance, world| {
+                world.continue_request(
+                    response.request_id,
+                    response as *const Response<R> as *const (),
+                    instance as *mut A as *mut (),
+                    response.value.is_none(),
+                )
+            },
+            critical,
+        );
+    }
+
+    /// Add a mapper to a registered actor class, which answers map-reduce queries
+    /// made with `World::map_reduce`. Each local instance is mapped to a value,
+    /// and all values of one machine are reduced to one before they are sent back.
+    pub fn add_mapper<A: Actor, Q: Message, V: Message, Map, Reduce>(
+        &mut self,
+        mapper: Map,
+        reducer: Reduce,
+        critical: bool,
+    ) where
+        Map: Fn(&Q, &A, &mut World) -> V + Send + 'static,
+        Reduce: Fn(V, V) -> V + Send + 'static,
+    {
+        let actor_id = self.actor_registry.get::<A>();
+        let message_id = self.register_message::<Gather<Q>>();
+        self.register_message::<Gathered<V>>();
+        let class = self.classes.get_mut(actor_id).expect("Actor not added yet");
+        class.add_mapper(
+            message_id,
+            move |gather: &Gather<Q>, instance: &A, world: &mut World| {
+                mapper(&gather.query, instance, world)
+            },
+            reducer,
+            |recipient: RawID, gather: &Gather<Q>, reduced: Option<V>, world: &mut World| {
+                let machine = world.local_machine_id();
+                let n_parts = world.n_local_recipients(recipient.type_id);
+                world.send(
+                    gather.requester,
+                    Gathered {
+                        request_id: gather.request_id,
+                        value: COption(reduced),
+                        timed_out: false,
+                        machine,
+                        n_parts: n_parts as u32,
+                    },
+                )
+            },
+            critical,
+        );
+    }
+
+    /// Enable a registered actor class to make map-reduce queries using
+    /// `World::map_reduce` and to handle their results
+    pub fn add_map_reduce_requester<A: Actor, Q: Message, V: Message>(&mut self, critical: bool) {
+        self.register_message::<Gather<Q>>();
+        self.add_handler::<A, Gathered<V>, _>(
+            |gathered, instance, world| {
+                world.continue_request(
+                    gathered.request_id,
+                    gathered as *const Gathered<V> as *const (),
+                    instance as *mut A as *mut (),
+                    gathered.timed_out,
+                )
+            },
+            critical,
+        );
+    }
+
+    /// Set how panics in message handlers of a registered actor class are dealt with
+    /// (the default is `PanicPolicy::FreezeClass`)
+    pub fn set_panic_policy<A: Actor>(&mut self, policy: PanicPolicy) {
+        let actor_id = self.actor_registry.get::<A>();
+        let class = self.classes.get_mut(actor_id).expect("Actor not added yet");
+        class.lifecycle.panic_policy = policy;
+    }
+
+    /// Restart instances of a registered actor class that panic in a message handler,
+    /// by replacing them with the instance returned by `restart`. The new instance
+    /// gets the same ID as the panicked one, but with the next version.
+    ///
+    /// If instances of the class panic more than `max_restarts` times within `within_turns`
+    /// networking turns, further panicked instances are removed instead and `supervisor`
+    /// (if given) is sent a `RestartIntensityExceeded` message. A panic in `restart` itself
+    /// is treated the same way.
+    ///
+    /// Unless the class of the supervisor has its own handler for `RestartIntensityExceeded`,
+    /// the supervisor instance then fails as if it panicked, according to the panic policy
+    /// of its class. If that class is supervised as well, the failure propagates further up.
+    pub fn supervise<A: Actor, F: Fn(A::ID, &mut World) -> A + Send + 'static>(
+        &mut self,
+        restart: F,
+        max_restarts: usize,
+        within_turns: usize,
+        supervisor: Option<RawID>,
+    ) {
+        self.set_supervision::<A>(
+            Box::new(move |id: RawID, world: &mut World, store: &mut InstanceStore, v_table: &ActorVTable| unsafe {
+                let mut instance = restart(A::ID::from_raw(id), world);
+                (v_table.state_v_table.set_raw_id)(&mut instance as *mut A as *mut (), id);
+                store.add(&mut instance as *mut A as *mut (), &v_table.state_v_table, true);
+                ::std::mem::forget(instance);
+            }),
+            max_restarts,
+            within_turns,
+            supervisor,
+        );
+    }
+
+    /// Like `supervise`, but restart panicked instances with a spawner of the class
+    /// (see `add_spawner`, which needs to be called first), handling the message
+    /// returned by `spawn_message` for the ID the restarted instance should have.
+    /// The spawner needs to give the new instance the ID from that message.
+    pub fn supervise_via_spawner<A: Actor, M: Message, F: Fn(A::ID) -> M + Send + 'static>(
+        &mut self,
+        spawn_message: F,
+        max_restarts: usize,
+        within_turns: usize,
+        supervisor: Option<RawID>,
+    ) {
+        let message_id = self.message_registry.get::<M>();
+        self.set_supervision::<A>(
+            Box::new(move |id: RawID, world: &mut World, store: &mut InstanceStore, v_table: &ActorVTable| {
+                let packet = Packet {
+                    recipient_id: id,
+                    message: spawn_message(A::ID::from_raw(id)),
+                };
+                match v_table.message_handlers.get(message_id) {
+                    Some(MessageHandler::OnSpawn { spawner, .. }) => {
+                        spawner(&packet as *const Packet<M> as *const (), world, store, &v_table.state_v_table)
+                    }
+                    _ => panic!("{} has no spawner for the restart message", v_table.type_name),
+                }
+            }),
+            max_restarts,
+            within_turns,
+            supervisor,
+        );
+    }
+
+    fn set_supervision<A: Actor>(&mut self, restart: RestartFn, max_restarts: usize, within_turns: usize, supervisor: Option<RawID>) {
+        let actor_id = self.actor_registry.get::<A>();
+        let class = self.classes.get_mut(actor_id).expect("Actor not added yet");
+        class.lifecycle.panic_policy = PanicPolicy::Restart;
+        class.lifecycle.supervision = Some(Supervision::new(restart, max_restarts, within_turns, supervisor));
+    }
+
+    /// Send a `PanicReport` to `observer` for every panic caught in a message handler.
+    /// The class of `observer` needs to have a handler for `PanicReport`.
+    pub fn set_panic_observer(&mut self, observer: RawID) {
+        self.register_message::<PanicReport>();
+        self.panic_observer = Some(observer);
+    }
+
+    /// Send a `DeadLetter` to `handler` for every message that could not be delivered.
+    /// The class of `handler` needs to have a handler for `DeadLetter`.
+    pub fn set_dead_letter_handler(&mut self, handler: RawID) {
+        self.register_message::<DeadLetter>();
+        self.dead_letter_handler = Some(handler);
+    }
+
+    /// Let all local instances of a registered actor class receive a `M::default()`
+    /// message every `every_n_turns` networking turns, starting with the next turn
+    /// that messages are processed in. The class needs to have a handler for `M`.
+    ///
+    /// Ticks are dispatched directly to the instances, without going through the inbox,
+    /// at the start of the first call to `process_all_messages` (or `process_phase`, ...)
+    /// of a turn, before any other messages are handled.
+    pub fn register_tick<A: Actor, M: Message + Default>(&mut self, every_n_turns: usize, critical: bool) {
+        assert!(every_n_turns > 0, "Ticks need to happen at least one turn apart");
+        let class_id = self.actor_registry.get::<A>();
+        let message_id = self.message_registry.get::<M>();
+        let recipient_id = RawID::new(class_id, 0, self.networking.machine_id, 0).local_broadcast();
+
+        self.ticks.push(TickSubscription {
+            class_id,
+            every_n_turns,
+            next_turn: self.networking.n_turns,
+            critical,
+            dispatch: Box::new(move |class: &mut Class, message_statistics: &mut TypeIdCounts, world: &mut World| {
+                let packet = Packet {
+                    recipient_id,
+                    message: M::default(),
+                };
+                class.handle_packet(
+                    message_id,
+                    &packet as *const Packet<M> as *const (),
+                    message_statistics,
+                    world,
+                );
+            }),
+        });
+    }
+
     /// Manually send a message
     pub fn send<M: Message>(&mut self, recipient: RawID, message: M) {
         let packet = Packet {
@@ -127,31 +577,112 @@ impl ActorSystem {
             message,
         };
 
+        if let Some(sender) = self.current_sender {
+            if self.loop_detector.is_enabled() {
+                let message_type = self.message_registry.get::<M>();
+                self.loop_detector.record(sender, message_type, recipient.type_id);
+            }
+        }
+
         let to_here = recipient.machine == self.networking.machine_id;
         let global = recipient.is_global_broadcast();
 
         if !to_here || global {
-            self.networking
-                .enqueue(self.message_registry.get::<M>(), packet.clone());
+            let message_type = self.message_registry.get::<M>();
+            if !self.networking.enqueue(message_type, &packet) {
+                self.dead_letter(recipient, message_type, DeadLetterReason::NotPortable, Vec::new());
+            }
         }
 
         if to_here || global {
-            if let Some(class) = self.classes[recipient.type_id.as_usize()].as_mut() {
+            if let Some(class) = self.classes.get_mut(recipient.type_id) {
                 class.inbox.put(packet, &self.message_registry);
-            } else if let Some(implementors) = self.trait_implementors[recipient.type_id.as_usize()].as_ref() {
+            } else if let Some(implementors) = self.trait_implementors.get(recipient.type_id) {
                 for implementor_type_id in implementors {
-                    let class = self.classes[implementor_type_id.as_usize()].as_mut().expect("Implementor should exist");
+                    let class = self.classes.get_mut(*implementor_type_id).expect("Implementor should exist");
                     class.inbox.put(packet.clone(), &self.message_registry);
                 }
             } else {
-                panic!(
-                    "Recipient {} doesn't exist, or Trait has no implementors",
-                    self.actor_registry.get_name(recipient.type_id),
-                );
+                let message_type = self.message_registry.get::<M>();
+                let encoded = self
+                    .networking
+                    .encode_packet(message_type, &packet as *const Packet<M> as *const ());
+                self.dead_letter(recipient, message_type, DeadLetterReason::NoSuchClass, encoded);
             }
         }
     }
 
+    /// Deliver an already encoded message (message type followed by a compact `Packet`)
+    fn send_raw(&mut self, data: &[u8]) {
+        #[allow(clippy::cast_ptr_alignment)]
+        let recipient = unsafe {
+            ::std::ptr::read_unaligned(
+                &data[::std::mem::size_of::<ShortTypeId>()] as *const u8 as *const RawID,
+            )
+        };
+
+        if let Some(sender) = self.current_sender {
+            if self.loop_detector.is_enabled() {
+                let message_type = ShortTypeId::new(LittleEndian::read_u16(data)).expect("Invalid message type");
+                self.loop_detector.record(sender, message_type, recipient.type_id);
+            }
+        }
+
+        let to_here = recipient.machine == self.networking.machine_id;
+        let global = recipient.is_global_broadcast();
+
+        if (!to_here || global) && !self.networking.enqueue_raw(recipient.machine, data) {
+            let message_type = ShortTypeId::new(LittleEndian::read_u16(data)).expect("Invalid message type");
+            self.dead_letter(recipient, message_type, DeadLetterReason::NotPortable, Vec::new());
+        }
+
+        if to_here || global {
+            if let Some(class) = self.classes.get_mut(recipient.type_id) {
+                class.inbox.put_raw(data);
+            } else if let Some(implementors) = self.trait_implementors.get(recipient.type_id) {
+                for implementor_type_id in implementors {
+                    let class = self.classes.get_mut(*implementor_type_id).expect("Implementor should exist");
+                    class.inbox.put_raw(data);
+                }
+            } else {
+                let message_type = ShortTypeId::new(LittleEndian::read_u16(data)).expect("Invalid message type");
+                let aligned = aligned_copy(&data[::std::mem::size_of::<ShortTypeId>()..]);
+                let encoded = self.networking.encode_packet(message_type, aligned.as_ptr() as *const ());
+                self.dead_letter(recipient, message_type, DeadLetterReason::NoSuchClass, encoded);
+            }
+        }
+    }
+
+    /// Count a message that could not be delivered and pass it on to the dead letter handler
+    fn dead_letter(&mut self, recipient: RawID, message_type: ShortTypeId, reason: DeadLetterReason, packet: Vec<u8>) {
+        self.dead_letter_counts.increment(message_type);
+
+        if let Some(handler) = self.dead_letter_handler {
+            // don't go in circles if the dead letter handler itself is gone
+            if message_type != self.message_registry.get::<DeadLetter>() {
+                let dead_letter = DeadLetter {
+                    recipient,
+                    message_name: self.message_registry.get_name(message_type).clone().into(),
+                    reason,
+                    packet: packet.into(),
+                };
+                self.send(handler, dead_letter);
+            }
+        }
+    }
+
+    /// How many local classes receive messages sent to a class or actor trait
+    fn n_local_recipients(&self, type_id: ShortTypeId) -> usize {
+        if self.classes.contains(type_id) {
+            1
+        } else {
+            self.trait_implementors
+                .get(type_id)
+                .map(|implementors| implementors.len())
+                .unwrap_or(0)
+        }
+    }
+
     /// Get a base RawID for an actor or actor trait
     pub fn id<A: ActorOrActorTrait>(&mut self) -> RawID {
         RawID::new(self.short_id::<A>(), 0, self.networking.machine_id, 0)
@@ -161,50 +692,508 @@ impl ActorSystem {
         self.actor_registry.get_or_register::<A>()
     }
 
-    fn single_message_cycle(&mut self) {
-        let mut world = World(self as *const Self as *mut Self);
+    fn single_message_cycle(&mut self, class_order: &[ShortTypeId], worker_shared: Option<&Arc<WorkerShared>>) {
+        if let Some(worker_shared) = worker_shared {
+            return self.single_message_cycle_threaded(class_order, worker_shared);
+        }
+
+        let mut world = World::new(self as *const Self as *mut Self);
 
-        for maybe_class in self.classes.iter_mut() {
-            if let Some(class) = maybe_class.as_mut() {
+        for &class_id in class_order {
+            if let Some(class) = self.classes.get_mut(class_id) {
+                self.current_sender = Some(class_id);
                 class.handle_messages(&mut self.message_statistics, &mut world);
             }
         }
+
+        self.current_sender = None;
+        self.collect_panic_reports();
+        self.collect_dead_letters();
+        self.detect_message_loops();
+    }
+
+    /// Like `single_message_cycle`, but handle the classes with queued messages at the same time
+    /// on the worker threads. Each class buffers the messages its handlers send, which are
+    /// only delivered once all classes are done, in class order - just as if the classes
+    /// were handled one after another, but without any class seeing messages of this cycle.
+    fn single_message_cycle_threaded(&mut self, class_order: &[ShortTypeId], worker_shared: &Arc<WorkerShared>) {
+        let mut busy_classes = self
+            .classes
+            .iter_mut()
+            .filter(|(_, class)| !class.inbox.is_empty())
+            .filter_map(|(class_id, class)| {
+                let position = class_order.iter().position(|id| *id == class_id)?;
+                Some((position, class_id, class))
+            })
+            .collect::<Vec<_>>();
+        busy_classes.sort_by_key(|&(position, _, _)| position);
+
+        let class_ids = busy_classes.iter().map(|&(_, class_id, _)| class_id).collect::<Vec<_>>();
+        let mut results = class_ids.iter().map(|_| None).collect::<Vec<Option<WorkerResult>>>();
+        let jobs = busy_classes
+            .into_iter()
+            .zip(results.iter_mut())
+            .map(|((_, class_id, class), result)| {
+                let worker_shared = Arc::clone(worker_shared);
+                Box::new(move || {
+                    *result = Some(handle_messages_on_worker(class, class_id, worker_shared));
+                }) as Box<dyn FnOnce() + Send + '_>
+            })
+            .collect();
+
+        let worker_pool = self.worker_pool.as_ref().expect("should have worker threads");
+        if let Err(payload) = worker_pool.scope(jobs) {
+            resume_unwind(payload);
+        }
+
+        let mut first_panic = None;
+        for (class_id, result) in class_ids.into_iter().zip(results) {
+            let result = result.expect("should have handled all busy classes");
+            self.message_statistics.add(&result.message_statistics);
+
+            self.current_sender = Some(class_id);
+            for entry in outbox::entries(&result.sent) {
+                self.send_raw(entry);
+            }
+            for (&turn, entry) in result.scheduled_turns.iter().zip(outbox::entries(&result.scheduled)) {
+                self.timers.outbox_for(turn).put_raw(entry);
+            }
+            self.remote_monitors.extend(result.remote_monitors);
+
+            if first_panic.is_none() {
+                first_panic = result.panic;
+            }
+        }
+
+        self.current_sender = None;
+        self.collect_panic_reports();
+        self.collect_dead_letters();
+        self.detect_message_loops();
+
+        // like in `single_message_cycle`, a panic outside of message handlers
+        // leaves the system in a panicked state
+        if let Some(payload) = first_panic {
+            resume_unwind(payload);
+        }
+    }
+
+    /// What worker threads need of the system to handle the messages of one message cycle
+    fn worker_shared(&mut self) -> WorkerShared {
+        let mut n_local_recipients = DenseTypeIdMap::new();
+        let mut slot_maps = DenseTypeIdMap::new();
+        for (class_id, class) in self.classes.iter() {
+            n_local_recipients.insert(class_id, 1);
+            slot_maps.insert(class_id, class.instance_store.shared_slot_map());
+        }
+        for (trait_id, implementors) in self.trait_implementors.iter() {
+            n_local_recipients.insert(trait_id, implementors.len());
+        }
+
+        WorkerShared {
+            machine_id: self.networking.machine_id,
+            n_turns: self.networking.n_turns,
+            panic_happened: self.panic_happened,
+            n_connected_machines: self.networking.n_connected_machines(),
+            registries: self.shared.registries(),
+            codecs: self.networking.codecs(),
+            requests: Arc::clone(&self.requests),
+            slot_maps,
+            n_local_recipients,
+        }
+    }
+
+    fn detect_message_loops(&mut self) {
+        if self.loop_detector.is_enabled() {
+            if let Some(message_loop) = self
+                .loop_detector
+                .finish_cycle(&self.actor_registry, &self.message_registry)
+            {
+                self.message_loops.push(message_loop);
+            }
+        }
+    }
+
+    fn collect_dead_letters(&mut self) {
+        let mut dead_letters = Vec::new();
+        for class in self.classes.values_mut() {
+            dead_letters.append(&mut class.lifecycle.dead_letters);
+        }
+
+        for (recipient, message_type, packet) in dead_letters {
+            self.dead_letter(recipient, message_type, DeadLetterReason::NoSuchInstance, packet);
+        }
+    }
+
+    /// Turn panics caught by classes into `PanicReport`s, in class order
+    fn collect_panic_reports(&mut self) {
+        let n_reports_before = self.panic_reports.len();
+
+        for class in self.classes.values_mut() {
+            for caught in class.lifecycle.caught_panics.drain(..) {
+                self.panic_reports.push(PanicReport {
+                    recipient: caught.recipient,
+                    actor_name: self.actor_registry.get_name(caught.recipient.type_id).clone().into(),
+                    message_name: self.message_registry.get_name(caught.message_type).clone().into(),
+                    turn: self.networking.n_turns,
+                    payload: caught.payload.into(),
+                });
+            }
+        }
+
+        if let Some(observer) = self.panic_observer {
+            for i in n_reports_before..self.panic_reports.len() {
+                let report = self.panic_reports[i].clone();
+                self.send(observer, report);
+            }
+        }
+    }
+
+    fn dispatch_ticks(&mut self) {
+        let n_turns = self.networking.n_turns;
+        let mut world = World::new(self as *mut Self);
+
+        for tick in &mut self.ticks {
+            if n_turns >= tick.next_turn {
+                tick.next_turn = n_turns + tick.every_n_turns;
+
+                if tick.critical || !self.panic_happened {
+                    let class = self.classes.get_mut(tick.class_id).expect("Actor not added yet");
+                    self.current_sender = Some(tick.class_id);
+                    (tick.dispatch)(class, &mut self.message_statistics, &mut world);
+                    self.current_sender = None;
+                }
+            }
+        }
+    }
+
+    /// Deliver messages that were scheduled for the current turn (or earlier)
+    fn deliver_due_messages(&mut self) {
+        for buffer in self.timers.take_due(self.networking.n_turns) {
+            for entry in outbox::entries(&buffer) {
+                self.send_raw(entry);
+            }
+            self.timers.recycle(buffer);
+        }
+    }
+
+    fn publish_registries(&mut self) {
+        let n_types = (self.actor_registry.n_types(), self.message_registry.n_types());
+        if n_types != self.published_n_types {
+            self.shared.publish_registries(&self.actor_registry, &self.message_registry);
+            self.networking.set_local_types(&self.actor_registry, &self.message_registry);
+            self.published_n_types = n_types;
+        }
+    }
+
+    fn expire_requests(&mut self) {
+        let n_turns = self.networking.n_turns;
+        let timeout_actions = lock_requests(&self.requests).expire(n_turns);
+        let mut world = self.world();
+
+        for on_timeout in timeout_actions {
+            on_timeout(&mut world);
+        }
+    }
+
+    fn deliver_staged_messages(&mut self) {
+        let shared = Arc::clone(&self.shared);
+        let n_turns = self.networking.n_turns;
+        unsafe {
+            shared.deliver_staged(|turn, entry| {
+                if turn > n_turns {
+                    self.timers.outbox_for(turn).put_raw(entry);
+                } else {
+                    self.send_raw(entry);
+                }
+            })
+        };
+
+        self.remote_monitors.extend(shared.take_staged_remote_monitors());
     }
 
     /// Process and handle all enqueued messages in the system
-    /// and the resulting messages, up to a recursion depth of 1000
+    /// and the resulting messages, until all inboxes are empty,
+    /// but at most up to a recursion depth of 1000.
+    ///
+    /// If `Tuning::n_worker_threads` is larger than 1, the inboxes of
+    /// different classes are processed in parallel. Messages sent during
+    /// one cycle are then only handled in the next cycle, which is deterministic,
+    /// but can differ in ordering from the single-threaded mode.
     pub fn process_all_messages(&mut self) {
+        self.process_messages_with_budget(ProcessingBudget::MaxCycles(1000));
+    }
+
+    /// Like `process_all_messages`, but with a custom limit on how long to keep
+    /// handling messages. Reports whether all inboxes were emptied
+    /// and which classes still have messages queued otherwise.
+    pub fn process_messages_with_budget(&mut self, budget: ProcessingBudget) -> ProcessingReport {
+        let class_order = Rc::clone(&self.class_order);
+        self.process_classes(&class_order, budget, true)
+    }
+
+    /// Process and handle all enqueued messages of the classes in a phase
+    /// (see `set_phases`), and the resulting messages sent within the phase.
+    /// Messages to classes of other phases stay queued until those are processed.
+    pub fn process_phase(&mut self, phase_name: &str) -> ProcessingReport {
+        let class_order = self.phase_mut(phase_name).class_ids.clone();
+        self.process_classes(&class_order, ProcessingBudget::MaxCycles(1000), false)
+    }
+
+    /// Process and handle all enqueued messages of one registered actor class,
+    /// including messages it sends to itself
+    pub fn process_messages_of<A: Actor>(&mut self) -> ProcessingReport {
+        let class_order = vec![self.actor_registry.get::<A>()];
+        self.process_classes(&class_order, ProcessingBudget::MaxCycles(1000), false)
+    }
+
+    /// Process the given classes. Messages staged through handles since the start
+    /// of the turn are only delivered if `all_classes` are processed, since they might
+    /// go to any of them.
+    fn process_classes(&mut self, class_order: &[ShortTypeId], budget: ProcessingBudget, all_classes: bool) -> ProcessingReport {
+        self.publish_registries();
+        let worker_shared = if self.worker_pool.is_some() {
+            Some(Arc::new(self.worker_shared()))
+        } else {
+            None
+        };
+        let mut n_cycles = 0;
+        let n_message_loops_before = self.message_loops.len();
+
         let result = catch_unwind(AssertUnwindSafe(|| {
-            for _i in 0..1000 {
-                self.single_message_cycle();
+            if self.started_turn != Some(self.networking.n_turns) {
+                self.start_turn();
+            } else if all_classes {
+                self.deliver_staged_messages();
+            }
+
+            let deadline = match budget {
+                ProcessingBudget::MaxDuration(duration) => Some(Instant::now() + duration),
+                ProcessingBudget::MaxCycles(_) => None,
+            };
+
+            while !self.is_quiescent(class_order) {
+                let budget_left = match budget {
+                    ProcessingBudget::MaxCycles(max_cycles) => n_cycles < max_cycles,
+                    ProcessingBudget::MaxDuration(_) => {
+                        deadline.map_or(false, |deadline| Instant::now() < deadline)
+                    }
+                };
+
+                if !budget_left {
+                    break;
+                }
+
+                self.single_message_cycle(class_order, worker_shared.as_ref());
+
+                n_cycles += 1;
             }
         }));
 
         if result.is_err() {
             self.panic_happened = true;
         }
+
+        ProcessingReport {
+            n_cycles,
+            quiescent: self.is_quiescent(class_order),
+            busy_classes: self.busy_classes(class_order),
+            message_loops: self.message_loops[n_message_loops_before..].to_vec(),
+        }
+    }
+
+    /// Deal with everything that is due once per turn, before the classes
+    /// of the first phase handle their messages
+    fn start_turn(&mut self) {
+        self.started_turn = Some(self.networking.n_turns);
+        self.deliver_staged_messages();
+        self.deliver_due_messages();
+        self.dispatch_ticks();
+        self.expire_requests();
+    }
+
+    fn is_quiescent(&self, class_order: &[ShortTypeId]) -> bool {
+        class_order
+            .iter()
+            .filter_map(|class_id| self.classes.get(*class_id))
+            .all(|class| class.inbox.is_empty())
+    }
+
+    fn busy_classes(&self, class_order: &[ShortTypeId]) -> Vec<(String, usize)> {
+        class_order
+            .iter()
+            .filter_map(|&class_id| match self.classes.get(class_id) {
+                Some(class) if !class.inbox.is_empty() => {
+                    let actor_name = self.actor_registry.get_name(class_id);
+                    Some((actor_name.to_owned(), class.inbox.len()))
+                }
+                _ => None,
+            })
+            .collect()
     }
 
     /// Get a `World` handle for the system.
     pub fn world(&mut self) -> World {
-        World(self as *mut Self)
+        World::new(self as *mut Self)
+    }
+
+    /// Get a handle that can be used to send messages into the system
+    /// from other threads. Messages sent through it are delivered at the
+    /// start of the next call to `process_all_messages`, or of the next turn.
+    pub fn handle(&mut self) -> SystemHandle {
+        self.publish_registries();
+        SystemHandle::new(Arc::clone(&self.shared))
     }
 
     /// Connect to peers in the networking topology.
     pub fn networking_connect(&mut self) {
+        self.publish_registries();
         self.networking.connect();
+        self.notify_network_observers();
     }
 
     /// Send and receive messages from peers in the networking topology.
     pub fn networking_send_and_receive(&mut self) {
+        self.publish_registries();
+        let down_type = self.message_registry.get_if_known::<Down>();
         self.networking
-            .send_and_receive(&mut self.classes, &mut self.trait_implementors);
+            .send_and_receive(&mut self.classes, &mut self.trait_implementors, down_type);
+
+        let remote_deaths = self.networking.take_remote_deaths();
+        if !remote_deaths.is_empty() {
+            self.remote_monitors.retain(|monitor| !remote_deaths.contains(monitor));
+        }
+        for machine in self.networking.take_disconnected_machines() {
+            self.notify_remote_monitors(machine);
+        }
+        self.shared.publish_n_turns(self.networking.n_turns);
+        self.notify_network_observers();
+    }
+
+    /// Broadcast peer lifecycle events to local implementors of `NetworkObserver`.
+    /// Until the first implementor is registered, events are kept for it.
+    fn notify_network_observers(&mut self) {
+        // without implementors, `NetworkEvent` isn't even registered as a message
+        let observers = match self.actor_registry.get_if_known::<NetworkObserverID>() {
+            Some(observers) if self
+                .trait_implementors
+                .get(observers)
+                .map_or(false, |implementors| !implementors.is_empty()) =>
+            {
+                observers
+            }
+            _ => return,
+        };
+        let recipient = RawID::new(observers, 0, self.networking.machine_id, 0).local_broadcast();
+        for event in self.networking.take_events() {
+            self.send(recipient, event);
+        }
+    }
+
+    /// Forget local watchers of monitored instances that don't exist anymore themselves
+    fn prune_dead_watchers(&mut self) {
+        let machine = self.networking.machine_id;
+        let classes = &self.classes;
+        let dead_watchers = classes
+            .iter()
+            .flat_map(|(_, class)| class.lifecycle.watchers())
+            .filter(|watcher| {
+                watcher.machine == machine
+                    && classes
+                        .get(watcher.type_id)
+                        .map_or(false, |class| !class.instance_store.contains(*watcher))
+            })
+            .collect::<Vec<_>>();
+
+        if !dead_watchers.is_empty() {
+            for class in self.classes.values_mut() {
+                class.lifecycle.forget_watchers(&dead_watchers);
+            }
+        }
+    }
+
+    /// Let watchers of actors on a disconnected machine know
+    fn notify_remote_monitors(&mut self, machine: MachineID) {
+        let (affected, unaffected) = self
+            .remote_monitors
+            .drain(..)
+            .partition::<Vec<_>, _>(|&(_watcher, target)| target.machine == machine);
+        self.remote_monitors = unaffected;
+
+        for (watcher, target) in affected {
+            self.send(watcher, Down { id: target, reason: DownReason::MachineDisconnected });
+        }
     }
 
     /// Mark the local "networking turn" as finished. Networking turns are
     /// used to track and manage time drift between peers in the networking topology.
     pub fn networking_finish_turn(&mut self) -> Option<usize> {
-        self.networking.finish_turn()
+        self.prune_dead_watchers();
+        let maybe_skip_turns = self.networking.finish_turn();
+        self.shared.publish_n_turns(self.networking.n_turns);
+        self.notify_network_observers();
+        maybe_skip_turns
+    }
+
+    /// When connecting to a peer that uses different type IDs for some types,
+    /// translate the message types and recipient types of its messages to local type IDs
+    /// (matching types by name), instead of refusing the connection.
+    /// IDs contained in messages are not translated, so this is only safe for peers
+    /// whose messages don't refer to actors by ID - pinning type IDs with a type manifest
+    /// (see `type_manifest`) is preferable.
+    pub fn networking_remap_type_ids(&mut self, remap_type_ids: bool) {
+        self.networking.set_remap_type_ids(remap_type_ids);
+    }
+
+    /// Get the type mismatches that connections to peers were refused because of so far
+    pub fn networking_type_mismatches(&self) -> &[TypeMismatch] {
+        self.networking.type_mismatches()
+    }
+
+    /// Get all invalid data received from peers so far.
+    /// Receiving invalid data closes the connection to the peer that sent it.
+    pub fn networking_input_errors(&self) -> &[(MachineID, InvalidInput)] {
+        self.networking.input_errors()
+    }
+
+    /// Only accept messages from the peer `machine` that are explicitly allowed
+    /// with `networking_allow_inbound`. Other messages from it are dropped and counted
+    /// (see `networking_rejected_message_counts`). Peers are unrestricted by default.
+    pub fn networking_restrict_inbound(&mut self, machine: MachineID) {
+        self.networking.inbound_policy_mut(machine);
+    }
+
+    /// Allow the peer `machine` to send messages of type `M` to instances of
+    /// the actor class or actor trait `A`. Restricts the peer, if it wasn't already.
+    /// `A` and `M` need to be registered already (for example by adding a handler for `M` to `A`).
+    pub fn networking_allow_inbound<A: ActorOrActorTrait, M: Message>(&mut self, machine: MachineID) {
+        let recipient_type = self.actor_registry.get::<A>();
+        let message_type = self.message_registry.get::<M>();
+        self.networking
+            .inbound_policy_mut(machine)
+            .allow(recipient_type, message_type);
+    }
+
+    /// Get counts of messages from restricted peers that were dropped because they weren't allowed,
+    /// per peer and message type
+    pub fn networking_rejected_message_counts(&self) -> HashMap<MachineID, HashMap<String, usize>> {
+        self.networking
+            .rejected_messages()
+            .iter()
+            .map(|(machine, counts)| {
+                let counts = counts
+                    .iter()
+                    .map(|(message_type, n_rejected)| {
+                        (self.message_registry.get_name(message_type).clone(), *n_rejected)
+                    })
+                    .collect();
+                (*machine, counts)
+            })
+            .collect()
+    }
+
+    /// Get the state of the connection to each peer
+    pub fn networking_connection_states(&self) -> HashMap<MachineID, ConnectionState> {
+        self.networking.connection_states()
     }
 
     /// Get the machine ID of this system in the network
@@ -226,8 +1215,7 @@ impl ActorSystem {
     pub fn get_instance_counts(&self) -> HashMap<String, usize> {
         self.classes
             .iter()
-            .filter_map(|maybe_class| maybe_class.as_ref())
-            .map(|class| {
+            .map(|(_, class)| {
                 (
                     class.v_table.type_name.split("::").last().unwrap().replace(">", ""),
                     *class.instance_store.n_instances,
@@ -239,22 +1227,41 @@ impl ActorSystem {
     pub fn get_message_statistics(&self) -> HashMap<String, usize> {
         self.message_statistics
             .iter()
-            .enumerate()
-            .filter_map(|(i, n_sent)| {
-                if *n_sent > 0 {
-                    let name = self
-                        .message_registry
-                        .get_name(ShortTypeId::new(i as u16).unwrap());
-                    Some((name.to_owned(), *n_sent))
-                } else {
-                    None
-                }
+            .map(|(message_type, n_sent)| {
+                let name = self.message_registry.get_name(message_type);
+                (name.to_owned(), *n_sent)
             }).collect()
     }
 
     /// Reset the counter for message statistics
     pub fn reset_message_statistics(&mut self) {
-        self.message_statistics = [0; MAX_MESSAGE_TYPES]
+        self.message_statistics.clear()
+    }
+
+    /// Get counts of messages that could not be delivered, per type
+    pub fn get_dead_letter_counts(&self) -> HashMap<String, usize> {
+        self.dead_letter_counts
+            .iter()
+            .map(|(message_type, n_dead)| {
+                let name = self.message_registry.get_name(message_type);
+                (name.to_owned(), *n_dead)
+            }).collect()
+    }
+
+    /// Get all message loops detected so far: chains of messages between classes
+    /// that went on for more than `Tuning::message_loop_threshold` message cycles in a row
+    pub fn message_loops(&self) -> &[MessageLoop] {
+        &self.message_loops
+    }
+
+    /// Get reports of all panics caught in message handlers so far
+    pub fn panic_reports(&self) -> &[PanicReport] {
+        &self.panic_reports
+    }
+
+    /// Forget all panic reports collected so far
+    pub fn clear_panic_reports(&mut self) {
+        self.panic_reports.clear();
     }
 
     /// Get the current length of all actor message queues
@@ -269,14 +1276,9 @@ impl ActorSystem {
 
         self.classes
             .iter()
-            .enumerate()
-            .filter_map(|(i, maybe_class)| {
-                maybe_class.as_ref().map(|class| {
-                    let actor_name = self
-                        .actor_registry
-                        .get_name(ShortTypeId::new(i as u16).unwrap());
-                    (actor_name.to_owned(), class.inbox.len())
-                })
+            .map(|(class_id, class)| {
+                let actor_name = self.actor_registry.get_name(class_id);
+                (actor_name.to_owned(), class.inbox.len())
             }).chain(connection_queue_length)
             .collect()
     }
@@ -292,66 +1294,687 @@ impl ActorSystem {
 /// A handle representing an `ActorSystem` that exposes a safe subset
 /// of functionality to be used within actor message handlers - for
 /// communication with other actors.
-pub struct World(*mut ActorSystem);
+///
+/// A `World` is bound to the thread it was created on. To interact
+/// with an actor system from other threads, use a `SystemHandle`.
+pub struct World {
+    kind: WorldKind,
+}
+
+enum WorldKind {
+    /// Used by the thread owning the actor system
+    Direct(*mut ActorSystem),
+    /// Used outside of the actor system, see `SystemHandle::world`
+    External(SystemHandle, Registries),
+    /// Used by the handlers of a class on a worker thread, see `Tuning::n_worker_threads`
+    Worker(Box<WorkerWorld>),
+}
+
+/// What the handlers of classes on worker threads can access of the actor system.
+/// It stays the same during all message cycles of one call to `process_all_messages`
+/// (or `process_phase`, ...).
+pub(crate) struct WorkerShared {
+    machine_id: MachineID,
+    n_turns: usize,
+    panic_happened: bool,
+    n_connected_machines: usize,
+    registries: Registries,
+    codecs: Arc<DenseTypeIdMap<WireCodec>>,
+    requests: Arc<Mutex<PendingRequests>>,
+    slot_maps: DenseTypeIdMap<Arc<Mutex<SlotMap>>>,
+    /// How many local classes receive messages sent to a class or actor trait
+    n_local_recipients: DenseTypeIdMap<usize>,
+}
+
+/// Buffers everything the handlers of one class do on a worker thread,
+/// to be carried out by the actor system once all classes are done
+struct WorkerWorld {
+    shared: Arc<WorkerShared>,
+    class_id: ShortTypeId,
+    sent: outbox::Outbox,
+    scheduled: outbox::Outbox,
+    /// The turn each entry of `scheduled` is due at
+    scheduled_turns: Vec<usize>,
+    remote_monitors: Vec<(RawID, RawID)>,
+}
+
+/// The outcome of handling the messages of one class on a worker thread
+struct WorkerResult {
+    sent: Vec<u8>,
+    scheduled: Vec<u8>,
+    scheduled_turns: Vec<usize>,
+    remote_monitors: Vec<(RawID, RawID)>,
+    message_statistics: TypeIdCounts,
+    /// A panic outside of message handlers
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+fn handle_messages_on_worker(class: &mut Class, class_id: ShortTypeId, shared: Arc<WorkerShared>) -> WorkerResult {
+    let mut world = World {
+        kind: WorldKind::Worker(Box::new(WorkerWorld {
+            shared,
+            class_id,
+            sent: outbox::Outbox::new(),
+            scheduled: outbox::Outbox::new(),
+            scheduled_turns: Vec::new(),
+            remote_monitors: Vec::new(),
+        })),
+    };
+    let mut message_statistics = TypeIdCounts::new();
+    let panic = catch_unwind(AssertUnwindSafe(|| class.handle_messages(&mut message_statistics, &mut world))).err();
+
+    match world.kind {
+        WorldKind::Worker(mut worker) => WorkerResult {
+            sent: worker.sent.take(),
+            scheduled: worker.scheduled.take(),
+            scheduled_turns: worker.scheduled_turns,
+            remote_monitors: worker.remote_monitors,
+            message_statistics,
+            panic,
+        },
+        _ => unreachable!(),
+    }
+}
 
-// TODO: make this true
-unsafe impl Sync for World {}
-unsafe impl Send for World {}
+/// Lock pending requests, even if an accumulator panicked while they were locked
+fn lock_requests(requests: &Mutex<PendingRequests>) -> ::std::sync::MutexGuard<PendingRequests> {
+    requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
 
 impl World {
+    fn new(system: *mut ActorSystem) -> World {
+        World {
+            kind: WorldKind::Direct(system),
+        }
+    }
+
+    pub(crate) fn external(handle: SystemHandle, registries: Registries) -> World {
+        World {
+            kind: WorldKind::External(handle, registries),
+        }
+    }
+
+    fn system(&mut self) -> Result<&mut ActorSystem, OutsideOfActorSystem> {
+        match self.kind {
+            WorldKind::Direct(system) => Ok(unsafe { &mut *system }),
+            WorldKind::External(..) | WorldKind::Worker(..) => Err(OutsideOfActorSystem),
+        }
+    }
+
+    fn id<A: ActorOrActorTrait>(&mut self) -> RawID {
+        match self.kind {
+            WorldKind::External(ref handle, ref registries) => RawID::new(
+                registries.actors.get::<A>(),
+                0,
+                handle.machine_id(),
+                0,
+            ),
+            WorldKind::Worker(ref worker) => RawID::new(
+                worker.shared.registries.actors.get::<A>(),
+                0,
+                worker.shared.machine_id,
+                0,
+            ),
+            WorldKind::Direct(system) => unsafe { &mut *system }.id::<A>(),
+        }
+    }
+
     /// Send a message to a RawID
     pub fn send<M: Message>(&mut self, receiver: RawID, message: M) {
-        unsafe { &mut *self.0 }.send(receiver, message);
+        let packet = Packet {
+            recipient_id: receiver,
+            message,
+        };
+
+        match self.kind {
+            WorldKind::Direct(system) => unsafe { &mut *system }.send(receiver, packet.message),
+            WorldKind::External(ref handle, ref registries) => match handle.message_type::<M>(registries) {
+                Ok(message_type) => handle.stage(message_type, packet),
+                Err(error) => println!("Dropping message sent through a SystemHandle: {}", error),
+            },
+            WorldKind::Worker(ref mut worker) => {
+                worker.sent.put(packet, &worker.shared.registries.messages)
+            }
+        }
+    }
+
+    /// Let `watcher` receive a `Down` message once the actor `target` dies
+    /// (including removal after a panic), or once the machine hosting `target` disconnects.
+    /// The class of `watcher` needs to have a handler for `Down`.
+    pub fn monitor(&mut self, watcher: RawID, target: RawID) {
+        if target.machine != self.local_machine_id() {
+            match self.kind {
+                WorldKind::Direct(system) => unsafe { &mut *system }.remote_monitors.push((watcher, target)),
+                WorldKind::External(ref handle, _) => handle.stage_remote_monitor(watcher, target),
+                WorldKind::Worker(ref mut worker) => worker.remote_monitors.push((watcher, target)),
+            }
+        }
+
+        self.send(target, Monitor { watcher });
+    }
+
+    /// Get the local number of networking turns
+    /// (see `SystemHandle::current_turn` when used through a `SystemHandle`)
+    pub fn current_turn(&mut self) -> usize {
+        match self.kind {
+            WorldKind::Direct(system) => unsafe { &*system }.networking.n_turns,
+            WorldKind::External(ref handle, _) => handle.current_turn(),
+            WorldKind::Worker(ref worker) => worker.shared.n_turns,
+        }
+    }
+
+    /// Send a message to a RawID, to be delivered once the networking turn `turn`
+    /// begins, as counted by `ActorSystem::networking_finish_turn` on this machine.
+    /// If that turn already began, the message is sent like with `send`.
+    pub fn send_at_turn<M: Message>(&mut self, receiver: RawID, message: M, turn: usize) {
+        if turn <= self.current_turn() {
+            return self.send(receiver, message);
+        }
+
+        let packet = Packet {
+            recipient_id: receiver,
+            message,
+        };
+
+        match self.kind {
+            WorldKind::Direct(system) => unsafe {
+                let system = &mut *system;
+                system.timers.outbox_for(turn).put(packet, &system.message_registry)
+            },
+            WorldKind::External(ref handle, ref registries) => match handle.message_type::<M>(registries) {
+                Ok(message_type) => handle.stage_at_turn(turn, message_type, packet),
+                Err(error) => println!("Dropping message sent through a SystemHandle: {}", error),
+            },
+            WorldKind::Worker(ref mut worker) => {
+                worker.scheduled.put(packet, &worker.shared.registries.messages);
+                worker.scheduled_turns.push(turn);
+            }
+        }
+    }
+
+    /// Send a message to a RawID, to be delivered `n_turns` networking turns from now
+    pub fn send_after<M: Message>(&mut self, receiver: RawID, message: M, n_turns: usize) {
+        let turn = self.current_turn() + n_turns;
+        self.send_at_turn(receiver, message, turn)
     }
 
     /// Get the RawID of the first local actor of a certain type
     /// (Note: no such actor might exist)
     pub fn local_first<A: ActorOrActorTrait>(&mut self) -> RawID {
-        unsafe { &mut *self.0 }.id::<A>()
+        self.id::<A>()
     }
 
     /// Get the RawID of the first global actor (among all network peers)
     /// of a certain type (Note: no such actor might exist)
     pub fn global_first<A: ActorOrActorTrait>(&mut self) -> RawID {
-        let mut id = unsafe { &mut *self.0 }.id::<A>();
+        let mut id = self.id::<A>();
         id.machine = MachineID(0);
         id
     }
 
     /// Get a RawID for a broadcast to all local actors of a certain type
     pub fn local_broadcast<A: ActorOrActorTrait>(&mut self) -> RawID {
-        unsafe { &mut *self.0 }.id::<A>().local_broadcast()
+        self.id::<A>().local_broadcast()
     }
 
     /// Get a RawID for a broadcast to all global actors
     /// (across all network peers) of a certain type
     pub fn global_broadcast<A: ActorOrActorTrait>(&mut self) -> RawID {
-        unsafe { &mut *self.0 }.id::<A>().global_broadcast()
+        self.id::<A>().global_broadcast()
+    }
+
+    /// The pending requests of the system, and the origin of requests made through this world
+    /// (see `RequestID::origin`)
+    fn requests(&mut self) -> Result<(Arc<Mutex<PendingRequests>>, u16), OutsideOfActorSystem> {
+        match self.kind {
+            WorldKind::Direct(system) => {
+                let system = unsafe { &*system };
+                let origin = system.current_sender.map_or(0, |class_id| class_id.as_u16());
+                Ok((Arc::clone(&system.requests), origin))
+            }
+            WorldKind::Worker(ref worker) => Ok((Arc::clone(&worker.shared.requests), worker.class_id.as_u16())),
+            WorldKind::External(..) => Err(OutsideOfActorSystem),
+        }
+    }
+
+    /// How many local classes receive messages sent to a class or actor trait
+    fn n_local_recipients(&mut self, type_id: ShortTypeId) -> usize {
+        match self.kind {
+            WorldKind::Direct(system) => unsafe { &*system }.n_local_recipients(type_id),
+            WorldKind::Worker(ref worker) => worker.shared.n_local_recipients.get(type_id).cloned().unwrap_or(0),
+            WorldKind::External(..) => 0,
+        }
+    }
+
+    /// How many machines (including this one) are connected
+    fn n_connected_machines(&mut self) -> usize {
+        match self.kind {
+            WorldKind::Direct(system) => unsafe { &*system }.networking.n_connected_machines(),
+            WorldKind::Worker(ref worker) => worker.shared.n_connected_machines,
+            WorldKind::External(..) => 1,
+        }
+    }
+
+    /// How many machines will send results for a map-reduce query sent to `target`.
+    /// Each of them sends one result per class that received the query there.
+    fn n_gathering_machines(&mut self, target: RawID) -> usize {
+        let gathers_locally = self.n_local_recipients(target.type_id) > 0;
+
+        if target.is_global_broadcast() {
+            // peers are expected to have at least one receiving class each
+            self.n_connected_machines() - 1 + gathers_locally as usize
+        } else if target.machine == self.local_machine_id() {
+            gathers_locally as usize
+        } else {
+            1
+        }
+    }
+
+    /// Ask `target` for a value, using a query that the target class handles with a responder
+    /// (see `ActorSystem::add_responder`). The response is handled by `continuation`
+    /// on the requesting instance, which needs to be of a class set up with
+    /// `ActorSystem::add_requester`. If no response arrives within `timeout_turns`
+    /// networking turns, `continuation` is called with `Err(RequestTimeout)` instead.
+    ///
+    /// Fails with `OutsideOfActorSystem` when used through a `SystemHandle`.
+    pub fn request<A, Q, R, F>(
+        &mut self,
+        requester: A::ID,
+        target: RawID,
+        query: Q,
+        timeout_turns: usize,
+        continuation: F,
+    ) -> Result<RequestID, OutsideOfActorSystem>
+    where
+        A: Actor,
+        Q: Message,
+        R: Message,
+        F: FnOnce(Result<&R, RequestTimeout>, &mut A, &mut World) -> Fate + Send + 'static,
+    {
+        let requester = requester.as_raw();
+        let (requests, origin) = self.requests()?;
+        let deadline_turn = self.current_turn() + timeout_turns;
+
+        let request_id = {
+            let mut requests = lock_requests(&requests);
+            let request_id = requests.next_id(origin);
+
+            requests.add(
+                request_id,
+                deadline_turn,
+                // a single response is all there is
+                Box::new(|_response_ptr: *const ()| true),
+                Box::new(move |response_ptr: *const (), instance_ptr: *mut (), world: &mut World| {
+                    let response = unsafe { &*(response_ptr as *const Response<R>) };
+                    let instance = unsafe { &mut *(instance_ptr as *mut A) };
+                    match *response.value {
+                        Some(ref value) => continuation(Ok(value), instance, world),
+                        None => continuation(Err(RequestTimeout(request_id)), instance, world),
+                    }
+                }),
+                Box::new(move |world: &mut World| {
+                    world.send(
+                        requester,
+                        Response::<R> {
+                            request_id,
+                            value: COption(None),
+                        },
+                    )
+                }),
+            );
+
+            request_id
+        };
+
+        self.send(
+            target,
+            Request {
+                request_id,
+                requester,
+                query,
+            },
+        );
+
+        Ok(request_id)
+    }
+
+    /// Broadcast a query to all instances of a class or actor trait (`target` needs to be
+    /// a local or global broadcast ID), which handle it with a mapper (see `ActorSystem::add_mapper`).
+    /// The per-machine results are combined using `reducer` and then handled by `continuation`
+    /// on the requesting instance, which needs to be of a class set up with
+    /// `ActorSystem::add_map_reduce_requester`. `continuation` gets `Ok(None)` if no instances
+    /// exist, or `Err(RequestTimeout)` if not all machines answered within `timeout_turns`.
+    /// Peers without any class receiving the query never answer, so global broadcasts
+    /// reaching such peers time out.
+    ///
+    /// Fails with `OutsideOfActorSystem` when used through a `SystemHandle`.
+    pub fn map_reduce<A, Q, V, Reduce, F>(
+        &mut self,
+        requester: A::ID,
+        target: RawID,
+        query: Q,
+        reducer: Reduce,
+        timeout_turns: usize,
+        continuation: F,
+    ) -> Result<RequestID, OutsideOfActorSystem>
+    where
+        A: Actor,
+        Q: Message,
+        V: Message,
+        Reduce: Fn(V, V) -> V + Send + 'static,
+        F: FnOnce(Result<Option<&V>, RequestTimeout>, &mut A, &mut World) -> Fate + Send + 'static,
+    {
+        let requester = requester.as_raw();
+        let (requests, origin) = self.requests()?;
+        let deadline_turn = self.current_turn() + timeout_turns;
+        let n_machines = self.n_gathering_machines(target);
+        let local_machine = self.local_machine_id();
+
+        let request_id = {
+            let mut requests = lock_requests(&requests);
+            let request_id = requests.next_id(origin);
+            let reduced = Arc::new(Mutex::new(Reduced(None)));
+            let reduced_for_continuation = Arc::clone(&reduced);
+            let mut parts = GatheredParts::new(n_machines);
+
+            requests.add(
+                request_id,
+                deadline_turn,
+                Box::new(move |gathered_ptr: *const ()| {
+                    let gathered = unsafe { &*(gathered_ptr as *const Gathered<V>) };
+                    if let Some(ref value) = *gathered.value {
+                        let mut reduced = reduced.lock().unwrap();
+                        reduced.0 = Some(match reduced.0.take() {
+                            Some(previous) => reducer(previous, value.clone()),
+                            None => value.clone(),
+                        });
+                    }
+                    parts.add(gathered.machine, gathered.n_parts)
+                }),
+                Box::new(move |gathered_ptr: *const (), instance_ptr: *mut (), world: &mut World| {
+                    let gathered = unsafe { &*(gathered_ptr as *const Gathered<V>) };
+                    let instance = unsafe { &mut *(instance_ptr as *mut A) };
+                    if gathered.timed_out {
+                        continuation(Err(RequestTimeout(request_id)), instance, world)
+                    } else {
+                        let reduced = reduced_for_continuation.lock().unwrap().0.take();
+                        continuation(Ok(reduced.as_ref()), instance, world)
+                    }
+                }),
+                Box::new(move |world: &mut World| {
+                    world.send(
+                        requester,
+                        Gathered::<V> {
+                            request_id,
+                            value: COption(None),
+                            timed_out: true,
+                            machine: local_machine,
+                            n_parts: 0,
+                        },
+                    )
+                }),
+            );
+
+            request_id
+        };
+
+        if n_machines == 0 {
+            // without anybody to ask, the requester answers itself right away
+            self.send(
+                requester,
+                Gathered::<V> {
+                    request_id,
+                    value: COption(None),
+                    timed_out: false,
+                    machine: local_machine,
+                    n_parts: 1,
+                },
+            );
+        } else {
+            self.send(
+                target,
+                Gather {
+                    request_id,
+                    requester,
+                    query,
+                },
+            );
+        }
+
+        Ok(request_id)
+    }
+
+    fn continue_request(
+        &mut self,
+        request_id: RequestID,
+        response_ptr: *const (),
+        instance_ptr: *mut (),
+        timed_out: bool,
+    ) -> Fate {
+        let (requests, _) = self
+            .requests()
+            .expect("Responses are only handled within the actor system");
+        // the continuation might make requests itself, so it is called after unlocking
+        let maybe_continuation = lock_requests(&requests).respond(request_id, response_ptr, timed_out);
+
+        if let Some(continuation) = maybe_continuation {
+            continuation(response_ptr, instance_ptr, self)
+        } else {
+            // still waiting for more responses, or the request already timed out
+            Fate::Live
+        }
     }
 
     /// Allocate a new instance id to be used by a to-be-spawned actor
+    /// (Note: not possible through a `SystemHandle`)
     pub fn allocate_instance_id<A: 'static + Actor>(&mut self) -> RawID {
-        let system: &mut ActorSystem = unsafe { &mut *self.0 };
-        let class = system.classes[system.actor_registry.get::<A>().as_usize()].as_mut()
+        let base_id = self.local_broadcast::<A>();
+        if let WorldKind::Worker(ref worker) = self.kind {
+            let slot_map = worker.shared.slot_maps.get(base_id.type_id).expect("Subactor type not found.");
+            return allocate_id_in(slot_map, base_id);
+        }
+
+        let system = self
+            .system()
+            .expect("Allocating instance IDs is only possible within the actor system");
+        let class = system.classes.get_mut(system.actor_registry.get::<A>())
                 .expect("Subactor type not found.");
-        unsafe { class.instance_store.allocate_id(self.local_broadcast::<A>()) }
+        unsafe { class.instance_store.allocate_id(base_id) }
     }
 
     /// Get the machine ID of this system in the network
     pub fn local_machine_id(&mut self) -> MachineID {
-        let system: &mut ActorSystem = unsafe { &mut *self.0 };
-        system.networking.machine_id
+        match self.kind {
+            WorldKind::External(ref handle, _) => handle.machine_id(),
+            WorldKind::Worker(ref worker) => worker.shared.machine_id,
+            WorldKind::Direct(system) => unsafe { &*system }.networking.machine_id,
+        }
+    }
+
+    /// Get the portable encoding of a packet in local layout
+    /// (Note: always empty when used through a `SystemHandle`)
+    pub(crate) fn encode_packet(&mut self, message_type: ShortTypeId, packet_ptr: *const ()) -> Vec<u8> {
+        match self.kind {
+            WorldKind::Direct(system) => unsafe { &*system }.networking.encode_packet(message_type, packet_ptr),
+            WorldKind::Worker(ref worker) => {
+                let mut encoded = Vec::new();
+                if let Some(codec) = worker.shared.codecs.get(message_type) {
+                    (codec.encode)(packet_ptr, &mut encoded);
+                }
+                encoded
+            }
+            WorldKind::External(..) => Vec::new(),
+        }
     }
 
     /// Returns whether the system is in a panicked state
+    /// (Note: always false when used through a `SystemHandle`)
     pub fn panic_happened(&self) -> bool {
-        let system: &mut ActorSystem = unsafe { &mut *self.0 };
-        system.panic_happened
+        match self.kind {
+            WorldKind::Direct(system) => {
+                unsafe { &*system }.panic_happened
+            }
+            WorldKind::Worker(ref worker) => worker.shared.panic_happened,
+            WorldKind::External(..) => false,
+        }
     }
 
     /// Get the name of an actor class by type ID
     pub fn get_actor_name(&mut self, type_id: ShortTypeId) -> &str {
-        let system: &mut ActorSystem = unsafe { &mut *self.0 };
-        system.actor_registry.get_name(type_id)
+        match self.kind {
+            WorldKind::External(_, ref registries) => registries.actors.get_name(type_id),
+            WorldKind::Worker(ref worker) => worker.shared.registries.actors.get_name(type_id),
+            WorldKind::Direct(system) => {
+                unsafe { &*system }.actor_registry.get_name(type_id)
+            }
+        }
     }
-}
\ No newline at end of file
+}
+
+/// A system with an Alice in the phase "first" and a Bob in the phase "second"
+#[cfg(test)]
+fn system_with_phases() -> (ActorSystem, crate::testing::AliceID, crate::testing::BobID) {
+    use crate::testing::{test_system, Alice, Bob};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.set_phases(&["first", "second"]);
+    system.assign_to_phase::<Alice>("first");
+    system.assign_to_phase::<Bob>("second");
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+    (system, alice, bob)
+}
+
+#[test]
+fn test_phase_only_handles_messages_of_its_classes() {
+    use crate::testing::{Alice, Bob, Forward, Note};
+
+    let (mut system, alice, bob) = system_with_phases();
+    system.send(alice.as_raw(), Forward(1, bob.as_raw()));
+    system.send(bob.as_raw(), Note(2));
+
+    let report = system.process_phase("first");
+    assert!(report.quiescent);
+    assert_eq!(Alice::received(&mut system, alice), vec![1]);
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxCycles(0));
+    assert_eq!(report.busy_classes.len(), 1);
+    assert!(report.busy_classes[0].0.ends_with("Bob"));
+    assert_eq!(report.busy_classes[0].1, 2);
+
+    let report = system.process_phase("second");
+    assert!(report.quiescent);
+    assert_eq!(Bob::received(&mut system, bob), vec![2, 1]);
+}
+
+#[test]
+fn test_ticks_are_dispatched_once_per_turn_before_the_first_phase() {
+    use crate::testing::Bob;
+
+    #[derive(Copy, Clone, Default, Portable)]
+    struct Tick;
+
+    let (mut system, _, bob) = system_with_phases();
+    system.add_handler::<Bob, Tick, _>(
+        |_, instance, _world| {
+            instance.received.push(99);
+            Fate::Live
+        },
+        false,
+    );
+    system.register_tick::<Bob, Tick>(1, false);
+    system.networking_finish_turn();
+
+    system.process_phase("first");
+    assert_eq!(Bob::received(&mut system, bob), vec![99]);
+    system.process_phase("second");
+    system.process_all_messages();
+    assert_eq!(Bob::received(&mut system, bob), vec![99]);
+
+    system.networking_finish_turn();
+    system.process_phase("first");
+    assert_eq!(Bob::received(&mut system, bob), vec![99, 99]);
+}
+
+#[test]
+fn test_scheduled_and_staged_messages_are_delivered_before_the_first_phase() {
+    use crate::testing::{Bob, Note};
+
+    let (mut system, _, bob) = system_with_phases();
+    system.world().send_after(bob.as_raw(), Note(1), 1);
+    system.networking_finish_turn();
+
+    system.process_phase("first");
+    // staged after the turn started, so only delivered when processing all classes
+    system.handle().world().send(bob.as_raw(), Note(2));
+    system.process_phase("second");
+    assert_eq!(Bob::received(&mut system, bob), vec![1]);
+
+    system.process_all_messages();
+    assert_eq!(Bob::received(&mut system, bob), vec![1, 2]);
+}
+
+#[test]
+fn test_type_manifest_round_trip() {
+    use crate::testing::{test_system, Alice, Bob};
+
+    let mut original = test_system();
+    Alice::register(&mut original);
+    Bob::register(&mut original);
+
+    let mut pinned = test_system();
+    pinned.pin_type_ids(&original.type_manifest()).unwrap();
+    Bob::register(&mut pinned);
+    Alice::register(&mut pinned);
+
+    assert!(pinned.id::<Alice>() == original.id::<Alice>());
+    assert!(pinned.id::<Bob>() == original.id::<Bob>());
+    assert_eq!(pinned.type_manifest(), original.type_manifest());
+}
+
+#[test]
+fn test_classes_are_handled_on_several_worker_threads() {
+    use crate::testing::{test_system_with_tuning, Alice, Bob, Note};
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[derive(Copy, Clone, Portable)]
+    struct Meet(RawID);
+
+    let mut system = test_system_with_tuning(Tuning {
+        n_worker_threads: 4,
+        ..Tuning::default()
+    });
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+
+    // both classes only get past the barrier if they are handled at the same time
+    let barrier = Arc::new(Barrier::new(2));
+    let threads = Arc::new(Mutex::new(Vec::new()));
+    let meet = {
+        let threads = Arc::clone(&threads);
+        move |meet: &Meet, world: &mut World| {
+            barrier.wait();
+            threads.lock().unwrap().push(thread::current().id());
+            world.send(meet.0, Note(1));
+            Fate::Live
+        }
+    };
+    let meet_for_bob = meet.clone();
+    system.add_handler::<Alice, Meet, _>(move |message, _, world| meet(message, world), false);
+    system.add_handler::<Bob, Meet, _>(move |message, _, world| meet_for_bob(message, world), false);
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+
+    system.send(alice.as_raw(), Meet(bob.as_raw()));
+    system.send(bob.as_raw(), Meet(alice.as_raw()));
+    system.process_all_messages();
+
+    let threads = threads.lock().unwrap().clone();
+    assert_eq!(threads.len(), 2);
+    assert!(threads[0] != threads[1]);
+    assert!(!threads.contains(&thread::current().id()));
+    assert_eq!(Alice::received(&mut system, alice), vec![1]);
+    assert_eq!(Bob::received(&mut system, bob), vec![1]);
+}
@@ -0,0 +1,186 @@
+use crate::id::MachineID;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+const NONCE_SIZE: usize = 16;
+const PROOF_SIZE: usize = 32;
+
+pub(crate) type Nonce = [u8; NONCE_SIZE];
+type HmacSha256 = Hmac<Sha256>;
+
+/// A secret shared by all machines of a network, used to prove to each
+/// other that they belong to it when connecting.
+///
+/// Authentication is a challenge/response exchange of HMAC-SHA256 proofs,
+/// done before anything else is sent over a connection:
+///
+/// 1. The connecting machine sends a hello: its own machine ID,
+///    the machine ID it expects to talk to and a fresh nonce.
+/// 2. The accepting machine answers with its own nonce and a proof over both nonces and machine IDs.
+/// 3. The connecting machine checks that proof and answers with its own proof,
+///    followed by its type handshake.
+///
+/// Only then does the accepting machine create a `Connection` for the claimed machine ID.
+#[derive(Clone, Default)]
+pub(crate) struct SharedSecret(Vec<u8>);
+
+impl SharedSecret {
+    pub fn new(secret: &[u8]) -> SharedSecret {
+        SharedSecret(secret.to_vec())
+    }
+
+    fn prove(
+        &self,
+        role: &[u8],
+        connecting: MachineID,
+        accepting: MachineID,
+        connecting_nonce: &Nonce,
+        accepting_nonce: &Nonce,
+    ) -> HmacSha256 {
+        hmac_sha256(
+            &self.0,
+            &[role, &[connecting.0, accepting.0], connecting_nonce, accepting_nonce],
+        )
+    }
+}
+
+/// The connecting side of authenticating a connection
+pub(crate) struct Authentication {
+    secret: SharedSecret,
+    machine_id: MachineID,
+    peer: MachineID,
+    nonce: Nonce,
+}
+
+impl Authentication {
+    pub fn new(secret: &SharedSecret, machine_id: MachineID, peer: MachineID) -> Authentication {
+        Authentication {
+            secret: secret.clone(),
+            machine_id,
+            peer,
+            nonce: new_nonce(),
+        }
+    }
+
+    /// The first message to send to the peer
+    pub fn hello(&self) -> Vec<u8> {
+        let mut hello = vec![self.machine_id.0, self.peer.0];
+        hello.extend_from_slice(&self.nonce);
+        hello
+    }
+
+    /// Check that the peer's challenge proves it is the expected machine of our network,
+    /// and answer with our proof, `None` if it doesn't
+    pub fn respond(&self, challenge: &[u8]) -> Option<Vec<u8>> {
+        if challenge.len() != 1 + NONCE_SIZE + PROOF_SIZE || challenge[0] != self.peer.0 {
+            return None;
+        }
+        let mut peer_nonce = [0; NONCE_SIZE];
+        peer_nonce.copy_from_slice(&challenge[1..1 + NONCE_SIZE]);
+
+        self.secret
+            .prove(b"kay accept", self.machine_id, self.peer, &self.nonce, &peer_nonce)
+            .verify(&challenge[1 + NONCE_SIZE..])
+            .ok()?;
+
+        Some(
+            self.secret
+                .prove(b"kay connect", self.machine_id, self.peer, &self.nonce, &peer_nonce)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+        )
+    }
+}
+
+/// The accepting side of authenticating a connection
+pub(crate) struct Challenge {
+    /// The machine ID the peer claims to have
+    pub peer: MachineID,
+    message: Vec<u8>,
+    expected_proof: HmacSha256,
+}
+
+impl Challenge {
+    /// Answer the hello of a peer, `None` if it is malformed or meant for another machine
+    pub fn new(secret: &SharedSecret, machine_id: MachineID, hello: &[u8], n_machines: usize) -> Option<Challenge> {
+        if hello.len() != 2 + NONCE_SIZE
+            || usize::from(hello[0]) >= n_machines
+            || hello[0] == machine_id.0
+            || hello[1] != machine_id.0
+        {
+            return None;
+        }
+        let peer = MachineID(hello[0]);
+        let mut peer_nonce = [0; NONCE_SIZE];
+        peer_nonce.copy_from_slice(&hello[2..]);
+        let nonce = new_nonce();
+
+        let mut message = vec![machine_id.0];
+        message.extend_from_slice(&nonce);
+        let proof = secret.prove(b"kay accept", peer, machine_id, &peer_nonce, &nonce);
+        message.extend_from_slice(&proof.finalize().into_bytes());
+
+        Some(Challenge {
+            peer,
+            message,
+            expected_proof: secret.prove(b"kay connect", peer, machine_id, &peer_nonce, &nonce),
+        })
+    }
+
+    /// The message to send to the peer
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Check that the peer's response proves it belongs to our network
+    /// and return the rest of the response, `None` if it doesn't
+    pub fn verify<'a>(&self, response: &'a [u8]) -> Option<&'a [u8]> {
+        if response.len() >= PROOF_SIZE && self.expected_proof.clone().verify(&response[..PROOF_SIZE]).is_ok() {
+            Some(&response[PROOF_SIZE..])
+        } else {
+            None
+        }
+    }
+}
+
+/// Get random bytes from the operating system (or the browser)
+pub(crate) fn new_nonce() -> Nonce {
+    let mut nonce = [0; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce).expect("Random bytes should be available");
+    nonce
+}
+
+fn hmac_sha256(key: &[u8], message: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC should accept keys of any length");
+    for part in message {
+        mac.update(part);
+    }
+    mac
+}
+
+#[test]
+fn test_hmac_sha256() {
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    let proof = hmac_sha256(b"Jefe", &[b"what do ya ", b"want for nothing?"]).finalize().into_bytes();
+    assert_eq!(
+        hex(&proof),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    assert_ne!(new_nonce(), new_nonce());
+}
+
+#[test]
+fn test_authentication() {
+    let secret = SharedSecret::new(b"secret");
+    let authentication = Authentication::new(&secret, MachineID(2), MachineID(0));
+    let challenge = Challenge::new(&secret, MachineID(0), &authentication.hello(), 3).unwrap();
+    let mut response = authentication.respond(challenge.message()).unwrap();
+    response.extend_from_slice(b"handshake");
+    assert_eq!(challenge.verify(&response), Some(&b"handshake"[..]));
+
+    let impostor = Authentication::new(&SharedSecret::new(b"guess"), MachineID(2), MachineID(0));
+    let challenge = Challenge::new(&secret, MachineID(0), &impostor.hello(), 3).unwrap();
+    assert!(impostor.respond(challenge.message()).is_none());
+    assert!(Challenge::new(&secret, MachineID(1), &authentication.hello(), 3).is_none());
+}
@@ -0,0 +1,183 @@
+use compact::Compact;
+use crate::messaging::{Message, Packet};
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+use crate::thread_safe_storage::ThreadSafeStorage;
+use crate::tuning::Tuning;
+use std::sync::Arc;
+
+pub mod multi_writer_queue;
+use self::multi_writer_queue::MultiWriterQueue;
+
+/// The message queue of a class. Any number of threads can `put`
+/// messages concurrently, while only the thread handling the class
+/// can `drain` it.
+///
+/// Like actor instances, queued messages live in the storage of the
+/// actor system, so they are persisted with a persistent storage.
+pub struct Inbox {
+    queue: MultiWriterQueue,
+}
+
+impl Inbox {
+    pub fn new(ident: &chunky::Ident, storage: &Arc<dyn ThreadSafeStorage>, tuning: &Tuning) -> Self {
+        Inbox {
+            queue: MultiWriterQueue::new(ident, Arc::clone(storage), tuning.inbox_queue_chunk_size),
+        }
+    }
+
+    pub fn put<M: Message>(&self, mut packet: Packet<M>, message_registry: &TypeRegistry) {
+        let packet_size = packet.total_size_bytes();
+        let total_size = ::std::mem::size_of::<ShortTypeId>() + packet_size;
+        let message_type = message_registry.get::<M>();
+
+        self.queue.enqueue(total_size, |queue_ptr| {
+            #[allow(clippy::cast_ptr_alignment)]
+            unsafe {
+                // Write message type
+                *(queue_ptr as *mut ShortTypeId) = message_type;
+                let payload_ptr = queue_ptr.add(::std::mem::size_of::<ShortTypeId>());
+
+                // Write the packet into the queue
+                Compact::compact_behind(&mut packet, payload_ptr as *mut Packet<M>);
+            }
+        });
+
+        ::std::mem::forget(packet);
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn put_raw(&self, buf: &[u8]) {
+        self.queue.enqueue(buf.len(), |queue_ptr| unsafe {
+            ::std::ptr::copy_nonoverlapping(&buf[0], queue_ptr, buf.len())
+        });
+    }
+
+    pub fn drain(&mut self) -> InboxIterator {
+        InboxIterator {
+            n_messages_to_read: self.queue.len(),
+            queue: &self.queue,
+        }
+    }
+}
+
+pub struct InboxIterator<'a> {
+    queue: &'a MultiWriterQueue,
+    n_messages_to_read: usize,
+}
+
+pub struct DispatchablePacket {
+    pub message_type: ShortTypeId,
+    pub packet_ptr: *const (),
+}
+
+impl<'a> Iterator for InboxIterator<'a> {
+    type Item = DispatchablePacket;
+
+    fn next(&mut self) -> Option<DispatchablePacket> {
+        if self.n_messages_to_read == 0 {
+            None
+        } else {
+            #[allow(clippy::cast_ptr_alignment)]
+            unsafe {
+                let ptr = self
+                    .queue
+                    .dequeue()
+                    .expect("should have something left for sure");
+                let message_type = *(ptr as *mut ShortTypeId);
+                let payload_ptr = (ptr as *mut u8).add(::std::mem::size_of::<ShortTypeId>());
+                self.n_messages_to_read -= 1;
+                Some(DispatchablePacket {
+                    message_type,
+                    packet_ptr: payload_ptr as *const (),
+                })
+            }
+        }
+    }
+}
+
+impl<'a> Drop for InboxIterator<'a> {
+    fn drop(&mut self) {
+        unsafe { self.queue.drop_old_chunks() };
+    }
+}
+
+#[cfg(test)]
+mod benches {
+    use super::multi_writer_queue::MultiWriterQueue;
+    use crate::thread_safe_storage::ThreadSafeStorage;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use test::{black_box, Bencher};
+
+    const N_ITEMS: usize = 100_000;
+    const ITEM_SIZE: usize = 40;
+    const N_WRITERS: usize = 4;
+
+    fn heap_storage() -> Arc<dyn ThreadSafeStorage> {
+        Arc::new(chunky::HeapStorage)
+    }
+
+    #[bench]
+    fn chunky_queue_single_writer(b: &mut Bencher) {
+        let mut queue = chunky::Queue::new(&"bench_queue".into(), 1024 * 1024, Rc::new(chunky::HeapStorage));
+        b.iter(|| unsafe {
+            for i in 0..N_ITEMS {
+                *(queue.enqueue(ITEM_SIZE) as *mut usize) = i;
+            }
+            while let Some(ptr) = queue.dequeue() {
+                black_box(*(ptr as *const usize));
+            }
+            queue.drop_old_chunks();
+        });
+    }
+
+    #[bench]
+    fn multi_writer_queue_single_writer(b: &mut Bencher) {
+        let queue = MultiWriterQueue::new(&"bench_queue".into(), heap_storage(), 1024 * 1024);
+        b.iter(|| unsafe {
+            for i in 0..N_ITEMS {
+                queue.enqueue(ITEM_SIZE, |ptr| *(ptr as *mut usize) = i);
+            }
+            while let Some(ptr) = queue.dequeue() {
+                black_box(*(ptr as *const usize));
+            }
+            queue.drop_old_chunks();
+        });
+    }
+
+    #[bench]
+    fn multi_writer_queue_concurrent_writers(b: &mut Bencher) {
+        let queue = Arc::new(MultiWriterQueue::new(&"bench_queue".into(), heap_storage(), 1024 * 1024));
+        b.iter(|| {
+            let writers: Vec<_> = (0..N_WRITERS)
+                .map(|_| {
+                    let queue = Arc::clone(&queue);
+                    ::std::thread::spawn(move || {
+                        for i in 0..N_ITEMS / N_WRITERS {
+                            queue.enqueue(ITEM_SIZE, |ptr| unsafe { *(ptr as *mut usize) = i });
+                        }
+                    })
+                }).collect();
+
+            let mut n_read = 0;
+            while n_read < N_ITEMS {
+                if let Some(ptr) = unsafe { queue.dequeue() } {
+                    black_box(unsafe { *(ptr as *const usize) });
+                    n_read += 1;
+                }
+            }
+
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            unsafe { queue.drop_old_chunks() };
+        });
+    }
+}
@@ -0,0 +1,621 @@
+use crate::thread_safe_storage::ThreadSafeStorage;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{spin_loop_hint, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Every entry starts with a header at an 8-byte aligned offset,
+// which is either not yet committed, a jump marker, or the total
+// size of the committed entry (header included). Entry sizes are
+// multiples of 8, so the lowest bit marks entries whose writer panicked.
+const HEADER_SIZE: usize = 8;
+const NOT_COMMITTED: usize = 0;
+const END_OF_CHUNK: usize = ::std::usize::MAX;
+const ABORTED: usize = 1;
+
+// Each chunk starts with the sequence number of the chunk after it (0 for none),
+// which is only needed to find all chunks again when loading a persisted queue
+const LINK_SIZE: usize = 8;
+const NO_NEXT_CHUNK: usize = 0;
+
+fn aligned_entry_size(item_size: usize) -> usize {
+    HEADER_SIZE + (item_size + HEADER_SIZE - 1) / HEADER_SIZE * HEADER_SIZE
+}
+
+struct Chunk {
+    memory: chunky::Chunk,
+    /// Where the entries start, 8-byte aligned and right after the link
+    entries: *mut u8,
+    capacity: usize,
+    sequence: usize,
+    reserved: AtomicUsize,
+    next: AtomicPtr<Chunk>,
+}
+
+impl Chunk {
+    fn from_memory(mut memory: chunky::Chunk, sequence: usize) -> Chunk {
+        let padding = memory.as_ptr().align_offset(HEADER_SIZE);
+        let entries = unsafe { memory.as_mut_ptr().add(padding + LINK_SIZE) };
+        let capacity = (memory.len() - padding - LINK_SIZE) / HEADER_SIZE * HEADER_SIZE;
+        Chunk {
+            memory,
+            entries,
+            capacity,
+            sequence,
+            reserved: AtomicUsize::new(0),
+            next: AtomicPtr::new(::std::ptr::null_mut()),
+        }
+    }
+
+    fn create_boxed(storage: &dyn ThreadSafeStorage, ident: &chunky::Ident, sequence: usize, min_capacity: usize) -> *mut Chunk {
+        // leave room for the link and for aligning the entries
+        let size = min_capacity + LINK_SIZE + HEADER_SIZE;
+        let mut memory = storage.create_chunk(ident.sub(sequence), size);
+        // zeroed, so all headers start out as NOT_COMMITTED
+        for byte in memory.iter_mut() {
+            *byte = 0;
+        }
+        Box::into_raw(Box::new(Chunk::from_memory(memory, sequence)))
+    }
+
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn link(&self) -> *mut usize {
+        self.entries.sub(LINK_SIZE) as *mut usize
+    }
+
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn header(&self, offset: usize) -> &AtomicUsize {
+        &*(self.entries.add(offset) as *const AtomicUsize)
+    }
+
+    unsafe fn payload_ptr(&self, offset: usize) -> *mut u8 {
+        self.entries.add(offset + HEADER_SIZE)
+    }
+
+    /// Go through the persisted entries from `offset` on, returning how many are committed
+    /// and where the last one ends. Anything after that is garbage of writers that never
+    /// committed their entry, which is zeroed, so the space can be reserved again.
+    unsafe fn scan(&mut self, mut offset: usize) -> (usize, usize) {
+        let mut n_committed = 0;
+
+        while offset + HEADER_SIZE <= self.capacity {
+            match self.header(offset).load(Ordering::SeqCst) {
+                NOT_COMMITTED => break,
+                END_OF_CHUNK => return (n_committed, self.capacity),
+                aborted if aborted & ABORTED != 0 => offset += aborted & !ABORTED,
+                entry_size => {
+                    n_committed += 1;
+                    offset += entry_size;
+                }
+            }
+        }
+
+        let end = ::std::cmp::min(offset, self.capacity);
+        ::std::ptr::write_bytes(self.entries.add(end), 0, self.capacity - end);
+        (n_committed, end)
+    }
+}
+
+/// Counts a writer as active in the epoch it entered in for as long as it lives,
+/// even if the writer panics
+struct ActiveWriter<'a>(&'a AtomicUsize);
+
+impl<'a> ActiveWriter<'a> {
+    fn enter(epoch: &AtomicUsize, n_active_writers: &'a [AtomicUsize; 2]) -> Self {
+        loop {
+            let entered_epoch = epoch.load(Ordering::SeqCst);
+            let n_active_in_epoch = &n_active_writers[entered_epoch % 2];
+            n_active_in_epoch.fetch_add(1, Ordering::SeqCst);
+            // if the epoch moved on meanwhile, the reader might not see us in time
+            if epoch.load(Ordering::SeqCst) == entered_epoch {
+                return ActiveWriter(n_active_in_epoch);
+            }
+            n_active_in_epoch.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<'a> Drop for ActiveWriter<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A reserved entry that is marked as aborted, unless it is committed
+struct UncommittedEntry<'a> {
+    header: &'a AtomicUsize,
+    entry_size: usize,
+}
+
+impl<'a> UncommittedEntry<'a> {
+    fn commit(self, len: &AtomicUsize) {
+        self.header.store(self.entry_size, Ordering::Release);
+        len.fetch_add(1, Ordering::SeqCst);
+        ::std::mem::forget(self);
+    }
+}
+
+impl<'a> Drop for UncommittedEntry<'a> {
+    fn drop(&mut self) {
+        // let the reader skip the entry instead of waiting for it forever
+        self.header.store(self.entry_size | ABORTED, Ordering::Release);
+    }
+}
+
+/// Where the reader is, persisted together with the chunks
+struct ReaderPosition {
+    sequence: usize,
+    offset: usize,
+}
+
+struct ReaderState {
+    chunk: *mut Chunk,
+    position: chunky::Value<ReaderPosition>,
+    /// Chunks that were completely read, together with the epoch they were retired in
+    retired_chunks: Vec<(*mut Chunk, usize)>,
+}
+
+/// An unbounded FIFO queue of heterogeneously sized items, which any number
+/// of threads can enqueue into concurrently without locking, while a single
+/// reader dequeues items.
+///
+/// Writers reserve space in the current chunk with an atomic increment and
+/// then commit their entry by setting its header. The writer that first
+/// overflows a chunk leaves a jump marker and links in a new chunk.
+/// If a writer panics, its entry is skipped by the reader.
+///
+/// Chunks that were completely read are freed once all writers that might
+/// still be using them are done. For that, writers count themselves as active
+/// in the current epoch, which the reader only moves on once all writers of the
+/// epoch before are done - so a chunk retired in one epoch is no longer used
+/// two epochs later, no matter how many writers started since.
+///
+/// Like `chunky::Queue`, the queue lives in chunks of a storage, so it is persisted
+/// with a persistent storage. Entries that were still being written when the queue
+/// was persisted are lost, together with the entries that were reserved after them.
+pub struct MultiWriterQueue {
+    ident: chunky::Ident,
+    storage: Arc<dyn ThreadSafeStorage>,
+    typical_chunk_size: usize,
+    next_sequence: chunky::Value<AtomicUsize>,
+    write_chunk: AtomicPtr<Chunk>,
+    len: AtomicUsize,
+    epoch: AtomicUsize,
+    n_active_writers: [AtomicUsize; 2],
+    reader: UnsafeCell<ReaderState>,
+}
+
+unsafe impl Send for MultiWriterQueue {}
+unsafe impl Sync for MultiWriterQueue {}
+
+impl MultiWriterQueue {
+    /// Create a new queue, or load it if it was persisted in `storage` under `ident`
+    pub fn new(ident: &chunky::Ident, storage: Arc<dyn ThreadSafeStorage>, typical_chunk_size: usize) -> Self {
+        let local_storage = crate::thread_safe_storage::LocalStorage::new_rc(&storage);
+        // sequence numbers start at 1, so 0 can mark the lack of a next chunk
+        let next_sequence = chunky::Value::load_or_default(ident.sub("next"), AtomicUsize::new(1), local_storage.clone());
+        let mut position = chunky::Value::load_or_default(ident.sub("reader"), ReaderPosition {
+            sequence: NO_NEXT_CHUNK,
+            offset: 0,
+        }, local_storage);
+
+        let (first_chunk, last_chunk, len) = if position.sequence == NO_NEXT_CHUNK {
+            let sequence = next_sequence.fetch_add(1, Ordering::SeqCst);
+            let first_chunk = Chunk::create_boxed(&*storage, ident, sequence, typical_chunk_size);
+            position.sequence = sequence;
+            (first_chunk, first_chunk, 0)
+        } else {
+            unsafe { Self::load_chunks(ident, &*storage, &position) }
+        };
+
+        MultiWriterQueue {
+            ident: ident.clone(),
+            storage,
+            typical_chunk_size,
+            next_sequence,
+            write_chunk: AtomicPtr::new(last_chunk),
+            len: AtomicUsize::new(len),
+            epoch: AtomicUsize::new(0),
+            n_active_writers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            reader: UnsafeCell::new(ReaderState {
+                chunk: first_chunk,
+                position,
+                retired_chunks: Vec::new(),
+            }),
+        }
+    }
+
+    /// Load all persisted chunks from the one the reader is in on,
+    /// returning the first and last chunk and the number of queued items
+    unsafe fn load_chunks(ident: &chunky::Ident, storage: &dyn ThreadSafeStorage, position: &ReaderPosition) -> (*mut Chunk, *mut Chunk, usize) {
+        let first_chunk = Box::into_raw(Box::new(Chunk::from_memory(storage.load_chunk(ident.sub(position.sequence)), position.sequence)));
+        let mut chunk_ptr = first_chunk;
+        let mut offset = position.offset;
+        let mut len = 0;
+
+        loop {
+            let chunk = &mut *chunk_ptr;
+            let (n_committed, end) = chunk.scan(offset);
+            chunk.reserved.store(end, Ordering::SeqCst);
+            len += n_committed;
+
+            let mut next_sequence = *chunk.link();
+
+            if end < chunk.capacity {
+                // chunks after an entry that was never committed are unreachable, so forget them
+                *chunk.link() = NO_NEXT_CHUNK;
+                while next_sequence != NO_NEXT_CHUNK {
+                    let lost_chunk = Chunk::from_memory(storage.load_chunk(ident.sub(next_sequence)), next_sequence);
+                    next_sequence = *lost_chunk.link();
+                    storage.forget_chunk(lost_chunk.memory);
+                }
+            }
+
+            if next_sequence == NO_NEXT_CHUNK {
+                return (first_chunk, chunk_ptr, len);
+            }
+
+            let next_chunk_ptr = Box::into_raw(Box::new(Chunk::from_memory(storage.load_chunk(ident.sub(next_sequence)), next_sequence)));
+            chunk.next.store(next_chunk_ptr, Ordering::SeqCst);
+            chunk_ptr = next_chunk_ptr;
+            offset = 0;
+        }
+    }
+
+    /// Number of committed items in the queue
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Enqueue an item of a given size, which is written by `write`
+    /// to the pointer it gets passed. Can be called from any thread.
+    pub fn enqueue<F: FnOnce(*mut u8)>(&self, size: usize, write: F) {
+        let entry_size = aligned_entry_size(size);
+        let _active_writer = ActiveWriter::enter(&self.epoch, &self.n_active_writers);
+
+        let (header, payload_ptr) = loop {
+            let chunk_ptr = self.write_chunk.load(Ordering::SeqCst);
+            let chunk = unsafe { &*chunk_ptr };
+            let offset = chunk.reserved.fetch_add(entry_size, Ordering::SeqCst);
+
+            if offset + entry_size <= chunk.capacity {
+                break unsafe { (chunk.header(offset), chunk.payload_ptr(offset)) };
+            }
+
+            if offset < chunk.capacity {
+                // we're the first to overflow this chunk,
+                // so we tell the reader to continue in the next one
+                unsafe { chunk.header(offset) }.store(END_OF_CHUNK, Ordering::Release);
+            }
+
+            let next_chunk_ptr = chunk.next.load(Ordering::SeqCst);
+
+            if next_chunk_ptr.is_null() {
+                let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+                let new_chunk_ptr = Chunk::create_boxed(
+                    &*self.storage,
+                    &self.ident,
+                    sequence,
+                    ::std::cmp::max(self.typical_chunk_size, entry_size),
+                );
+                let new_chunk = unsafe { &*new_chunk_ptr };
+                // claim the beginning of the new chunk for ourselves before publishing it
+                new_chunk.reserved.store(entry_size, Ordering::SeqCst);
+
+                match chunk.next.compare_exchange(
+                    ::std::ptr::null_mut(),
+                    new_chunk_ptr,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => {
+                        unsafe { *chunk.link() = sequence };
+                        let _ = self.write_chunk.compare_exchange(
+                            chunk_ptr,
+                            new_chunk_ptr,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        );
+                        break unsafe { (new_chunk.header(0), new_chunk.payload_ptr(0)) };
+                    }
+                    Err(_) => {
+                        // somebody else was faster, retry in their chunk
+                        let new_chunk = unsafe { Box::from_raw(new_chunk_ptr) };
+                        self.storage.forget_chunk(new_chunk.memory);
+                    }
+                }
+            } else {
+                // help moving on the write chunk
+                let _ = self.write_chunk.compare_exchange(
+                    chunk_ptr,
+                    next_chunk_ptr,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                );
+            }
+        };
+
+        let entry = UncommittedEntry { header, entry_size };
+        write(payload_ptr);
+        entry.commit(&self.len);
+    }
+
+    /// Dequeue an item. Returns a pointer to the item in the queue, unless the queue is empty.
+    ///
+    /// Must only be called by one reader at a time. The pointer stays valid
+    /// until the next call of `drop_old_chunks`.
+    pub unsafe fn dequeue(&self) -> Option<*const u8> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let reader = &mut *self.reader.get();
+
+        loop {
+            let chunk = &*reader.chunk;
+            let offset = reader.position.offset;
+
+            let header = if offset + HEADER_SIZE > chunk.capacity {
+                END_OF_CHUNK
+            } else {
+                chunk.header(offset).load(Ordering::Acquire)
+            };
+
+            match header {
+                NOT_COMMITTED => {
+                    // a writer reserved this entry before the one we know
+                    // is committed, but is still busy writing it
+                    spin_loop_hint();
+                }
+                END_OF_CHUNK => {
+                    let next_chunk_ptr = chunk.next.load(Ordering::SeqCst);
+                    if next_chunk_ptr.is_null() {
+                        spin_loop_hint();
+                    } else {
+                        self.retire(reader, next_chunk_ptr);
+                    }
+                }
+                aborted if aborted & ABORTED != 0 => {
+                    reader.position.offset += aborted & !ABORTED;
+                }
+                entry_size => {
+                    let payload_ptr = chunk.payload_ptr(offset);
+                    reader.position.offset += entry_size;
+                    self.len.fetch_sub(1, Ordering::SeqCst);
+                    return Some(payload_ptr);
+                }
+            }
+        }
+    }
+
+    /// Move the reader on to the next chunk, retiring the one it was in
+    unsafe fn retire(&self, reader: &mut ReaderState, next_chunk_ptr: *mut Chunk) {
+        // make sure no writer starts using the retired chunk from now on...
+        let _ = self.write_chunk.compare_exchange(
+            reader.chunk,
+            next_chunk_ptr,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        // ...so only writers that entered in this epoch or earlier might still use it
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        reader.retired_chunks.push((reader.chunk, epoch));
+
+        reader.chunk = next_chunk_ptr;
+        reader.position.sequence = (*next_chunk_ptr).sequence;
+        reader.position.offset = 0;
+    }
+
+    /// Free chunks which have already been read, if no writer might still be using them.
+    /// Must only be called by the reader.
+    pub unsafe fn drop_old_chunks(&self) {
+        let reader = &mut *self.reader.get();
+
+        // Moving on the epoch twice is enough to free all retired chunks,
+        // if no writer that entered before they were retired is still active
+        for _ in 0..2 {
+            if reader.retired_chunks.is_empty() {
+                return;
+            }
+
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            if self.n_active_writers[(epoch + 1) % 2].load(Ordering::SeqCst) == 0 {
+                // all writers of the epoch before are done, the only ones left
+                // entered in the current epoch - and all new ones enter in the next
+                self.epoch.store(epoch + 1, Ordering::SeqCst);
+            }
+
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let storage = &self.storage;
+            reader.retired_chunks.retain(|&(chunk_ptr, retired_in_epoch)| {
+                if retired_in_epoch + 2 <= epoch {
+                    storage.forget_chunk(Box::from_raw(chunk_ptr).memory);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    }
+}
+
+impl Drop for MultiWriterQueue {
+    fn drop(&mut self) {
+        let reader = unsafe { &mut *self.reader.get() };
+
+        for (chunk_ptr, _) in reader.retired_chunks.drain(..) {
+            self.storage.forget_chunk(unsafe { Box::from_raw(chunk_ptr) }.memory);
+        }
+
+        // the chunks still in use are only unloaded, so they stay persisted
+        let mut chunk_ptr = reader.chunk;
+        while !chunk_ptr.is_null() {
+            let chunk = unsafe { Box::from_raw(chunk_ptr) };
+            chunk_ptr = chunk.next.load(Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+fn heap_queue(typical_chunk_size: usize) -> MultiWriterQueue {
+    MultiWriterQueue::new(&"test_queue".into(), Arc::new(chunky::HeapStorage), typical_chunk_size)
+}
+
+#[cfg(test)]
+fn read_all(queue: &MultiWriterQueue) -> Vec<(usize, usize)> {
+    let mut items = Vec::new();
+    while let Some(ptr) = unsafe { queue.dequeue() } {
+        items.push(unsafe { *(ptr as *const (usize, usize)) });
+    }
+    unsafe { queue.drop_old_chunks() };
+    items
+}
+
+#[test]
+fn test_items_of_each_writer_stay_in_order() {
+    const N_WRITERS: usize = 4;
+    const N_ITEMS_PER_WRITER: usize = 10_000;
+
+    // small chunks, to roll over to new chunks a lot while writers are racing
+    let queue = Arc::new(heap_queue(256));
+    let writers: Vec<_> = (0..N_WRITERS)
+        .map(|writer| {
+            let queue = Arc::clone(&queue);
+            ::std::thread::spawn(move || {
+                for i in 0..N_ITEMS_PER_WRITER {
+                    queue.enqueue(::std::mem::size_of::<(usize, usize)>(), |ptr| unsafe {
+                        *(ptr as *mut (usize, usize)) = (writer, i)
+                    });
+                }
+            })
+        })
+        .collect();
+
+    let mut next_expected = vec![0; N_WRITERS];
+    let mut n_read = 0;
+    while n_read < N_WRITERS * N_ITEMS_PER_WRITER {
+        if let Some(ptr) = unsafe { queue.dequeue() } {
+            let (writer, i) = unsafe { *(ptr as *const (usize, usize)) };
+            assert_eq!(i, next_expected[writer]);
+            next_expected[writer] += 1;
+            n_read += 1;
+        }
+        unsafe { queue.drop_old_chunks() };
+    }
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert!(queue.is_empty());
+    assert_eq!(next_expected, vec![N_ITEMS_PER_WRITER; N_WRITERS]);
+}
+
+#[test]
+fn test_items_roll_over_into_new_chunks() {
+    let queue = heap_queue(64);
+
+    // fill up several chunks, including items larger than a whole chunk
+    for i in 0..20 {
+        let size = if i % 5 == 0 { 100 } else { 16 };
+        queue.enqueue(size, |ptr| unsafe { *(ptr as *mut (usize, usize)) = (i, size) });
+    }
+    assert_eq!(queue.len(), 20);
+
+    let items = read_all(&queue);
+    assert_eq!(items.len(), 20);
+    for (i, &(item, size)) in items.iter().enumerate() {
+        assert_eq!(item, i);
+        assert_eq!(size, if i % 5 == 0 { 100 } else { 16 });
+    }
+
+    // the queue stays usable after the read chunks were dropped
+    queue.enqueue(16, |ptr| unsafe { *(ptr as *mut (usize, usize)) = (20, 16) });
+    assert_eq!(read_all(&queue), vec![(20, 16)]);
+}
+
+#[test]
+fn test_entries_of_panicking_writers_are_skipped() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let queue = heap_queue(1024);
+    queue.enqueue(16, |ptr| unsafe { *(ptr as *mut (usize, usize)) = (1, 1) });
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        queue.enqueue(16, |_ptr| panic!("writer panicked"));
+    }));
+    assert!(result.is_err());
+    queue.enqueue(16, |ptr| unsafe { *(ptr as *mut (usize, usize)) = (3, 3) });
+
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.n_active_writers[0].load(Ordering::SeqCst), 0);
+    assert_eq!(queue.n_active_writers[1].load(Ordering::SeqCst), 0);
+    assert_eq!(read_all(&queue), vec![(1, 1), (3, 3)]);
+}
+
+#[test]
+fn test_read_chunks_are_freed_while_newer_writers_are_active() {
+    let queue = heap_queue(64);
+    let fill_chunk = |first: usize| {
+        for i in first..first + 4 {
+            queue.enqueue(8, |ptr| unsafe { *(ptr as *mut (usize, usize)) = (i, 0) });
+        }
+    };
+
+    // a writer that might still be using the first chunk
+    let old_writer = ActiveWriter::enter(&queue.epoch, &queue.n_active_writers);
+    fill_chunk(0);
+    fill_chunk(4);
+    assert_eq!(read_all(&queue).len(), 8);
+    let n_retired = || unsafe { (*queue.reader.get()).retired_chunks.len() };
+    assert!(n_retired() > 0);
+
+    // writers keep coming, but only the one from before the chunks were retired matters
+    let newer_writer = ActiveWriter::enter(&queue.epoch, &queue.n_active_writers);
+    unsafe { queue.drop_old_chunks() };
+    assert!(n_retired() > 0);
+
+    drop(old_writer);
+    unsafe { queue.drop_old_chunks() };
+    assert_eq!(n_retired(), 0);
+
+    // chunks retired now still wait for the writer that is active
+    fill_chunk(8);
+    fill_chunk(12);
+    assert_eq!(read_all(&queue).len(), 8);
+    assert!(n_retired() > 0);
+    drop(newer_writer);
+    unsafe { queue.drop_old_chunks() };
+    assert_eq!(n_retired(), 0);
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_queued_items_are_persisted() {
+    let directory = ::std::env::temp_dir().join(format!("kay_mwq_test_{}", ::std::process::id()));
+    ::std::fs::create_dir_all(&directory).unwrap();
+    let storage = || -> Arc<dyn ThreadSafeStorage> { Arc::new(chunky::MmapStorage::new(directory.clone())) };
+    let ident: chunky::Ident = "persisted_queue".into();
+
+    {
+        let queue = MultiWriterQueue::new(&ident, storage(), 64);
+        for i in 0..10 {
+            queue.enqueue(16, |ptr| unsafe { *(ptr as *mut (usize, usize)) = (i, 16) });
+        }
+        for _ in 0..3 {
+            unsafe { queue.dequeue() };
+        }
+        unsafe { queue.drop_old_chunks() };
+    }
+
+    {
+        let queue = MultiWriterQueue::new(&ident, storage(), 64);
+        assert_eq!(queue.len(), 7);
+        queue.enqueue(16, |ptr| unsafe { *(ptr as *mut (usize, usize)) = (10, 16) });
+        let items = read_all(&queue);
+        assert_eq!(items, (3..11).map(|i| (i, 16)).collect::<Vec<_>>());
+    }
+
+    ::std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -1,38 +1,68 @@
 use crate::messaging::HandlerFnRef;
 use crate::actor_system::{World};
 use crate::tuning::Tuning;
-use chunky;
 use crate::id::RawID;
 use crate::messaging::Fate;
-use super::ActorStateVTable;
+use super::{ActorStateVTable, OnPanic, PanicPolicy};
+use crate::thread_safe_storage::{LocalStorage, ThreadSafeStorage};
 use compact::Compact;
+use ::std::panic::{catch_unwind, AssertUnwindSafe};
 use ::std::rc::Rc;
+use ::std::sync::{Arc, Mutex, MutexGuard};
 
 mod slot_map;
-use self::slot_map::{SlotMap, SlotIndices};
+pub(crate) use self::slot_map::SlotMap;
+use self::slot_map::SlotIndices;
 
 pub struct InstanceStore {
     instances: chunky::MultiArena,
-    slot_map: SlotMap,
+    /// Shared, so that handlers of other classes can allocate IDs
+    /// for new instances of this class while it is handled on another thread
+    slot_map: Arc<Mutex<SlotMap>>,
     pub n_instances: chunky::Value<usize>,
+    /// IDs of instances removed since the last call to `take_deaths`
+    deaths: Vec<RawID>,
+}
+
+// The storage `Rc`s of the instances are only shared within the store, so the store
+// can be moved to another thread as a whole. The chunks themselves come from
+// a `ThreadSafeStorage`, which allows using and dropping them on other threads.
+unsafe impl Send for InstanceStore {}
+
+/// Allocate an ID in the slot map of a class, which might be handled on another thread
+pub(crate) fn allocate_id_in(slot_map: &Mutex<SlotMap>, base_id: RawID) -> RawID {
+    let (instance_id, version) = slot_map.lock().unwrap().allocate_id();
+    RawID::new(
+        base_id.type_id,
+        instance_id as u32,
+        base_id.machine,
+        version as u8,
+    )
 }
 
 impl InstanceStore {
-    pub fn new(ident: &chunky::Ident, typical_size: usize, storage: Rc<dyn chunky::ChunkStorage>, tuning: &Tuning) -> InstanceStore {
+    pub fn new(ident: &chunky::Ident, typical_size: usize, storage: &Arc<dyn ThreadSafeStorage>, tuning: &Tuning) -> InstanceStore {
+        let local_storage = LocalStorage::new_rc(storage);
         InstanceStore {
                 instances: chunky::MultiArena::new(
                     ident.sub("inst"),
                     tuning.instance_chunk_size,
                     typical_size,
-                    Rc::clone(&storage)
+                    Rc::clone(&local_storage)
                 ),
-                n_instances: chunky::Value::load_or_default(ident.sub("n"), 0, Rc::clone(&storage)),
-                slot_map: SlotMap::new(&ident.sub("slts"), storage, tuning),
+                n_instances: chunky::Value::load_or_default(ident.sub("n"), 0, local_storage),
+                slot_map: Arc::new(Mutex::new(SlotMap::new(&ident.sub("slts"), LocalStorage::new_rc(storage), tuning))),
+                deaths: Vec::new(),
             }
     }
 
-    fn allocate_instance_id(&mut self) -> (usize, usize) {
-        self.slot_map.allocate_id()
+    /// The slot map is only ever locked briefly, never while a handler runs
+    fn slot_map(&self) -> MutexGuard<SlotMap> {
+        self.slot_map.lock().unwrap()
+    }
+
+    pub(crate) fn shared_slot_map(&self) -> Arc<Mutex<SlotMap>> {
+        Arc::clone(&self.slot_map)
     }
 
     fn at_index_mut(&mut self, index: SlotIndices) -> *mut () {
@@ -40,19 +70,18 @@ impl InstanceStore {
     }
 
     fn at_mut(&mut self, id: usize, version: u8) -> Option<*mut ()> {
-        self.slot_map
-            .indices_of(id, version)
-            .map(move |index| self.at_index_mut(index))
+        let indices = self.slot_map().indices_of(id, version);
+        indices.map(move |index| self.at_index_mut(index))
+    }
+
+    pub fn contains(&self, id: RawID) -> bool {
+        self.slot_map()
+            .indices_of(id.instance_id as usize, id.version)
+            .is_some()
     }
 
     pub unsafe fn allocate_id(&mut self, base_id: RawID) -> RawID {
-        let (instance_id, version) = self.allocate_instance_id();
-        RawID::new(
-            base_id.type_id,
-            instance_id as u32,
-            base_id.machine,
-            version as u8,
-        )
+        allocate_id_in(&self.slot_map, base_id)
     }
 
     pub unsafe fn add(&mut self, initial_state: *mut (), state_v_table: &ActorStateVTable, increment_n_instances: bool) {
@@ -60,7 +89,7 @@ impl InstanceStore {
         let size = (state_v_table.total_size_bytes)(initial_state);
         let (slot_ptr, index) = self.instances.push(size);
 
-        self.slot_map
+        self.slot_map()
             .associate(id.instance_id as usize, index.into());
 
         if increment_n_instances {*self.n_instances += 1}
@@ -71,7 +100,7 @@ impl InstanceStore {
     fn swap_remove(&mut self, indices: SlotIndices, state_v_table: &ActorStateVTable) -> bool {
         match self.instances.swap_remove_within_bin(indices.into()) {
             Some(swapped_actor) => {
-                self.slot_map
+                self.slot_map()
                     .associate((state_v_table.get_raw_id)(swapped_actor as *const ()).instance_id as usize, indices);
                 true
             }
@@ -79,27 +108,46 @@ impl InstanceStore {
         }
     }
 
-    fn remove(&mut self, id: RawID, state_v_table: &ActorStateVTable) {
+    pub fn remove(&mut self, id: RawID, state_v_table: &ActorStateVTable, reserve_id: bool) {
         let i = self
-            .slot_map
+            .slot_map()
             .indices_of_no_version_check(id.instance_id as usize)
             .expect("actor should exist when removing");
-        self.remove_at_index(i, id, state_v_table);
+        self.remove_at_index(i, id, state_v_table, reserve_id);
     }
 
-    fn remove_at_index(&mut self, i: SlotIndices, id: RawID, state_v_table: &ActorStateVTable) {
+    /// Remove an instance, either freeing its ID or (if `reserve_id` is set)
+    /// keeping it for a replacement instance, see `release_reserved_id`
+    fn remove_at_index(&mut self, i: SlotIndices, id: RawID, state_v_table: &ActorStateVTable, reserve_id: bool) {
         // TODO: not sure if this is the best place to drop actor state
         let old_actor_ptr = self.at_index_mut(i);
         (state_v_table.drop)(old_actor_ptr);
         self.swap_remove(i, state_v_table);
-        self.slot_map
-            .free(id.instance_id as usize, id.version as usize);
+        if reserve_id {
+            self.slot_map()
+                .reserve_next_version(id.instance_id as usize, id.version as usize);
+        } else {
+            self.slot_map()
+                .free(id.instance_id as usize, id.version as usize);
+        }
         *self.n_instances -= 1;
+        self.deaths.push(id);
+    }
+
+    pub fn take_deaths(&mut self) -> Vec<RawID> {
+        ::std::mem::replace(&mut self.deaths, Vec::new())
+    }
+
+    /// Free the ID of an instance that was removed after a panic
+    /// while its class had the `Restart` policy, if it is not restarted after all
+    pub fn release_reserved_id(&mut self, id: RawID) {
+        self.slot_map()
+            .free(id.instance_id as usize, id.version as usize + 1);
     }
 
     fn resize(&mut self, id: usize, state_v_table: &ActorStateVTable) -> bool {
         let index = self
-            .slot_map
+            .slot_map()
             .indices_of_no_version_check(id)
             .expect("actor should exist when resizing");
         self.resize_at_index(index, state_v_table)
@@ -111,12 +159,31 @@ impl InstanceStore {
         self.swap_remove(old_i, state_v_table)
     }
 
-    pub fn receive_instance(&mut self, recipient_id: RawID, packet_ptr: *const (), world: &mut World, handler: &Box<HandlerFnRef>, state_v_table: &ActorStateVTable) {
+    pub fn for_each_instance<F: FnMut(*mut ())>(&mut self, mut f: F) {
+        let bin_indices_and_lens: Vec<_> = self.instances.populated_bin_indices_and_lens().collect();
+
+        for (bin_index, len) in bin_indices_and_lens {
+            for slot in 0..len {
+                f(self.at_index_mut(SlotIndices::new(bin_index, slot)));
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn receive_instance(&mut self, recipient_id: RawID, packet_ptr: *const (), world: &mut World, handler: &HandlerFnRef, state_v_table: &ActorStateVTable, panic_policy: PanicPolicy, on_panic: OnPanic) -> bool {
         if let Some(actor) = self.at_mut(
             recipient_id.instance_id as usize,
             recipient_id.version,
         ) {
-            let fate = handler(actor, packet_ptr, world);
+            let mut reserve_id = false;
+            let fate = match catch_unwind(AssertUnwindSafe(|| handler(actor, packet_ptr, world))) {
+                Ok(fate) => fate,
+                Err(payload) => {
+                    on_panic(recipient_id, payload);
+                    reserve_id = panic_policy == PanicPolicy::Restart;
+                    panic_policy.fate_after_panic()
+                }
+            };
             let is_still_compact = (state_v_table.is_still_compact)(actor);
 
             match fate {
@@ -125,14 +192,16 @@ impl InstanceStore {
                         self.resize(recipient_id.instance_id as usize, &state_v_table);
                     }
                 }
-                Fate::Die => self.remove(recipient_id, &state_v_table),
+                Fate::Die => self.remove(recipient_id, &state_v_table, reserve_id),
             }
+
+            true
         } else {
-            eprintln!("Could not find actor {}", recipient_id.format(world));
+            false
         }
     }
 
-    pub fn receive_broadcast(&mut self, packet_ptr: *const (), world: &mut World, handler: &Box<HandlerFnRef>, state_v_table: &ActorStateVTable) {
+    pub fn receive_broadcast(&mut self, packet_ptr: *const (), world: &mut World, handler: &HandlerFnRef, state_v_table: &ActorStateVTable, panic_policy: PanicPolicy, on_panic: OnPanic) {
     // this function has to deal with the fact that during the iteration,
     // receivers of the broadcast can be resized
     // and thus removed from a bin, swapping in either
@@ -150,10 +219,19 @@ impl InstanceStore {
 
         for _ in 0..recipients_todo {
             let index = SlotIndices::new(bin_index, slot);
+            let mut reserve_id = false;
             let (fate, is_still_compact, id) = {
                 let actor = self.at_index_mut(index);
-                let fate = handler(actor, packet_ptr, world);
-                (fate, actor.is_still_compact(), (state_v_table.get_raw_id)(actor))
+                let id = (state_v_table.get_raw_id)(actor);
+                let fate = match catch_unwind(AssertUnwindSafe(|| handler(actor, packet_ptr, world))) {
+                    Ok(fate) => fate,
+                    Err(payload) => {
+                        on_panic(id, payload);
+                        reserve_id = panic_policy == PanicPolicy::Restart;
+                        panic_policy.fate_after_panic()
+                    }
+                };
+                (fate, actor.is_still_compact(), id)
             };
 
             let repeat_slot = match fate {
@@ -175,7 +253,7 @@ impl InstanceStore {
                     }
                 }
                 Fate::Die => {
-                    self.remove_at_index(index, id, state_v_table);
+                    self.remove_at_index(index, id, state_v_table, reserve_id);
                     // this should also work in the case where the "resized" actor
                     // itself is added to the same bin again
                     let swapped_in_another_receiver =
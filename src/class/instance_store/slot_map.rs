@@ -1,4 +1,3 @@
-use chunky;
 use std::rc::Rc;
 use crate::tuning::Tuning;
 
@@ -50,6 +49,9 @@ pub struct SlotMap {
     free_ids_with_versions: chunky::Vector<(usize, usize)>,
 }
 
+// Like `InstanceStore`, a slot map has its own storage `Rc` for chunks of a `ThreadSafeStorage`
+unsafe impl Send for SlotMap {}
+
 impl SlotMap {
     pub fn new(ident: &chunky::Ident, storage: Rc<dyn chunky::ChunkStorage>, tuning: &Tuning) -> Self {
         SlotMap {
@@ -94,6 +96,15 @@ impl SlotMap {
         self.entries.at(id).cloned()
     }
 
+    /// Invalidate the current version of an ID, without making the ID available again.
+    /// This lets an instance be replaced under the same ID with the next version.
+    pub fn reserve_next_version(&mut self, id: usize, version: usize) {
+        *self
+            .last_known_version
+            .at_mut(id)
+            .expect("should have last known version when reserving") = (version + 1) as u8;
+    }
+
     pub fn free(&mut self, id: usize, version: usize) {
         *self
             .last_known_version
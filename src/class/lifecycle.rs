@@ -0,0 +1,267 @@
+use crate::actor_system::World;
+use crate::id::RawID;
+use crate::messaging::Fate;
+use crate::monitor::{Down, DownReason};
+use crate::panic_report::{payload_to_string, CaughtPanic};
+use crate::supervision::{RestartIntensityExceeded, Supervision};
+use crate::type_registry::ShortTypeId;
+use super::{ActorVTable, InstanceStore};
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// What happens when a message handler of an actor class panics.
+/// Panics are always contained to the class they happen in.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PanicPolicy {
+    /// Remove the instance that panicked, as if it returned `Fate::Die`
+    KillInstance,
+    /// Keep the instance (in whatever state the panic left it in) and go on with the next message
+    SkipMessage,
+    /// Only handle messages marked as `Critical` in this class from now on,
+    /// allowing inspection of the panicked class
+    FreezeClass,
+    /// Remove the instance that panicked and create a new one under the same ID
+    /// with the next version, as set up with `ActorSystem::supervise` or `ActorSystem::supervise_via_spawner`
+    Restart,
+}
+
+impl Default for PanicPolicy {
+    fn default() -> Self {
+        PanicPolicy::FreezeClass
+    }
+}
+
+impl PanicPolicy {
+    pub(crate) fn fate_after_panic(self) -> Fate {
+        match self {
+            PanicPolicy::KillInstance | PanicPolicy::Restart => Fate::Die,
+            PanicPolicy::SkipMessage | PanicPolicy::FreezeClass => Fate::Live,
+        }
+    }
+}
+
+/// Keeps track of panics and deaths of the instances of a class
+pub struct Lifecycle {
+    pub panic_policy: PanicPolicy,
+    /// Did a handler of this class panic while the `FreezeClass` policy was set?
+    /// If so, only messages marked as `Critical` are still handled by this class.
+    pub frozen: bool,
+    /// Panics caught since they were last collected by the actor system
+    pub(crate) caught_panics: Vec<CaughtPanic>,
+    pub(crate) supervision: Option<Supervision>,
+    /// Instances removed after a panic, to be restarted under their next ID version,
+    /// together with the type of the message they panicked in
+    pending_restarts: Vec<(RawID, ShortTypeId)>,
+    /// Watchers of monitored instances
+    monitors: HashMap<RawID, Vec<RawID>>,
+    /// Messages to instances that don't exist, as (recipient, message type, encoded packet),
+    /// since they were last collected by the actor system
+    pub dead_letters: Vec<(RawID, ShortTypeId, Vec<u8>)>,
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Lifecycle {
+            panic_policy: PanicPolicy::default(),
+            frozen: false,
+            caught_panics: Vec::new(),
+            supervision: None,
+            pending_restarts: Vec::new(),
+            monitors: HashMap::new(),
+            dead_letters: Vec::new(),
+        }
+    }
+
+    pub fn record_panic(&mut self, id: RawID, message_type: ShortTypeId, payload: Box<dyn Any + Send>) {
+        self.caught_panics.push(CaughtPanic {
+            recipient: id,
+            message_type,
+            payload: payload_to_string(&*payload),
+        });
+        if self.panic_policy == PanicPolicy::FreezeClass {
+            self.frozen = true;
+        }
+    }
+
+    /// Record a panic of an instance, which is then dealt with according to `panic_policy`
+    pub fn instance_panicked(&mut self, id: RawID, message_type: ShortTypeId, payload: Box<dyn Any + Send>) {
+        self.record_panic(id, message_type, payload);
+        if self.panic_policy == PanicPolicy::Restart {
+            self.pending_restarts.push((id, message_type));
+        }
+    }
+
+    pub fn add_monitor(&mut self, target: RawID, watcher: RawID) {
+        self.monitors.entry(target).or_insert_with(Vec::new).push(watcher);
+    }
+
+    /// All watchers of monitored instances of this class
+    pub fn watchers(&self) -> impl Iterator<Item = RawID> + '_ {
+        self.monitors.values().flat_map(|watchers| watchers.iter().cloned())
+    }
+
+    /// Stop monitoring instances of this class for the given watchers
+    pub fn forget_watchers(&mut self, dead_watchers: &[RawID]) {
+        self.monitors.retain(|_target, watchers| {
+            watchers.retain(|watcher| !dead_watchers.contains(watcher));
+            !watchers.is_empty()
+        });
+    }
+
+    /// Restart panicked instances and notify watchers of instances that died
+    /// while handling the last messages
+    pub fn after_dispatch(&mut self, instance_store: &mut InstanceStore, v_table: &ActorVTable, world: &mut World) {
+        self.restart_panicked_instances(instance_store, v_table, world);
+
+        for id in instance_store.take_deaths() {
+            if let Some(watchers) = self.monitors.remove(&id) {
+                for watcher in watchers {
+                    world.send(watcher, Down { id, reason: DownReason::Died });
+                }
+            }
+        }
+    }
+
+    /// Restart instances that were removed after a panic, as long as the
+    /// restart intensity allows it. Otherwise, or if restarting panics itself,
+    /// their IDs are freed and the supervisor of the class (if any) is notified.
+    fn restart_panicked_instances(&mut self, instance_store: &mut InstanceStore, v_table: &ActorVTable, world: &mut World) {
+        if self.pending_restarts.is_empty() {
+            return;
+        }
+
+        let turn = world.current_turn();
+        let mut failed_restarts = Vec::new();
+
+        for (failed, message_type) in self.pending_restarts.drain(..) {
+            let restart_allowed = match self.supervision {
+                Some(ref mut supervision) => supervision.allow_restart(turn),
+                None => false,
+            };
+
+            let supervision = match self.supervision {
+                Some(ref supervision) => supervision,
+                None => {
+                    instance_store.release_reserved_id(failed);
+                    continue;
+                }
+            };
+
+            if restart_allowed {
+                let restarted_id = RawID {
+                    version: failed.version.wrapping_add(1),
+                    ..failed
+                };
+                match catch_unwind(AssertUnwindSafe(|| {
+                    (supervision.restart)(restarted_id, world, instance_store, v_table)
+                })) {
+                    Ok(()) => continue,
+                    Err(payload) => failed_restarts.push((restarted_id, message_type, payload)),
+                }
+            }
+
+            instance_store.release_reserved_id(failed);
+            if let Some(supervisor) = supervision.supervisor {
+                world.send(supervisor, RestartIntensityExceeded {
+                    failed,
+                    max_restarts: supervision.max_restarts,
+                    within_turns: supervision.within_turns,
+                });
+            }
+        }
+
+        for (id, message_type, payload) in failed_restarts {
+            self.record_panic(id, message_type, payload);
+        }
+    }
+
+    /// Treat a supervisor instance as failed itself, because an instance it supervises
+    /// exceeded its restart intensity - unless the class of the supervisor handles
+    /// `RestartIntensityExceeded` itself. This way, failures propagate up a tree
+    /// of supervised classes until they reach a supervisor that handles them.
+    pub fn supervised_instance_failed(&mut self, supervisor: RawID, message_type: ShortTypeId, failed: RawID, instance_store: &mut InstanceStore, v_table: &ActorVTable) {
+        if !instance_store.contains(supervisor) {
+            return;
+        }
+        self.instance_panicked(
+            supervisor,
+            message_type,
+            Box::new(format!("Restart intensity of supervised instance {} exceeded", failed)),
+        );
+        if let Fate::Die = self.panic_policy.fate_after_panic() {
+            let reserve_id = self.panic_policy == PanicPolicy::Restart;
+            instance_store.remove(supervisor, &v_table.state_v_table, reserve_id);
+        }
+    }
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+fn system_with_policy(policy: PanicPolicy) -> (crate::actor_system::ActorSystem, crate::testing::AliceID, crate::testing::BobID) {
+    use crate::testing::{test_system, Alice, Bob};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.set_panic_policy::<Alice>(policy);
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+    (system, alice, bob)
+}
+
+#[test]
+fn test_kill_instance_policy_removes_panicked_instance() {
+    use crate::id::TypedID;
+    use crate::testing::{Alice, Explode, Note};
+
+    let (mut system, alice, _) = system_with_policy(PanicPolicy::KillInstance);
+    let other_alice = Alice::spawn(&mut system);
+    system.send(alice.as_raw(), Explode);
+    system.process_all_messages();
+
+    assert!(!system.panic_happened);
+    assert_eq!(system.get_instance_counts()["Alice"], 1);
+    system.send(other_alice.as_raw(), Note(1));
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, other_alice), vec![1]);
+}
+
+#[test]
+fn test_skip_message_policy_keeps_panicked_instance() {
+    use crate::id::TypedID;
+    use crate::testing::{Alice, Explode, Note};
+
+    let (mut system, alice, _) = system_with_policy(PanicPolicy::SkipMessage);
+    system.send(alice.as_raw(), Note(1));
+    system.send(alice.as_raw(), Explode);
+    system.send(alice.as_raw(), Note(2));
+    system.process_all_messages();
+
+    assert!(!system.panic_happened);
+    assert_eq!(Alice::received(&mut system, alice), vec![1, 2]);
+    assert_eq!(system.panic_reports().len(), 1);
+    assert_eq!(&*system.panic_reports()[0].payload, "Exploded");
+}
+
+#[test]
+fn test_freeze_class_policy_only_freezes_panicked_class() {
+    use crate::id::TypedID;
+    use crate::testing::{Alice, Bob, Explode, Note};
+
+    let (mut system, alice, bob) = system_with_policy(PanicPolicy::FreezeClass);
+    system.send(alice.as_raw(), Explode);
+    system.send(alice.as_raw(), Note(1));
+    system.send(bob.as_raw(), Note(2));
+    system.process_all_messages();
+
+    assert!(!system.panic_happened);
+    // inspecting the frozen class is still possible with critical messages
+    assert_eq!(Alice::received(&mut system, alice), vec![]);
+    assert_eq!(Bob::received(&mut system, bob), vec![2]);
+}
@@ -2,45 +2,58 @@ use crate::messaging::HandlerFnRef;
 use crate::messaging::Message;
 use crate::actor::Actor;
 use crate::type_registry::ShortTypeId;
-use crate::actor_system::{World, MAX_MESSAGE_TYPES};
+use crate::actor_system::World;
 use crate::id::{broadcast_instance_id, RawID, TypedID};
 use crate::messaging::{Fate, Packet};
+use crate::monitor::{Down, DownReason, Monitor};
+use crate::supervision::RestartIntensityExceeded;
 use crate::tuning::Tuning;
+use crate::type_id_map::{SparseTypeIdMap, TypeIdCounts};
 use compact::Compact;
-use std::rc::Rc;
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use crate::thread_safe_storage::ThreadSafeStorage;
 
 mod instance_store;
-use self::instance_store::InstanceStore;
+pub use self::instance_store::InstanceStore;
+pub(crate) use self::instance_store::{allocate_id_in, SlotMap};
 pub mod inbox;
 use self::inbox::{Inbox, DispatchablePacket};
+pub mod outbox;
+mod lifecycle;
+pub use self::lifecycle::{Lifecycle, PanicPolicy};
 
 pub struct Class {
     pub instance_store: InstanceStore,
     pub v_table: ActorVTable,
-    pub inbox: Inbox
+    pub inbox: Inbox,
+    pub lifecycle: Lifecycle
 }
 
+pub type OnPanic<'a> = &'a mut dyn FnMut(RawID, Box<dyn Any + Send>);
+
 pub struct ActorVTable {
-    pub message_handlers: [MessageHandler; MAX_MESSAGE_TYPES],
+    pub message_handlers: SparseTypeIdMap<MessageHandler>,
     pub state_v_table: ActorStateVTable,
     pub type_name: &'static str,
 }
 
 pub struct ActorStateVTable {
-    pub is_still_compact: Box<dyn Fn(*const ()) -> bool>,
-    pub total_size_bytes: Box<dyn Fn(*const ()) -> usize>,
-    pub compact_behind: Box<dyn Fn(*mut (), *mut ())>,
-    pub drop: Box<dyn Fn(*mut ())>,
-    pub get_raw_id: Box<dyn Fn(*const ()) -> RawID>,
-    pub set_raw_id: Box<dyn Fn(*mut (), RawID)>,
+    pub is_still_compact: Box<dyn Fn(*const ()) -> bool + Send>,
+    pub total_size_bytes: Box<dyn Fn(*const ()) -> usize + Send>,
+    pub compact_behind: Box<dyn Fn(*mut (), *mut ()) + Send>,
+    pub drop: Box<dyn Fn(*mut ()) + Send>,
+    pub get_raw_id: Box<dyn Fn(*const ()) -> RawID + Send>,
+    pub set_raw_id: Box<dyn Fn(*mut (), RawID) + Send>,
     pub typical_size: usize
 }
 
 impl ActorVTable {
     pub fn new_for_actor_type<A: Actor>() -> ActorVTable {
-        let actor_name = unsafe { ::std::intrinsics::type_name::<A>() };
+        let actor_name = ::std::intrinsics::type_name::<A>();
         ActorVTable {
-            message_handlers: unsafe { make_array!(MAX_MESSAGE_TYPES, |_| MessageHandler::Unassigned) },
+            message_handlers: SparseTypeIdMap::new(),
             type_name: actor_name,
             state_v_table: ActorStateVTable {
                 is_still_compact: Box::new(|act: *const ()| unsafe {(*(act as *const A)).is_still_compact()}),
@@ -56,30 +69,33 @@ impl ActorVTable {
 }
 
 pub enum MessageHandler {
-    Unassigned,
     OnMessage{handler: Box<HandlerFnRef>, critical: bool},
-    OnSpawn{spawner: Box<dyn Fn(*const (), &mut World, &mut InstanceStore, &ActorStateVTable)>, critical: bool}
+    OnSpawn{spawner: Box<dyn Fn(*const (), &mut World, &mut InstanceStore, &ActorStateVTable) + Send>, critical: bool},
+    OnMapReduce{map_reducer: Box<dyn Fn(*const (), &mut World, &mut InstanceStore) + Send>, critical: bool},
+    OnMonitor,
+    OnEscalation
 }
 
 impl Class {
-    pub fn new(v_table: ActorVTable, storage: Rc<dyn chunky::ChunkStorage>, tuning: &Tuning) -> Self {
-        let ident: chunky::Ident = v_table.type_name.split("<").map(|piece|
+    pub fn new(v_table: ActorVTable, storage: &Arc<dyn ThreadSafeStorage>, tuning: &Tuning) -> Self {
+        let ident: chunky::Ident = v_table.type_name.split('<').map(|piece|
             piece.split("::").last().unwrap_or("")
         ).collect::<Vec<_>>().join("<").replace("<", "(").replace(">", ")").into();
         Class {
-            instance_store: InstanceStore::new(&ident, v_table.state_v_table.typical_size, Rc::clone(&storage), tuning),
+            instance_store: InstanceStore::new(&ident, v_table.state_v_table.typical_size, storage, tuning),
             inbox: Inbox::new(&ident.sub("inbx"), storage, tuning),
+            lifecycle: Lifecycle::new(),
             v_table,
         }
     }
 
-    pub fn add_handler<A: Actor, M: Message, F: Fn(&M, &mut A, &mut World) -> Fate + 'static>(
+    pub fn add_handler<A: Actor, M: Message, F: Fn(&M, &mut A, &mut World) -> Fate + Send + 'static>(
         &mut self,
         message_id: ShortTypeId,
         handler: F,
         critical: bool,
     ) {
-        self.v_table.message_handlers[message_id.as_usize()] = MessageHandler::OnMessage {
+        self.v_table.message_handlers.insert(message_id, MessageHandler::OnMessage {
                 handler: Box::new(move |actor_ptr: *mut (), packet_ptr: *const (), world: &mut World| -> Fate {
                     unsafe {
                         let actor = &mut *(actor_ptr as *mut A);
@@ -88,16 +104,16 @@ impl Class {
                     }
                 }),
                 critical
-        };
+        });
     }
 
-    pub fn add_spawner<A: Actor, M: Message, F: Fn(&M, &mut World) -> A + 'static>(
+    pub fn add_spawner<A: Actor, M: Message, F: Fn(&M, &mut World) -> A + Send + 'static>(
         &mut self,
         message_id: ShortTypeId,
         constructor: F,
         critical: bool,
     ) {
-        self.v_table.message_handlers[message_id.as_usize()] = MessageHandler::OnSpawn {
+        self.v_table.message_handlers.insert(message_id, MessageHandler::OnSpawn {
             spawner: Box::new(move |packet_ptr: *const (), world: &mut World, store: &mut InstanceStore, intrinsics: &ActorStateVTable| {
                 unsafe {
                     let packet = &*(packet_ptr as *const Packet<M>);
@@ -107,43 +123,118 @@ impl Class {
                 }
             }),
             critical
-        };
+        });
     }
 
-    pub fn handle_messages(&mut self, message_statistics: &mut [usize], world: &mut World) {
+    pub fn add_mapper<A: Actor, M: Message, V, Map, Reduce, Finish>(
+        &mut self,
+        message_id: ShortTypeId,
+        mapper: Map,
+        reducer: Reduce,
+        finish: Finish,
+        critical: bool,
+    ) where
+        Map: Fn(&M, &A, &mut World) -> V + Send + 'static,
+        Reduce: Fn(V, V) -> V + Send + 'static,
+        Finish: Fn(RawID, &M, Option<V>, &mut World) + Send + 'static,
+    {
+        self.v_table.message_handlers.insert(message_id, MessageHandler::OnMapReduce {
+            map_reducer: Box::new(move |packet_ptr: *const (), world: &mut World, store: &mut InstanceStore| {
+                let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+                let mut reduced = None;
+                store.for_each_instance(|actor_ptr| {
+                    let actor = unsafe { &*(actor_ptr as *const A) };
+                    let value = mapper(&packet.message, actor, world);
+                    reduced = Some(match reduced.take() {
+                        Some(previous) => reducer(previous, value),
+                        None => value,
+                    });
+                });
+                finish(packet.recipient_id, &packet.message, reduced, world);
+            }),
+            critical
+        });
+    }
+
+    pub fn handle_messages(&mut self, message_statistics: &mut TypeIdCounts, world: &mut World) {
         for DispatchablePacket { message_type, packet_ptr} in self.inbox.drain() {
-            Self::dispatch_packet(&mut self.instance_store, &self.v_table, message_type, packet_ptr, world);
-            message_statistics[message_type.as_usize()] += 1;
+            Self::dispatch_packet(&mut self.instance_store, &self.v_table, &mut self.lifecycle, message_type, packet_ptr, world);
+            message_statistics.increment(message_type);
         }
+
+        self.lifecycle.after_dispatch(&mut self.instance_store, &self.v_table, world);
+    }
+
+    /// Handle a single packet that doesn't come from the inbox
+    pub fn handle_packet(
+        &mut self,
+        message_type: ShortTypeId,
+        packet_ptr: *const (),
+        message_statistics: &mut TypeIdCounts,
+        world: &mut World,
+    ) {
+        Self::dispatch_packet(&mut self.instance_store, &self.v_table, &mut self.lifecycle, message_type, packet_ptr, world);
+        message_statistics.increment(message_type);
+        self.lifecycle.after_dispatch(&mut self.instance_store, &self.v_table, world);
     }
 
     fn dispatch_packet(
         instance_store: &mut InstanceStore,
         v_table: &ActorVTable,
+        lifecycle: &mut Lifecycle,
         message_type: ShortTypeId,
         packet_ptr: *const (),
         world: &mut World,
     )
     {
-        let handler_kind = &v_table.message_handlers[message_type.as_usize()];
+        let handler_kind = v_table.message_handlers.get(message_type);
+        let recipient_id = unsafe {(*(packet_ptr as *const Packet<()>)).recipient_id};
+        let only_critical = lifecycle.frozen || world.panic_happened();
+
+        if let Some(MessageHandler::OnMessage{handler, critical}) = handler_kind {
+            if *critical || !only_critical {
+                let panic_policy = lifecycle.panic_policy;
+                let mut on_panic = |id: RawID, payload: Box<dyn Any + Send>| {
+                    lifecycle.instance_panicked(id, message_type, payload)
+                };
 
-        if let MessageHandler::OnMessage{ref handler, critical} = handler_kind {
-            if *critical || !world.panic_happened() {
-                let recipient_id = unsafe {(*(packet_ptr as *const Packet<()>)).recipient_id};
                 if recipient_id.instance_id == broadcast_instance_id() {
-                    instance_store.receive_broadcast(packet_ptr, world, handler, &v_table.state_v_table);
+                    instance_store.receive_broadcast(packet_ptr, world, handler, &v_table.state_v_table, panic_policy, &mut on_panic);
                 } else {
-                    instance_store.receive_instance(recipient_id, packet_ptr, world, handler,  &v_table.state_v_table);
+                    let found = instance_store.receive_instance(recipient_id, packet_ptr, world, handler,  &v_table.state_v_table, panic_policy, &mut on_panic);
+                    if !found {
+                        lifecycle.dead_letters.push((recipient_id, message_type, world.encode_packet(message_type, packet_ptr)));
+                    }
                 }
             }
-        } else if let MessageHandler::OnSpawn{spawner, critical} = handler_kind {
-            if *critical || !world.panic_happened() {
-                spawner(packet_ptr, world, instance_store, &v_table.state_v_table);
+        } else if let Some(MessageHandler::OnSpawn{spawner, critical}) = handler_kind {
+            if *critical || !only_critical {
+                if let Err(payload) = catch_unwind(AssertUnwindSafe(|| {
+                    spawner(packet_ptr, world, instance_store, &v_table.state_v_table)
+                })) {
+                    lifecycle.record_panic(recipient_id, message_type, payload);
+                }
+            }
+        } else if let Some(MessageHandler::OnMapReduce{map_reducer, critical}) = handler_kind {
+            if *critical || !only_critical {
+                if let Err(payload) = catch_unwind(AssertUnwindSafe(|| {
+                    map_reducer(packet_ptr, world, instance_store)
+                })) {
+                    lifecycle.record_panic(recipient_id, message_type, payload);
+                }
             }
-        } else {
-            if !world.panic_happened() {
-                panic!("Handler for message {} not found in {}", message_type.as_usize(), v_table.type_name);
+        } else if let Some(MessageHandler::OnMonitor) = handler_kind {
+            let watcher = unsafe {(*(packet_ptr as *const Packet<Monitor>)).message.watcher};
+            if instance_store.contains(recipient_id) {
+                lifecycle.add_monitor(recipient_id, watcher);
+            } else {
+                world.send(watcher, Down { id: recipient_id, reason: DownReason::Died });
             }
+        } else if let Some(MessageHandler::OnEscalation) = handler_kind {
+            let failed = unsafe {(*(packet_ptr as *const Packet<RestartIntensityExceeded>)).message.failed};
+            lifecycle.supervised_instance_failed(recipient_id, message_type, failed, instance_store, v_table);
+        } else if !only_critical {
+            panic!("Handler for message {} not found in {}", message_type.as_usize(), v_table.type_name);
         }
     }
 }
\ No newline at end of file
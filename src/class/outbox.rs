@@ -0,0 +1,100 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use compact::Compact;
+use crate::messaging::{Message, Packet};
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+
+/// Collects encoded messages, so they can be delivered
+/// to their recipients later, in the order they were put in.
+///
+/// Entries use the same layout as network batches: a `u32` size,
+/// followed by the message type and the compacted `Packet`.
+pub struct Outbox {
+    buffer: Vec<u8>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox {
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn put<M: Message>(&mut self, mut packet: Packet<M>, message_registry: &TypeRegistry) {
+        let packet_size = packet.total_size_bytes();
+        let total_size = ::std::mem::size_of::<ShortTypeId>() + packet_size;
+
+        self.buffer
+            .write_u32::<LittleEndian>(total_size as u32)
+            .unwrap();
+        self.buffer
+            .write_u16::<LittleEndian>(message_registry.get::<M>().into())
+            .unwrap();
+        let packet_pos = self.buffer.len();
+        self.buffer.resize(packet_pos + packet_size, 0);
+
+        unsafe {
+            // store packet compactly in the outbox
+            Compact::compact_behind(
+                &mut packet,
+                &mut self.buffer[packet_pos] as *mut u8 as *mut Packet<M>,
+            );
+        }
+
+        ::std::mem::forget(packet);
+    }
+
+    /// Append an entry (message type followed by the compacted `Packet`) as is
+    pub fn put_raw(&mut self, entry: &[u8]) {
+        self.buffer
+            .write_u32::<LittleEndian>(entry.len() as u32)
+            .unwrap();
+        self.buffer.extend_from_slice(entry);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Take out all collected entries, to be iterated with `entries`
+    pub fn take(&mut self) -> Vec<u8> {
+        self.take_replacing(Vec::new())
+    }
+
+    /// Like `take`, but continue collecting in the given empty buffer, reusing its allocation
+    pub fn take_replacing(&mut self, empty_buffer: Vec<u8>) -> Vec<u8> {
+        debug_assert!(empty_buffer.is_empty());
+        ::std::mem::replace(&mut self.buffer, empty_buffer)
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Iterate over the entries (message type followed by the compacted `Packet`)
+/// of a buffer taken out of an `Outbox`
+pub fn entries(buffer: &[u8]) -> OutboxEntries {
+    OutboxEntries { buffer, pos: 0 }
+}
+
+pub struct OutboxEntries<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for OutboxEntries<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.pos >= self.buffer.len() {
+            None
+        } else {
+            let entry_size = LittleEndian::read_u32(&self.buffer[self.pos..]) as usize;
+            let entry_start = self.pos + ::std::mem::size_of::<u32>();
+            self.pos = entry_start + entry_size;
+            Some(&self.buffer[entry_start..self.pos])
+        }
+    }
+}
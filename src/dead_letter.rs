@@ -0,0 +1,143 @@
+use compact::{CString, CVec};
+use crate::id::RawID;
+use crate::messaging::{Message, Packet};
+use crate::wire::{Portable, WireError, WireReader};
+
+/// Describes a message that could not be delivered,
+/// see `ActorSystem::set_dead_letter_handler` and `ActorSystem::get_dead_letter_counts`
+#[derive(Compact, Clone, Portable)]
+pub struct DeadLetter {
+    /// The intended recipient of the message
+    pub recipient: RawID,
+    /// The name of the message type
+    pub message_name: CString,
+    /// Why the message could not be delivered
+    pub reason: DeadLetterReason,
+    /// The undelivered packet (recipient and message) in its portable encoding,
+    /// see `decode_message` - empty for message types that aren't `Portable`
+    pub packet: CVec<u8>,
+}
+
+impl DeadLetter {
+    /// Get a copy of the undelivered message, which needs to be of type `M`
+    /// (as named by `message_name`)
+    pub fn decode_message<M: Message + Portable>(&self) -> Result<M, WireError> {
+        let mut reader = WireReader::new(&self.packet);
+        let packet = Packet::<M>::decode(&mut reader)?;
+        if reader.remaining() == 0 {
+            Ok(packet.message)
+        } else {
+            Err(WireError::TrailingBytes)
+        }
+    }
+}
+
+/// Why a message could not be delivered
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Portable)]
+pub enum DeadLetterReason {
+    /// The recipient instance doesn't exist (anymore), or has a newer version
+    NoSuchInstance,
+    /// The recipient type is neither a registered actor class,
+    /// nor an actor trait with implementors
+    NoSuchClass,
+    /// The recipient is on another machine, but the message type isn't `Portable`
+    NotPortable,
+}
+
+/// A system with a Bob that records the `Note`s of dead letters, adding 1000
+/// if there is no class for the recipient, or recording 2000 for non-portable messages
+#[cfg(test)]
+fn system_with_dead_letter_handler() -> (crate::actor_system::ActorSystem, crate::testing::BobID) {
+    dead_letter_handler_in(crate::testing::test_system())
+}
+
+#[cfg(test)]
+fn dead_letter_handler_in(mut system: crate::actor_system::ActorSystem) -> (crate::actor_system::ActorSystem, crate::testing::BobID) {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::testing::{Alice, Bob, Note};
+
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.add_handler::<Bob, DeadLetter, _>(
+        |dead_letter, instance, _world| {
+            match dead_letter.reason {
+                DeadLetterReason::NoSuchInstance => instance.received.push(dead_letter.decode_message::<Note>().unwrap().0),
+                DeadLetterReason::NoSuchClass => instance.received.push(1000 + dead_letter.decode_message::<Note>().unwrap().0),
+                DeadLetterReason::NotPortable => {
+                    assert!(dead_letter.packet.is_empty());
+                    instance.received.push(2000)
+                }
+            }
+            Fate::Live
+        },
+        false,
+    );
+    let bob = Bob::spawn(&mut system);
+    system.set_dead_letter_handler(bob.as_raw());
+    (system, bob)
+}
+
+#[test]
+fn test_message_to_dead_instance_is_kept_in_dead_letter() {
+    use crate::id::TypedID;
+    use crate::testing::{Alice, Bob, Die, Note};
+
+    let (mut system, bob) = system_with_dead_letter_handler();
+    let alice = Alice::spawn(&mut system);
+    system.send(alice.as_raw(), Die);
+    system.send(alice.as_raw(), Note(7));
+    system.process_all_messages();
+
+    assert_eq!(Bob::received(&mut system, bob), vec![7]);
+    assert_eq!(system.get_dead_letter_counts().values().sum::<usize>(), 1);
+}
+
+#[test]
+fn test_message_to_unknown_class_is_kept_in_dead_letter() {
+    use crate::id::MachineID;
+    use crate::testing::{Bob, Note};
+    use crate::type_registry::ShortTypeId;
+
+    let (mut system, bob) = system_with_dead_letter_handler();
+    let unknown = RawID::new(ShortTypeId::new(999).unwrap(), 0, MachineID(0), 0);
+    system.send(unknown, Note(7));
+    // staged messages are delivered in their encoded form
+    system.handle().world().send(unknown, Note(8));
+    system.process_all_messages();
+
+    assert_eq!(Bob::received(&mut system, bob), vec![1007, 1008]);
+}
+
+#[test]
+fn test_non_portable_message_is_only_sent_locally() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::{MachineID, TypedID};
+    use crate::messaging::Fate;
+    use crate::networking::Networking;
+    use crate::testing::{Alice, Bob};
+    use crate::tuning::Tuning;
+
+    #[derive(Compact, Clone)]
+    struct Local(CVec<u32>);
+
+    let networking = Networking::new(0, vec!["127.0.0.1:0".to_owned(), "127.0.0.1:0".to_owned()], 4096, 30, 10);
+    let (mut system, bob) = dead_letter_handler_in(ActorSystem::new(networking, Tuning::default()));
+    system.add_handler::<Alice, Local, _>(
+        |local, instance, _world| {
+            instance.received.extend_from_copy_slice(&local.0);
+            Fate::Live
+        },
+        false,
+    );
+    let alice = Alice::spawn(&mut system);
+    let mut remote_alice = alice.as_raw();
+    remote_alice.machine = MachineID(1);
+
+    system.send(alice.as_raw(), Local(vec![1, 2].into()));
+    system.send(remote_alice, Local(vec![3].into()));
+    system.process_all_messages();
+
+    assert_eq!(Alice::received(&mut system, alice), vec![1, 2]);
+    assert_eq!(Bob::received(&mut system, bob), vec![2000]);
+}
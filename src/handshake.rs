@@ -0,0 +1,271 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use crate::id::MachineID;
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+use std::collections::HashMap;
+
+/// Which type registry a `TypeMismatch` is about
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TypeKind {
+    /// Actor classes and actor traits
+    Actor,
+    /// Message types
+    Message,
+}
+
+/// Two machines disagree on what a type ID means, so they can't understand each other's messages.
+///
+/// This happens when the type ID of a type that both machines know differs
+/// (see `ActorSystem::type_manifest` to pin type IDs across builds).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TypeMismatch {
+    /// The machine that was connected to
+    pub peer: MachineID,
+    /// Whether this is about an actor or a message type
+    pub kind: TypeKind,
+    /// The type ID in question
+    pub id: u16,
+    /// The type with this ID on this machine, if any
+    pub local_name: Option<String>,
+    /// The type with this ID on the peer, if any
+    pub peer_name: Option<String>,
+}
+
+impl ::std::fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let kind = match self.kind {
+            TypeKind::Actor => "actor",
+            TypeKind::Message => "message",
+        };
+        let describe = |name: &Option<String>| match name {
+            Some(name) => name.clone(),
+            None => "unregistered".to_owned(),
+        };
+        write!(
+            f,
+            "Machine ID {} disagrees on {} type ID {}: here it is {}, there it is {}",
+            self.peer.0,
+            kind,
+            self.id,
+            describe(&self.local_name),
+            describe(&self.peer_name)
+        )
+    }
+}
+
+/// What two machines tell each other when connecting: their machine ID
+/// and the IDs and names of all their actor and message types
+pub(crate) struct Handshake {
+    pub machine_id: MachineID,
+    actor_types: HashMap<u16, String>,
+    message_types: HashMap<u16, String>,
+}
+
+impl Handshake {
+    pub fn new(machine_id: MachineID, actors: &TypeRegistry, messages: &TypeRegistry) -> Handshake {
+        let types = |registry: &TypeRegistry| {
+            registry
+                .short_ids_to_names
+                .iter()
+                .map(|(short_id, name)| (short_id.as_u16(), name.clone()))
+                .collect()
+        };
+        Handshake {
+            machine_id,
+            actor_types: types(actors),
+            message_types: types(messages),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![self.machine_id.0];
+        for types in &[&self.actor_types, &self.message_types] {
+            let mut entries: Vec<_> = types.iter().collect();
+            entries.sort_by_key(|&(id, _)| *id);
+            data.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+            for (id, name) in entries {
+                data.write_u16::<LittleEndian>(*id).unwrap();
+                data.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+                data.extend_from_slice(name.as_bytes());
+            }
+        }
+        data
+    }
+
+    /// Decode a handshake sent by a peer, `None` if it is malformed
+    pub fn decode(data: &[u8]) -> Option<Handshake> {
+        let machine_id = MachineID(*data.get(0)?);
+        let mut pos = 1;
+        let read_u16 = |pos: &mut usize| -> Option<u16> {
+            let bytes = data.get(*pos..*pos + 2)?;
+            *pos += 2;
+            Some(LittleEndian::read_u16(bytes))
+        };
+        let read_types = |pos: &mut usize| -> Option<HashMap<u16, String>> {
+            let n_types = read_u16(pos)?;
+            let mut types = HashMap::new();
+            for _ in 0..n_types {
+                let id = read_u16(pos)?;
+                let name_len = read_u16(pos)? as usize;
+                let name = ::std::str::from_utf8(data.get(*pos..*pos + name_len)?).ok()?;
+                *pos += name_len;
+                types.insert(id, name.to_owned());
+            }
+            Some(types)
+        };
+
+        let actor_types = read_types(&mut pos)?;
+        let message_types = read_types(&mut pos)?;
+
+        Some(Handshake {
+            machine_id,
+            actor_types,
+            message_types,
+        })
+    }
+
+    /// Find the lowest type ID that the peer uses differently,
+    /// either for a different type, or because it uses a different ID for the same type
+    pub fn first_mismatch(&self, peer: &Handshake) -> Option<TypeMismatch> {
+        let mismatches = |kind: TypeKind, local: &HashMap<u16, String>, remote: &HashMap<u16, String>| {
+            let local_ids: HashMap<&String, u16> = local.iter().map(|(id, name)| (name, *id)).collect();
+            let remote_ids: HashMap<&String, u16> = remote.iter().map(|(id, name)| (name, *id)).collect();
+
+            let mut mismatching_ids: Vec<u16> = local
+                .iter()
+                .filter(|&(id, name)| match remote.get(id) {
+                    Some(remote_name) => remote_name != name,
+                    None => remote_ids.contains_key(name),
+                })
+                .map(|(id, _)| *id)
+                .chain(
+                    remote
+                        .iter()
+                        .filter(|&(id, name)| !local.contains_key(id) && local_ids.contains_key(name))
+                        .map(|(id, _)| *id),
+                )
+                .collect();
+            mismatching_ids.sort_unstable();
+
+            mismatching_ids.into_iter().next().map(|id| TypeMismatch {
+                peer: peer.machine_id,
+                kind,
+                id,
+                local_name: local.get(&id).cloned(),
+                peer_name: remote.get(&id).cloned(),
+            })
+        };
+
+        mismatches(TypeKind::Actor, &self.actor_types, &peer.actor_types)
+            .or_else(|| mismatches(TypeKind::Message, &self.message_types, &peer.message_types))
+    }
+
+    /// Translate the type IDs of a peer to local type IDs, matching types by name.
+    /// Only covers types known to both machines whose IDs differ.
+    pub fn remapping_from(&self, peer: &Handshake) -> TypeIdRemap {
+        let remap = |local: &HashMap<u16, String>, remote: &HashMap<u16, String>| {
+            let local_ids: HashMap<&String, u16> = local.iter().map(|(id, name)| (name, *id)).collect();
+            remote
+                .iter()
+                .filter_map(|(remote_id, name)| {
+                    local_ids
+                        .get(name)
+                        .filter(|&local_id| local_id != remote_id)
+                        .and_then(|&local_id| ShortTypeId::new(local_id))
+                        .map(|local_id| (*remote_id, local_id))
+                })
+                .collect()
+        };
+
+        TypeIdRemap {
+            actors: remap(&self.actor_types, &peer.actor_types),
+            messages: remap(&self.message_types, &peer.message_types),
+        }
+    }
+}
+
+/// Translates type IDs in messages coming from a peer to local type IDs.
+///
+/// Only the message type and the recipient ID are translated:
+/// IDs contained in the messages themselves keep the peer's type IDs.
+pub(crate) struct TypeIdRemap {
+    actors: HashMap<u16, ShortTypeId>,
+    messages: HashMap<u16, ShortTypeId>,
+}
+
+impl TypeIdRemap {
+    /// Translate a network message (message type followed by a portably encoded `Packet`) in place
+    pub fn apply(&self, data: &mut [u8]) {
+        let message_type = LittleEndian::read_u16(data);
+        if let Some(local_id) = self.messages.get(&message_type) {
+            LittleEndian::write_u16(data, local_id.as_u16());
+        }
+
+        // the type ID follows the instance ID in the encoded recipient `RawID`
+        let type_id_pos = ::std::mem::size_of::<ShortTypeId>() + ::std::mem::size_of::<u32>();
+        if data.len() < type_id_pos + ::std::mem::size_of::<ShortTypeId>() {
+            // will be rejected as truncated when decoding
+            return;
+        }
+        let recipient_type = LittleEndian::read_u16(&data[type_id_pos..]);
+        if let Some(local_id) = self.actors.get(&recipient_type) {
+            LittleEndian::write_u16(&mut data[type_id_pos..], local_id.as_u16());
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_handshake(machine_id: u8, actor_types: &[(u16, &str)], message_types: &[(u16, &str)]) -> Handshake {
+    let types = |types: &[(u16, &str)]| types.iter().map(|&(id, name)| (id, name.to_owned())).collect();
+    Handshake {
+        machine_id: MachineID(machine_id),
+        actor_types: types(actor_types),
+        message_types: types(message_types),
+    }
+}
+
+#[test]
+fn test_handshake_round_trip() {
+    let handshake = test_handshake(3, &[(5, "Alice"), (7, "Bob")], &[(2, "Note")]);
+    let decoded = Handshake::decode(&handshake.encode()).unwrap();
+
+    assert_eq!(decoded.machine_id, MachineID(3));
+    assert_eq!(decoded.actor_types, handshake.actor_types);
+    assert_eq!(decoded.message_types, handshake.message_types);
+    assert!(Handshake::decode(&handshake.encode()[..4]).is_none());
+}
+
+#[test]
+fn test_first_mismatch_finds_types_with_different_ids() {
+    let local = test_handshake(0, &[(5, "Alice"), (7, "Bob")], &[(2, "Note")]);
+    let same = test_handshake(1, &[(5, "Alice"), (7, "Bob"), (8, "Carol")], &[(2, "Note")]);
+    let different = test_handshake(1, &[(5, "Alice"), (9, "Bob")], &[(2, "Note")]);
+
+    assert_eq!(local.first_mismatch(&same), None);
+    assert_eq!(
+        local.first_mismatch(&different),
+        Some(TypeMismatch {
+            peer: MachineID(1),
+            kind: TypeKind::Actor,
+            id: 7,
+            local_name: Some("Bob".to_owned()),
+            peer_name: None,
+        })
+    );
+}
+
+#[test]
+fn test_remap_translates_message_and_recipient_types() {
+    let local = test_handshake(0, &[(5, "Alice"), (7, "Bob")], &[(2, "Note")]);
+    let peer = test_handshake(1, &[(5, "Alice"), (9, "Bob")], &[(3, "Note")]);
+    let remap = local.remapping_from(&peer);
+
+    // message type, then recipient instance ID, type ID, machine and version
+    let mut message = vec![3, 0, 1, 0, 0, 0, 9, 0, 0, 0];
+    remap.apply(&mut message);
+    assert_eq!(message, vec![2, 0, 1, 0, 0, 0, 7, 0, 0, 0]);
+
+    // types that agree stay as they are
+    let mut message = vec![2, 0, 1, 0, 0, 0, 5, 0, 0, 0];
+    remap.apply(&mut message);
+    assert_eq!(message, vec![2, 0, 1, 0, 0, 0, 5, 0, 0, 0]);
+}
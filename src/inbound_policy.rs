@@ -0,0 +1,20 @@
+use crate::type_registry::ShortTypeId;
+use std::collections::HashSet;
+
+/// Which messages a restricted peer may send: pairs of recipient type
+/// (an actor class or actor trait) and message type.
+/// Messages from peers without a policy are all accepted.
+#[derive(Default)]
+pub(crate) struct InboundPolicy {
+    allowed: HashSet<(ShortTypeId, ShortTypeId)>,
+}
+
+impl InboundPolicy {
+    pub fn allow(&mut self, recipient_type: ShortTypeId, message_type: ShortTypeId) {
+        self.allowed.insert((recipient_type, message_type));
+    }
+
+    pub fn allows(&self, recipient_type: ShortTypeId, message_type: ShortTypeId) -> bool {
+        self.allowed.contains(&(recipient_type, message_type))
+    }
+}
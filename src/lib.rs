@@ -17,17 +17,24 @@
 //! The [Compact](https://TODO) library is used to help with this, offering
 //! serialisation-free linear memory layouts for plain old data and nested datastructures.
 //! This does, in turn, impose the constraint that actor state and messages need to implement
-//! [Compact](https://TODO)
+//! [Compact](https://TODO). Messages that are sent between machines also need to implement
+//! `Portable` (usually with `#[derive(Portable)]`), which defines their encoding on the wire.
 
 #![warn(missing_docs)]
 #![feature(core_intrinsics)]
 #![feature(optin_builtin_traits)]
 #![feature(specialization)]
+#![allow(incomplete_features)]
 #![feature(box_syntax)]
+#![cfg_attr(test, feature(test))]
 extern crate chunky;
 extern crate compact;
 #[macro_use]
 extern crate compact_macros;
+#[macro_use]
+extern crate kay_macros;
+// lets the `Portable` derive refer to `::kay` inside this crate as well
+extern crate self as kay;
 extern crate byteorder;
 extern crate core;
 #[cfg(feature = "browser")]
@@ -36,37 +43,68 @@ extern crate stdweb;
 #[cfg(feature = "server")]
 extern crate tungstenite;
 extern crate url;
+#[cfg(test)]
+extern crate test;
 #[cfg(feature = "serde-serialization")]
 #[macro_use]
 extern crate serde_derive;
 #[cfg(feature = "serde-serialization")]
 extern crate serde;
 
-macro_rules! make_array {
-    ($n:expr, $constructor:expr) => {{
-        let mut items: [_; $n] = ::std::mem::uninitialized();
-        for (i, place) in items.iter_mut().enumerate() {
-            ::std::ptr::write(place, $constructor(i));
-        }
-        items
-    }};
-}
-
 mod tuning;
 mod actor;
 mod actor_system;
+mod auth;
 mod external;
 mod id;
+mod loop_detector;
 mod class;
+mod dead_letter;
+mod handshake;
+mod inbound_policy;
 mod messaging;
+mod monitor;
+mod network_observer;
 mod networking;
+mod panic_report;
+#[cfg(feature = "server")]
+mod pending_peer;
+mod processing;
+mod reconnect;
+mod request;
+mod session;
 mod storage_aware;
+mod supervision;
+mod system_handle;
+mod thread_safe_storage;
+mod timer_wheel;
+mod type_id_map;
 mod type_registry;
+mod wire;
+mod worker_pool;
+#[cfg(test)]
+mod testing;
 
 pub use self::actor::{Actor, ActorOrActorTrait, TraitIDFrom};
 pub use self::actor_system::{ActorSystem, World};
+pub use self::dead_letter::{DeadLetter, DeadLetterReason};
+pub use self::class::PanicPolicy;
 pub use self::external::External;
+pub use self::handshake::{TypeKind, TypeMismatch};
 pub use self::id::{MachineID, RawID, TypedID};
+pub use self::loop_detector::MessageLoop;
 pub use self::messaging::{Fate, Message, Packet};
+pub use self::monitor::{Down, DownReason};
+pub use self::network_observer::{NetworkEvent, NetworkObserver, NetworkObserverID};
 pub use self::networking::Networking;
-pub use self::tuning::Tuning;
\ No newline at end of file
+pub use self::panic_report::PanicReport;
+pub use self::processing::{ProcessingBudget, ProcessingReport};
+pub use self::reconnect::ConnectionState;
+pub use self::request::{RequestID, RequestTimeout};
+pub use self::supervision::RestartIntensityExceeded;
+pub use self::system_handle::{OutsideOfActorSystem, SystemHandle, UnknownMessageType};
+pub use self::thread_safe_storage::ThreadSafeStorage;
+pub use self::tuning::Tuning;
+pub use self::type_registry::{ManifestError, RegistrationError};
+pub use self::wire::{InvalidInput, Portable, WireError, WireReader};
+pub use kay_macros::Portable;
\ No newline at end of file
@@ -0,0 +1,242 @@
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+use std::collections::{HashMap, HashSet};
+
+/// A (sender class, message type, recipient class) triple
+type Link = (u16, u16, u16);
+
+/// A chain of messages between actor classes that keeps going on
+/// for many message cycles in a row, see `ActorSystem::message_loops`
+#[derive(Clone, Debug)]
+pub struct MessageLoop {
+    /// The (sender class, message type, recipient class) names of each link in the loop,
+    /// where the recipient of each link is the sender of the next one
+    pub links: Vec<(String, String, String)>,
+}
+
+impl ::std::fmt::Display for MessageLoop {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        for (i, (sender, message, recipient)) in self.links.iter().enumerate() {
+            if i == 0 {
+                write!(f, "{}", sender)?;
+            }
+            write!(f, " -[{}]-> {}", message, recipient)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks which classes send which messages to each other in every message cycle,
+/// to find chains of messages that persist for more than `threshold` cycles in a row
+pub(crate) struct LoopDetector {
+    threshold: usize,
+    /// How many cycles apart a link can happen and still count as happening in a row.
+    /// With worker threads, messages are only handled in the cycle after they were sent,
+    /// so the links of a loop take turns (see `Tuning::n_worker_threads`).
+    pub max_gap: usize,
+    n_finished_cycles: usize,
+    links_this_cycle: HashSet<Link>,
+    /// How many cycles each link happened in a row, and the last cycle it happened in
+    n_cycles_in_a_row: HashMap<Link, (usize, usize)>,
+    reported: HashSet<Vec<Link>>,
+}
+
+impl LoopDetector {
+    /// A `threshold` of 0 disables loop detection
+    pub fn new(threshold: usize) -> Self {
+        LoopDetector {
+            threshold,
+            max_gap: 1,
+            n_finished_cycles: 0,
+            links_this_cycle: HashSet::new(),
+            n_cycles_in_a_row: HashMap::new(),
+            reported: HashSet::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold > 0
+    }
+
+    pub fn record(&mut self, sender: ShortTypeId, message_type: ShortTypeId, recipient: ShortTypeId) {
+        self.links_this_cycle
+            .insert((sender.as_u16(), message_type.as_u16(), recipient.as_u16()));
+    }
+
+    /// Returns a newly detected loop, if any
+    pub fn finish_cycle(&mut self, actor_registry: &TypeRegistry, message_registry: &TypeRegistry) -> Option<MessageLoop> {
+        self.n_finished_cycles += 1;
+        let this_cycle = self.n_finished_cycles;
+        for link in self.links_this_cycle.drain() {
+            let (n_cycles, last_cycle) = self.n_cycles_in_a_row.entry(link).or_insert((0, this_cycle));
+            *n_cycles += 1;
+            *last_cycle = this_cycle;
+        }
+        let max_gap = self.max_gap;
+        self.n_cycles_in_a_row
+            .retain(|_, &mut (_, last_cycle)| this_cycle - last_cycle < max_gap);
+
+        // forget loops that stopped, so they are reported again if they come back
+        let n_cycles_in_a_row = &self.n_cycles_in_a_row;
+        self.reported
+            .retain(|links| links.iter().all(|link| n_cycles_in_a_row.contains_key(link)));
+
+        let persistent_links: Vec<Link> = self
+            .n_cycles_in_a_row
+            .iter()
+            .filter(|&(_, &(n_cycles, _))| n_cycles > self.threshold)
+            .map(|(link, _)| *link)
+            .collect();
+
+        let reported = &self.reported;
+        let cycle = find_cycle(&persistent_links, &|cycle| !reported.contains(cycle))?;
+        self.reported.insert(cycle.clone());
+
+        let name = |registry: &TypeRegistry, id: u16| {
+            ShortTypeId::new(id)
+                .map(|id| registry.get_name(id).clone())
+                .unwrap_or_default()
+        };
+
+        Some(MessageLoop {
+            links: cycle
+                .into_iter()
+                .map(|(sender, message_type, recipient)| {
+                    (
+                        name(actor_registry, sender),
+                        name(message_registry, message_type),
+                        name(actor_registry, recipient),
+                    )
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Find a chain of links that leads back to the class it started from,
+/// in its canonical form, skipping cycles that `is_new` rejects
+fn find_cycle(links: &[Link], is_new: &dyn Fn(&[Link]) -> bool) -> Option<Vec<Link>> {
+    for &start in links {
+        let mut path = vec![start];
+        if let Some(cycle) = extend_to_cycle(&mut path, links, is_new) {
+            return Some(cycle);
+        }
+    }
+
+    None
+}
+
+fn extend_to_cycle(path: &mut Vec<Link>, links: &[Link], is_new: &dyn Fn(&[Link]) -> bool) -> Option<Vec<Link>> {
+    let (_, _, last_recipient) = *path.last().expect("Path should not be empty");
+
+    if let Some(cycle_start) = path.iter().position(|&(sender, _, _)| sender == last_recipient) {
+        let mut cycle = path[cycle_start..].to_vec();
+        // rotate into a canonical form, to recognize each loop wherever it was entered
+        let first = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap_or(0);
+        cycle.rotate_left(first);
+        return if is_new(&cycle) { Some(cycle) } else { None };
+    }
+
+    for &link in links.iter().filter(|&&(sender, _, _)| sender == last_recipient) {
+        if !path.contains(&link) {
+            path.push(link);
+            if let Some(cycle) = extend_to_cycle(path, links, is_new) {
+                return Some(cycle);
+            }
+            path.pop();
+        }
+    }
+
+    None
+}
+
+/// A system in which Alice and Bob keep sending `Forward`s back and forth
+#[cfg(test)]
+fn ping_pong_system(message_loop_threshold: usize) -> crate::actor_system::ActorSystem {
+    ping_pong_system_with_threads(message_loop_threshold, 1)
+}
+
+#[cfg(test)]
+fn ping_pong_system_with_threads(message_loop_threshold: usize, n_worker_threads: usize) -> crate::actor_system::ActorSystem {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::testing::{test_system_with_tuning, Alice, Bob, Forward};
+    use crate::tuning::Tuning;
+
+    let mut system = test_system_with_tuning(Tuning {
+        message_loop_threshold,
+        n_worker_threads,
+        ..Tuning::default()
+    });
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.add_handler::<Alice, Forward, _>(
+        |forward, instance, world| {
+            world.send(forward.1, Forward(forward.0 + 1, instance.id.as_raw()));
+            Fate::Live
+        },
+        false,
+    );
+    system.add_handler::<Bob, Forward, _>(
+        |forward, instance, world| {
+            world.send(forward.1, Forward(forward.0 + 1, instance.id.as_raw()));
+            Fate::Live
+        },
+        false,
+    );
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+    system.send(alice.as_raw(), Forward(0, bob.as_raw()));
+    system
+}
+
+#[test]
+fn test_message_loop_is_reported_once() {
+    use crate::processing::ProcessingBudget;
+
+    let mut system = ping_pong_system(3);
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxCycles(10));
+
+    assert_eq!(report.message_loops.len(), 1);
+    let links = &report.message_loops[0].links;
+    assert_eq!(links.len(), 2);
+    assert!(links.iter().all(|(_, message, _)| message.ends_with("Forward")));
+    assert_eq!(links[0].0, links[1].2);
+    assert_eq!(links[0].2, links[1].0);
+
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxCycles(10));
+    assert!(report.message_loops.is_empty());
+    assert_eq!(system.message_loops().len(), 1);
+}
+
+#[test]
+fn test_message_loop_is_detected_with_worker_threads() {
+    use crate::processing::ProcessingBudget;
+
+    // Alice and Bob only send every other cycle, since messages are handled in the next one
+    let mut system = ping_pong_system_with_threads(3, 2);
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxCycles(10));
+
+    assert_eq!(report.message_loops.len(), 1);
+    assert_eq!(report.message_loops[0].links.len(), 2);
+}
+
+#[test]
+fn test_message_loop_detection_is_disabled_by_default() {
+    use crate::processing::ProcessingBudget;
+
+    let mut system = ping_pong_system(crate::tuning::Tuning::default().message_loop_threshold);
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxCycles(200));
+
+    assert!(report.message_loops.is_empty());
+}
+
+#[test]
+fn test_find_cycle_skips_reported_cycles() {
+    let first = vec![(1, 10, 2), (2, 10, 1)];
+    let second = vec![(1, 11, 3), (3, 11, 1)];
+    let links = [first[1], second[1], first[0], second[0]];
+
+    assert_eq!(find_cycle(&links, &|_| true), Some(first.clone()));
+    assert_eq!(find_cycle(&links, &|cycle| cycle != &first[..]), Some(second));
+    assert_eq!(find_cycle(&links, &|_| false), None);
+}
@@ -10,11 +10,12 @@ pub enum Fate {
     Die,
 }
 
-/// Must be implemented by everything that can be sent between actors
+/// Must be implemented by everything that can be sent between actors.
+/// To be sent to actors on other machines, messages also need to be `Portable`.
 pub trait Message: Compact + 'static {}
 impl<T: Compact + 'static> Message for T {}
 
-pub type HandlerFnRef = dyn Fn(*mut(), *const (), &mut World) -> Fate;
+pub type HandlerFnRef = dyn Fn(*mut(), *const (), &mut World) -> Fate + Send;
 
 #[derive(Compact, Clone)]
 #[repr(C)]
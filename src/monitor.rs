@@ -0,0 +1,92 @@
+use crate::id::RawID;
+
+/// Sent to a watcher when an actor it monitors (see `World::monitor`) is gone
+#[derive(Compact, Clone, Portable)]
+pub struct Down {
+    /// The monitored actor
+    pub id: RawID,
+    /// Why the monitored actor is gone
+    pub reason: DownReason,
+}
+
+/// Why a monitored actor is gone
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Portable)]
+pub enum DownReason {
+    /// The actor died (or was removed after a panic), or didn't exist
+    /// anymore when the monitor was set up
+    Died,
+    /// The machine hosting the actor disconnected. The actor might still be alive,
+    /// and if it died right before, the watcher also gets a `Down` with `DownReason::Died`
+    MachineDisconnected,
+}
+
+/// Sent to a monitored actor to let its class remember the watcher
+#[derive(Compact, Clone, Portable)]
+pub(crate) struct Monitor {
+    pub watcher: RawID,
+}
+
+#[cfg(test)]
+fn system_with_watcher() -> (crate::actor_system::ActorSystem, crate::testing::AliceID, crate::testing::BobID) {
+    use crate::messaging::Fate;
+    use crate::testing::{test_system, Alice, Bob};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.add_handler::<Bob, Down, _>(
+        |down, instance, _world| {
+            instance.received.push(down.id.instance_id);
+            Fate::Live
+        },
+        false,
+    );
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+    (system, alice, bob)
+}
+
+#[test]
+fn test_watcher_is_told_when_monitored_instance_dies() {
+    use crate::id::TypedID;
+    use crate::testing::{Bob, Die};
+
+    let (mut system, alice, bob) = system_with_watcher();
+    system.world().monitor(bob.as_raw(), alice.as_raw());
+    system.process_all_messages();
+    assert_eq!(Bob::received(&mut system, bob), vec![]);
+
+    system.send(alice.as_raw(), Die);
+    system.process_all_messages();
+    assert_eq!(Bob::received(&mut system, bob), vec![alice.as_raw().instance_id]);
+}
+
+#[test]
+fn test_monitoring_a_dead_instance_tells_watcher_right_away() {
+    use crate::id::TypedID;
+    use crate::testing::{Bob, Die};
+
+    let (mut system, alice, bob) = system_with_watcher();
+    system.send(alice.as_raw(), Die);
+    system.process_all_messages();
+
+    system.world().monitor(bob.as_raw(), alice.as_raw());
+    system.process_all_messages();
+    assert_eq!(Bob::received(&mut system, bob), vec![alice.as_raw().instance_id]);
+}
+
+#[test]
+fn test_dead_watchers_are_forgotten() {
+    use crate::id::TypedID;
+    use crate::testing::Die;
+
+    let (mut system, alice, bob) = system_with_watcher();
+    system.world().monitor(bob.as_raw(), alice.as_raw());
+    system.send(bob.as_raw(), Die);
+    system.process_all_messages();
+    system.networking_finish_turn();
+
+    system.send(alice.as_raw(), Die);
+    system.process_all_messages();
+    assert!(system.get_dead_letter_counts().is_empty());
+}
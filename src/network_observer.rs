@@ -0,0 +1,131 @@
+use crate::actor::{Actor, ActorOrActorTrait, TraitIDFrom};
+use crate::actor_system::{ActorSystem, World};
+use crate::id::{MachineID, RawID, TypedID};
+use crate::messaging::Fate;
+
+/// Something that happened to a peer in the networking topology
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Portable)]
+pub enum NetworkEvent {
+    /// The peer connected and its type handshake was accepted
+    PeerConnected(MachineID),
+    /// The connection to the peer closed. It might reconnect later
+    PeerDisconnected(MachineID),
+    /// The peer fell behind by more than the acceptable turn distance
+    PeerLagging {
+        /// The lagging peer
+        peer: MachineID,
+        /// How many networking turns the peer is behind the local actor system
+        turns_behind: usize,
+    },
+    /// The peer caught up again after lagging
+    PeerCaughtUp(MachineID),
+}
+
+/// Built-in actor trait for classes that want to learn about peers connecting,
+/// disconnecting or lagging behind. Each event is broadcast to all local
+/// instances of all classes registered with `NetworkObserverID::register_implementor`.
+/// Events that happen before the first class is registered are delivered once it is.
+pub trait NetworkObserver {
+    /// Handle a network event
+    fn on_network_event(&mut self, event: NetworkEvent, world: &mut World);
+}
+
+/// Refers to instances of classes implementing `NetworkObserver`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct NetworkObserverID {
+    _raw_id: RawID,
+}
+
+// the ID type stands in for the actor trait itself
+impl ActorOrActorTrait for NetworkObserverID {
+    type ID = NetworkObserverID;
+}
+
+impl TypedID for NetworkObserverID {
+    type Target = NetworkObserverID;
+
+    fn as_raw(&self) -> RawID {
+        self._raw_id
+    }
+
+    fn from_raw(raw: RawID) -> Self {
+        NetworkObserverID { _raw_id: raw }
+    }
+}
+
+impl<A: Actor + NetworkObserver> TraitIDFrom<A> for NetworkObserverID {}
+
+impl NetworkObserverID {
+    /// Register an actor class as an implementor of `NetworkObserver`,
+    /// making its instances receive network events
+    pub fn register_implementor<A: Actor + NetworkObserver>(system: &mut ActorSystem) {
+        system.register_implementor::<A, NetworkObserverID>();
+        system.add_handler::<A, NetworkEvent, _>(
+            |event, instance, world| {
+                instance.on_network_event(*event, world);
+                Fate::Live
+            },
+            false,
+        );
+    }
+}
+
+#[cfg(test)]
+impl NetworkObserver for crate::testing::Alice {
+    fn on_network_event(&mut self, event: NetworkEvent, _world: &mut World) {
+        self.received.push(match event {
+            NetworkEvent::PeerConnected(peer) => 100 + u32::from(peer.0),
+            NetworkEvent::PeerDisconnected(peer) => 200 + u32::from(peer.0),
+            _ => 0,
+        });
+    }
+}
+
+#[cfg(test)]
+fn exchange_until<F: Fn(&ActorSystem) -> bool>(system: &mut ActorSystem, done: F) {
+    let started = ::std::time::Instant::now();
+    while !done(system) {
+        assert!(started.elapsed() < ::std::time::Duration::from_secs(10), "Timed out");
+        system.networking_connect();
+        system.networking_send_and_receive();
+        ::std::thread::sleep(::std::time::Duration::from_millis(5));
+    }
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_observers_learn_about_peers_connecting_and_disconnecting() {
+    use crate::networking::Networking;
+    use crate::reconnect::ConnectionState;
+    use crate::testing::Alice;
+    use crate::tuning::Tuning;
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+
+    let free_address = || TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let network = vec![free_address(), free_address()];
+    let mut system = ActorSystem::new(Networking::new(0, network.clone(), 4096, 30, 10), Tuning::default());
+
+    let (stop_peer, peer_stopped) = channel::<()>();
+    let peer = ::std::thread::spawn(move || {
+        let mut peer_system = ActorSystem::new(Networking::new(1, network, 4096, 30, 10), Tuning::default());
+        exchange_until(&mut peer_system, |_| peer_stopped.try_recv().is_ok());
+    });
+
+    let peer_state = |system: &ActorSystem| system.networking_connection_states()[&MachineID(1)];
+    exchange_until(&mut system, |system| peer_state(system) == ConnectionState::Connected);
+
+    // the observer only exists after the peer connected
+    Alice::register(&mut system);
+    NetworkObserverID::register_implementor::<Alice>(&mut system);
+    let alice = Alice::spawn(&mut system);
+    system.networking_send_and_receive();
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![101]);
+
+    stop_peer.send(()).unwrap();
+    peer.join().unwrap();
+    exchange_until(&mut system, |system| peer_state(system) != ConnectionState::Connected);
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![101, 201]);
+}
@@ -1,10 +1,24 @@
+use crate::auth::{new_nonce, Authentication, SharedSecret};
 use crate::class::Class;
+use crate::handshake::{Handshake, TypeIdRemap, TypeMismatch};
 use crate::id::{broadcast_machine_id, MachineID, RawID};
+use crate::inbound_policy::InboundPolicy;
 use crate::messaging::{Message, Packet};
-use crate::type_registry::ShortTypeId;
+use crate::monitor::{Down, DownReason};
+use crate::network_observer::NetworkEvent;
+#[cfg(feature = "server")]
+use crate::pending_peer::{queue_binary, AuthenticatedPeer, IncomingPeer, OutgoingPeer, Step};
+use crate::reconnect::{now, ConnectionState, Reconnect};
+use crate::session::{Resume, Session};
+use crate::type_id_map::{DenseTypeIdMap, TypeIdCounts};
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+use crate::wire::{aligned_copy, InvalidInput, MaybePortable, WireCodec, WireError, WireReader};
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
-use compact::Compact;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "server")]
+use std::time::Instant;
 #[cfg(feature = "server")]
 use std::net::{TcpListener, TcpStream};
 #[cfg(feature = "browser")]
@@ -14,25 +28,56 @@ use stdweb::web::{SocketBinaryType, SocketReadyState, TypedArray, WebSocket};
 #[cfg(feature = "server")]
 use tungstenite::util::NonBlockingError;
 #[cfg(feature = "server")]
-use tungstenite::{
-    accept as websocket_accept, client as websocket_client, HandshakeError,
-    Message as WebSocketMessage, WebSocket,
-};
-#[cfg(feature = "server")]
-use url::Url;
+use tungstenite::{Message as WebSocketMessage, WebSocket};
+
 /// Represents a networking configuration, topology and state of an `ActorSystem`
 pub struct Networking {
     /// The machine ID of the local actor system
     pub machine_id: MachineID,
-    batch_message_bytes: usize,
     /// The progress of networking turns of the local actor system
     pub n_turns: usize,
     acceptable_turn_distance: usize,
     skip_turns_per_turn_head: usize,
     network: Vec<String>,
     network_connections: Vec<Option<Connection>>,
+    /// Identifies this run of the local actor system to peers
+    incarnation: u64,
+    /// What is sent to and received from each peer, across reconnects
+    sessions: Vec<Session>,
+    resend_buffer_bytes: usize,
+    disconnected_machines: Vec<MachineID>,
+    /// Local watchers and the remote actors they monitored, which peers reported as died
+    remote_deaths: Vec<(RawID, RawID)>,
+    /// Peer lifecycle events not delivered to observers yet
+    events: Vec<NetworkEvent>,
+    /// When to try connecting to each peer again
+    reconnects: Vec<Reconnect>,
+    min_reconnect_delay: Duration,
+    max_reconnect_delay: Duration,
+    /// What peers have to prove knowledge of when connecting
+    shared_secret: SharedSecret,
+    /// What this machine tells peers when connecting
+    local_handshake: Handshake,
+    remap_type_ids: bool,
+    type_mismatches: Vec<TypeMismatch>,
+    /// Did the last handshake with each peer fail because of a type mismatch?
+    refused: Vec<bool>,
+    input_errors: Vec<(MachineID, InvalidInput)>,
+    /// Restrictions of what restricted peers may send
+    inbound_policies: HashMap<MachineID, InboundPolicy>,
+    /// Messages from restricted peers that weren't allowed
+    rejected_messages: HashMap<MachineID, TypeIdCounts>,
+    /// Translate messages between their local layout and the wire encoding,
+    /// shared with worker threads (see `Tuning::n_worker_threads`)
+    codecs: Arc<DenseTypeIdMap<WireCodec>>,
     #[cfg(feature = "server")]
     listener: TcpListener,
+    /// Peers that connected to us, but didn't authenticate yet
+    #[cfg(feature = "server")]
+    incoming_peers: Vec<IncomingPeer>,
+    /// Peers we are connecting to, that didn't authenticate yet
+    #[cfg(feature = "server")]
+    outgoing_peers: Vec<OutgoingPeer>,
 }
 
 impl Networking {
@@ -53,150 +98,416 @@ impl Networking {
 
         Networking {
             machine_id: MachineID(machine_id),
-            batch_message_bytes,
             n_turns: 0,
             acceptable_turn_distance,
             skip_turns_per_turn_head,
             network_connections: (0..network.len()).into_iter().map(|_| None).collect(),
+            incarnation: LittleEndian::read_u64(&new_nonce()).max(1),
+            sessions: (0..network.len())
+                .map(|peer| Session::new(MachineID(peer as u8), batch_message_bytes))
+                .collect(),
+            resend_buffer_bytes: 64 * batch_message_bytes,
+            disconnected_machines: Vec::new(),
+            remote_deaths: Vec::new(),
+            events: Vec::new(),
+            reconnects: vec![Reconnect::default(); network.len()],
+            min_reconnect_delay: Duration::from_millis(500),
+            max_reconnect_delay: Duration::from_secs(30),
+            shared_secret: SharedSecret::default(),
+            local_handshake: Handshake::new(MachineID(machine_id), &TypeRegistry::new(), &TypeRegistry::new()),
+            remap_type_ids: false,
+            type_mismatches: Vec::new(),
+            refused: vec![false; network.len()],
+            input_errors: Vec::new(),
+            inbound_policies: HashMap::new(),
+            rejected_messages: HashMap::new(),
+            codecs: Arc::new(DenseTypeIdMap::new()),
             network,
             #[cfg(feature = "server")]
             listener,
+            #[cfg(feature = "server")]
+            incoming_peers: Vec::new(),
+            #[cfg(feature = "server")]
+            outgoing_peers: Vec::new(),
         }
     }
 
+    /// Require all machines of the network to prove that they know `secret` when connecting.
+    /// Peers that fail to do so are rejected before any messages are exchanged with them.
+    ///
+    /// Without a shared secret, any peer can connect and claim any machine ID.
+    pub fn with_shared_secret(mut self, secret: &[u8]) -> Networking {
+        self.shared_secret = SharedSecret::new(secret);
+        self
+    }
+
+    /// Configure how long to wait before trying to connect to a peer again,
+    /// after a failed attempt or a dropped connection.
+    /// The delay doubles with every failed attempt in a row, from `min_delay` up to `max_delay`.
+    pub fn with_reconnect_backoff(mut self, min_delay: Duration, max_delay: Duration) -> Networking {
+        self.min_reconnect_delay = min_delay;
+        self.max_reconnect_delay = max_delay;
+        self
+    }
+
+    /// Configure how many bytes of messages to keep for each peer until it acknowledges them,
+    /// so they can be sent again after reconnecting. If that's exceeded, the messages are dropped
+    /// and a new session (without the dropped messages) is started with the peer when reconnecting.
+    pub fn with_resend_buffer(mut self, resend_buffer_bytes: usize) -> Networking {
+        self.resend_buffer_bytes = resend_buffer_bytes;
+        self
+    }
+
     #[cfg(feature = "server")]
     pub(crate) fn connect(&mut self) {
-        // first wait for a larger machine_id to connect
-        if self
-            .network_connections
-            .iter()
-            .enumerate()
-            .any(|(machine_id, connection)| {
-                machine_id > self.machine_id.0 as usize && connection.is_none()
-            })
-        {
-            match self.listener.accept() {
-                Ok((stream, addr)) => {
-                    println!("Got connection from {}, shaking hands...", addr);
-                    let mut handshake_state = Some(websocket_accept(stream));
-                    loop {
-                        handshake_state = match handshake_state {
-                            Some(Ok(mut websocket)) => {
-                                loop {
-                                    match websocket.read_message() {
-                                        Ok(WebSocketMessage::Binary(data)) => {
-                                            let peer_machine_id = data[0];
-                                            self.network_connections[peer_machine_id as usize] =
-                                                Some(Connection::new(
-                                                    websocket,
-                                                    self.batch_message_bytes,
-                                                ));
-                                            println!(
-                                                "...machine ID {} connected!",
-                                                peer_machine_id
-                                            );
-                                            break;
-                                        }
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            if let Some(real_err) = e.into_non_blocking() {
-                                                println!(
-                                                    "Error while expecting first message: {}",
-                                                    real_err
-                                                );
-                                                break;
-                                            }
-                                        }
-                                    }
-                                }
-                                break;
-                            }
-                            Some(Err(HandshakeError::Interrupted(s))) => Some(s.handshake()),
-                            Some(Err(HandshakeError::Failure(e))) => {
-                                println!("Error while accepting connection: {}", e);
-                                break;
-                            }
-                            None => break,
+        let now = now();
+
+        // first let larger machine_ids connect,
+        // they might reconnect before we notice that their old connection dropped
+        if self.network_connections.len() > self.machine_id.0 as usize + 1 {
+            // only keep as many peers waiting as there can be, so strangers can't exhaust our sockets
+            while self.incoming_peers.len() < self.network.len() {
+                match self.listener.accept() {
+                    Ok((stream, address)) => {
+                        println!("Got connection from {}, shaking hands...", address);
+                        match IncomingPeer::new(stream, address, now) {
+                            Ok(incoming_peer) => self.incoming_peers.push(incoming_peer),
+                            Err(reason) => println!("Rejecting connection from {}: {}", address, reason),
                         }
                     }
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        println!("Error while accepting connection: {}", e);
+                        break;
+                    }
                 }
-                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => {}
-                Err(e) => println!("Error while accepting connection: {}", e),
             }
-        }
 
-        // then try to connect to all smaller machine_ids
-        for (machine_id, address) in self.network.iter().enumerate() {
-            if machine_id < self.machine_id.0 as usize {
-                if self.network_connections[machine_id].is_none() {
-                    let stream = TcpStream::connect(address).unwrap();
-                    stream.set_read_timeout(None).unwrap();
-                    stream.set_write_timeout(None).unwrap();
-                    let mut websocket =
-                        websocket_client(Url::parse(&format!("ws://{}", address)).unwrap(), stream)
-                            .unwrap()
-                            .0;
-                    match websocket
-                        .write_message(WebSocketMessage::binary(vec![self.machine_id.0]))
-                        .and_then(|_| websocket.write_pending())
-                    {
-                        Ok(_) => {}
-                        Err(e) => panic!("Error while sending first message: {}", e),
+            for incoming_peer in ::std::mem::replace(&mut self.incoming_peers, Vec::new()) {
+                let address = incoming_peer.address;
+                let advanced = incoming_peer.advance(
+                    &self.shared_secret,
+                    self.machine_id,
+                    self.network_connections.len(),
+                    now,
+                );
+                match advanced.and_then(|step| match step {
+                    Step::Waiting(incoming_peer) => {
+                        self.incoming_peers.push(incoming_peer);
+                        Ok(())
                     }
-                    self.network_connections[machine_id] =
-                        Some(Connection::new(websocket, self.batch_message_bytes));
+                    Step::Done(authenticated_peer) => self.accept_peer(authenticated_peer),
+                }) {
+                    Ok(()) => {}
+                    Err(reason) => println!("Rejecting connection from {}: {}", address, reason),
+                }
+            }
+        }
+
+        // then connect to all smaller machine_ids
+        for machine_id in 0..self.network.len() {
+            if self.connects_to(machine_id)
+                && self.network_connections[machine_id].is_none()
+                && !self.outgoing_peers.iter().any(|peer| peer.machine_id == machine_id)
+                && self.reconnects[machine_id].is_due(now)
+            {
+                let authentication = Authentication::new(
+                    &self.shared_secret,
+                    self.machine_id,
+                    MachineID(machine_id as u8),
+                );
+                let resume_offer = self.sessions[machine_id].resume_offer(self.incarnation);
+                let mut handshake = resume_offer.encode();
+                handshake.extend_from_slice(&self.local_handshake.encode());
+                match OutgoingPeer::new(
+                    machine_id,
+                    &self.network[machine_id],
+                    authentication,
+                    handshake,
+                    resume_offer.peer_incarnation,
+                    now,
+                ) {
+                    Ok(outgoing_peer) => self.outgoing_peers.push(outgoing_peer),
+                    Err(reason) => self.connection_failed(machine_id, &reason, now),
+                }
+            }
+        }
+
+        for outgoing_peer in ::std::mem::replace(&mut self.outgoing_peers, Vec::new()) {
+            let machine_id = outgoing_peer.machine_id;
+            match outgoing_peer.advance(now) {
+                Ok(Step::Waiting(outgoing_peer)) => self.outgoing_peers.push(outgoing_peer),
+                Ok(Step::Done(connected_peer)) => {
+                    let mut connection = Connection::new(connected_peer.websocket);
+                    connection.offered_peer_incarnation = connected_peer.offered_peer_incarnation;
+                    self.network_connections[machine_id] = Some(connection);
                     println!("Connected to Machine ID {}", machine_id);
                 }
+                Err(reason) => self.connection_failed(machine_id, &reason, now),
             }
         }
     }
 
+    /// Try connecting to a peer again later, after an attempt failed
+    #[cfg(feature = "server")]
+    fn connection_failed(&mut self, machine_id: usize, reason: &str, now: Instant) {
+        let delay = self.reconnects[machine_id].failed(now, self.min_reconnect_delay, self.max_reconnect_delay);
+        println!(
+            "Could not connect to Machine ID {}: {}, retrying in {:?}",
+            machine_id, reason, delay
+        );
+    }
+
     #[cfg(feature = "browser")]
     pub fn connect(&mut self) {
+        let now = now();
         for (machine_id, address) in self.network.iter().enumerate() {
-            if machine_id != self.machine_id.0 as usize {
-                if self.network_connections[machine_id].is_none() {
-                    let wsAddress = websocket_address(address);
-                    let websocket = WebSocket::new(&wsAddress).unwrap();
-                    let mut connection = Some(Connection::new(websocket, self.batch_message_bytes));
-                    connection
-                        .as_mut()
-                        .unwrap()
-                        .out_batches
-                        .insert(0, vec![self.machine_id.0]);
-                    self.network_connections[machine_id] = connection;
-                }
+            if self.connects_to(machine_id)
+                && self.network_connections[machine_id].is_none()
+                && self.reconnects[machine_id].is_due(now)
+            {
+                let wsAddress = websocket_address(address);
+                let websocket = match WebSocket::new(&wsAddress) {
+                    Ok(websocket) => websocket,
+                    Err(e) => {
+                        let delay = self.reconnects[machine_id].failed(
+                            now,
+                            self.min_reconnect_delay,
+                            self.max_reconnect_delay,
+                        );
+                        println!(
+                            "Could not connect to Machine ID {}: {:?}, retrying in {:?}",
+                            machine_id, e, delay
+                        );
+                        continue;
+                    }
+                };
+                let mut connection = Connection::new(websocket);
+                let authentication = Authentication::new(
+                    &self.shared_secret,
+                    self.machine_id,
+                    MachineID(machine_id as u8),
+                );
+                connection.own_hello = Some(authentication.hello());
+                connection.authentication = Some(authentication);
+                let resume_offer = self.sessions[machine_id].resume_offer(self.incarnation);
+                let mut handshake = resume_offer.encode();
+                handshake.extend_from_slice(&self.local_handshake.encode());
+                connection.own_handshake = Some(handshake);
+                connection.offered_peer_incarnation = resume_offer.peer_incarnation;
+                self.network_connections[machine_id] = Some(connection);
             }
         }
     }
 
+    /// Do we connect to this peer, or does it connect to us?
+    fn connects_to(&self, machine_id: usize) -> bool {
+        if cfg!(feature = "browser") {
+            machine_id != self.machine_id.0 as usize
+        } else {
+            // servers with larger machine IDs connect to those with smaller ones
+            machine_id < self.machine_id.0 as usize
+        }
+    }
+
+    /// Get the state of the connection to each peer
+    pub(crate) fn connection_states(&self) -> HashMap<MachineID, ConnectionState> {
+        let now = now();
+        self.network_connections
+            .iter()
+            .enumerate()
+            .filter(|&(machine_id, _)| machine_id != self.machine_id.0 as usize)
+            .map(|(machine_id, maybe_connection)| {
+                let state = match maybe_connection {
+                    Some(connection) if connection.peer_verified => ConnectionState::Connected,
+                    Some(_) => ConnectionState::Handshaking,
+                    #[cfg(feature = "server")]
+                    None if self.outgoing_peers.iter().any(|peer| peer.machine_id == machine_id) => {
+                        ConnectionState::Handshaking
+                    }
+                    None if self.refused[machine_id] => ConnectionState::Refused {
+                        next_attempt_in: self.reconnects[machine_id].next_attempt_in(now),
+                    },
+                    None if !self.connects_to(machine_id) => ConnectionState::AwaitingPeer,
+                    None => ConnectionState::Reconnecting {
+                        n_failed_attempts: self.reconnects[machine_id].n_failed_attempts,
+                        next_attempt_in: self.reconnects[machine_id].next_attempt_in(now),
+                    },
+                };
+                (MachineID(machine_id as u8), state)
+            })
+            .collect()
+    }
+
+    /// Exchange type handshakes with a peer that connected to us and authenticated
+    #[cfg(feature = "server")]
+    fn accept_peer(&mut self, authenticated_peer: AuthenticatedPeer) -> Result<(), String> {
+        let AuthenticatedPeer { mut websocket, machine_id: peer_id, handshake: peer_handshake } = authenticated_peer;
+        let (peer_resume, peer_handshake) = Resume::decode(&peer_handshake)
+            .and_then(|(peer_resume, peer_handshake)| {
+                Handshake::decode(peer_handshake).map(|peer_handshake| (peer_resume, peer_handshake))
+            })
+            .filter(|(_, handshake)| handshake.machine_id == peer_id)
+            .ok_or_else(|| "Got a malformed handshake".to_owned())?;
+
+        // tell the peer about our types in any case,
+        // so it can report a mismatch on its side as well
+        let peer = peer_id.0 as usize;
+        let resume_offer = self.sessions[peer].resume_offer(self.incarnation);
+        let mut handshake = resume_offer.encode();
+        handshake.extend_from_slice(&self.local_handshake.encode());
+        queue_binary(&mut websocket, handshake)
+            .map_err(|e| format!("Error while answering handshake: {}", e))?;
+        let remap = self
+            .check_peer_types(&peer_handshake)
+            .map_err(|()| "Type mismatch".to_owned())?;
+        self.start_session(peer, &peer_resume, resume_offer.peer_incarnation);
+
+        // the peer might have reconnected before we noticed that its old connection dropped
+        if self.network_connections[peer]
+            .as_ref()
+            .map_or(false, |connection| connection.peer_verified)
+        {
+            self.disconnected_machines.push(peer_id);
+            self.events.push(NetworkEvent::PeerDisconnected(peer_id));
+        }
+
+        let mut connection = Connection::new(websocket);
+        connection.peer_verified = true;
+        connection.remap = remap;
+        self.network_connections[peer] = Some(connection);
+        self.events.push(NetworkEvent::PeerConnected(peer_id));
+        println!("...machine ID {} connected!", peer_id.0);
+        Ok(())
+    }
+
+    /// Make a message type sendable and receivable over the network, if it is `Portable`
+    pub(crate) fn register_message<M: Message>(&mut self, message_type_id: ShortTypeId) {
+        if let Some(codec) = M::wire_codec() {
+            Arc::make_mut(&mut self.codecs).get_or_insert_with(message_type_id, || codec);
+        }
+    }
+
+    pub(crate) fn codecs(&self) -> Arc<DenseTypeIdMap<WireCodec>> {
+        Arc::clone(&self.codecs)
+    }
+
+    /// Get the portable encoding of a packet in local layout,
+    /// which is empty for message types that weren't registered or aren't `Portable`
+    pub(crate) fn encode_packet(&self, message_type_id: ShortTypeId, packet_ptr: *const ()) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        if let Some(codec) = self.codecs.get(message_type_id) {
+            (codec.encode)(packet_ptr, &mut encoded);
+        }
+        encoded
+    }
+
+    /// Update the types this machine tells peers about when connecting
+    pub(crate) fn set_local_types(&mut self, actors: &TypeRegistry, messages: &TypeRegistry) {
+        self.local_handshake = Handshake::new(self.machine_id, actors, messages);
+    }
+
+    /// Translate the type IDs a peer uses to local ones, instead of refusing to
+    /// connect to peers whose type IDs differ (see `TypeMismatch`)
+    pub(crate) fn set_remap_type_ids(&mut self, remap_type_ids: bool) {
+        self.remap_type_ids = remap_type_ids;
+    }
+
+    /// Get all type mismatches with peers that led to refused connections so far
+    pub(crate) fn type_mismatches(&self) -> &[TypeMismatch] {
+        &self.type_mismatches
+    }
+
+    /// Get all invalid data received from peers so far, which closed the connection to them
+    pub(crate) fn input_errors(&self) -> &[(MachineID, InvalidInput)] {
+        &self.input_errors
+    }
+
+    /// Get the policy of what a peer may send, restricting it if it wasn't already
+    pub(crate) fn inbound_policy_mut(&mut self, machine_id: MachineID) -> &mut InboundPolicy {
+        self.inbound_policies.entry(machine_id).or_insert_with(InboundPolicy::default)
+    }
+
+    /// Get counts of messages from restricted peers that weren't allowed, per message type
+    pub(crate) fn rejected_messages(&self) -> &HashMap<MachineID, TypeIdCounts> {
+        &self.rejected_messages
+    }
+
+    /// Resume the session with a peer if both sides offered to, or start a new one
+    fn start_session(&mut self, machine_id: usize, peer_resume: &Resume, offered_peer_incarnation: u64) {
+        let session = &mut self.sessions[machine_id];
+        // report the peer as lagging again if it still is
+        session.lagging = false;
+        if !peer_resume.agrees_with(self.incarnation, offered_peer_incarnation)
+            || !session.resume(peer_resume.n_received)
+        {
+            // if the peer resumes nonetheless, it gets out of sequence and starts over as well
+            session.restart(peer_resume.incarnation);
+        }
+    }
+
+    /// Check that a peer agrees with us on all type IDs. If it doesn't,
+    /// either get a remapping of its type IDs (if enabled), or record the mismatch.
+    /// Refused peers are still connected to again (with backoff), since they
+    /// might come back with matching types - the mismatch is only recorded once then.
+    fn check_peer_types(&mut self, peer_handshake: &Handshake) -> Result<Option<TypeIdRemap>, ()> {
+        let peer = usize::from(peer_handshake.machine_id.0);
+        let result = match self.local_handshake.first_mismatch(peer_handshake) {
+            None => Ok(None),
+            Some(_) if self.remap_type_ids => {
+                Ok(Some(self.local_handshake.remapping_from(peer_handshake)))
+            }
+            Some(mismatch) => {
+                println!("Refusing connection: {}", mismatch);
+                if !self.type_mismatches.contains(&mismatch) {
+                    self.type_mismatches.push(mismatch);
+                }
+                Err(())
+            }
+        };
+        self.refused[peer] = result.is_err();
+        result
+    }
+
     pub(crate) fn finish_turn(&mut self) -> Option<usize> {
         let mut maybe_skip_turns = None;
 
-        for maybe_connection in &mut self.network_connections {
-            if let Some(Connection { n_turns, .. }) = *maybe_connection {
+        for (machine_id, session) in self.sessions.iter_mut().enumerate() {
+            if self.network_connections[machine_id].is_some() {
+                let peer = MachineID(machine_id as u8);
+                let n_turns = session.n_turns;
                 if n_turns + self.acceptable_turn_distance < self.n_turns {
                     maybe_skip_turns = Some(
                         (self.n_turns - self.acceptable_turn_distance - n_turns)
                             * self.skip_turns_per_turn_head,
                     );
+                    if !session.lagging {
+                        session.lagging = true;
+                        self.events.push(NetworkEvent::PeerLagging {
+                            peer,
+                            turns_behind: self.n_turns - n_turns,
+                        });
+                    }
+                } else if session.lagging {
+                    session.lagging = false;
+                    self.events.push(NetworkEvent::PeerCaughtUp(peer));
                 }
             }
         }
 
         self.n_turns += 1;
 
-        for maybe_connection in self.network_connections.iter_mut() {
-            if let Some(ref mut connection) = *maybe_connection {
+        for (machine_id, session) in self.sessions.iter_mut().enumerate() {
+            if self.network_connections[machine_id].is_some() || session.is_established() {
                 // write turn end, use 0 as "message type" to distinguish from actual packet
-                {
-                    let data = connection.enqueue_in_batch(
-                        ::std::mem::size_of::<ShortTypeId>() + ::std::mem::size_of::<u32>(),
-                    );
+                if let Some(data) = session.enqueue_in_batch(
+                    ::std::mem::size_of::<ShortTypeId>() + ::std::mem::size_of::<u32>(),
+                    self.resend_buffer_bytes,
+                ) {
                     data.write_u16::<LittleEndian>(0).unwrap();
                     data.write_u32::<LittleEndian>(self.n_turns as u32).unwrap();
                 }
-                connection.n_turns_since_own_turn = 0;
+                session.n_turns_since_own_turn = 0;
             }
         }
 
@@ -205,19 +516,78 @@ impl Networking {
 
     pub(crate) fn send_and_receive(
         &mut self,
-        classes: &mut [Option<Class>],
-        implementors: &mut [Option<Vec<ShortTypeId>>],
+        classes: &mut DenseTypeIdMap<Class>,
+        implementors: &mut DenseTypeIdMap<Vec<ShortTypeId>>,
+        down_type: Option<ShortTypeId>,
     ) {
         self.connect();
 
+        for machine_id in 0..self.network_connections.len() {
+            let peer_handshake = self.network_connections[machine_id]
+                .as_mut()
+                .and_then(|connection| connection.take_received_handshake());
+
+            if let Some(peer_handshake) = peer_handshake {
+                let offered_peer_incarnation = self.network_connections[machine_id]
+                    .as_ref()
+                    .unwrap()
+                    .offered_peer_incarnation;
+                match Resume::decode(&peer_handshake)
+                    .and_then(|(peer_resume, peer_handshake)| {
+                        Handshake::decode(peer_handshake).map(|peer_handshake| (peer_resume, peer_handshake))
+                    })
+                    .filter(|(_, peer_handshake)| usize::from(peer_handshake.machine_id.0) == machine_id)
+                    .ok_or(())
+                    .and_then(|(peer_resume, peer_handshake)| {
+                        let remap = self.check_peer_types(&peer_handshake)?;
+                        self.start_session(machine_id, &peer_resume, offered_peer_incarnation);
+                        Ok(remap)
+                    })
+                {
+                    Ok(remap) => {
+                        let connection = self.network_connections[machine_id].as_mut().unwrap();
+                        connection.peer_verified = true;
+                        connection.remap = remap;
+                        self.reconnects[machine_id].succeeded();
+                        self.events.push(NetworkEvent::PeerConnected(MachineID(machine_id as u8)));
+                    }
+                    Err(()) => {
+                        println!("Closing connection to Machine ID {} after handshake", machine_id);
+                        self.network_connections[machine_id] = None;
+                        self.reconnects[machine_id].failed(
+                            now(),
+                            self.min_reconnect_delay,
+                            self.max_reconnect_delay,
+                        );
+                    }
+                }
+            }
+        }
+
         for (machine_id, maybe_connection) in self.network_connections.iter_mut().enumerate() {
+            let session = &mut self.sessions[machine_id];
             let closed_reason = if let Some(ref mut connection) = *maybe_connection {
-                match connection
-                    .try_send_pending()
-                    .and_then(|_| connection.try_receive(classes, implementors))
-                {
-                    Ok(()) => None,
-                    Err(err) => Some(err),
+                let mut ingress = Ingress {
+                    classes: &mut *classes,
+                    implementors: &*implementors,
+                    codecs: &self.codecs,
+                    policy: self.inbound_policies.get(&MachineID(machine_id as u8)),
+                    rejected: self
+                        .rejected_messages
+                        .entry(MachineID(machine_id as u8))
+                        .or_insert_with(TypeIdCounts::new),
+                    down_type,
+                    remote_deaths: &mut self.remote_deaths,
+                };
+                if session.is_broken() {
+                    // start a new session when reconnecting
+                    Some(ConnectionError::ResendBufferOverflow)
+                } else {
+                    connection
+                        .try_send_pending(session)
+                        .map_err(ConnectionError::Socket)
+                        .and_then(|_| connection.try_receive(session, &mut ingress))
+                        .err()
                 }
             } else {
                 None
@@ -228,7 +598,22 @@ impl Networking {
                     "Closed connection to Machine ID {} while receiving: {}",
                     machine_id, closed_reason
                 );
-                *maybe_connection = None
+                if let ConnectionError::InvalidInput(invalid_input) = closed_reason {
+                    session.received_invalid_input();
+                    self.input_errors.push((MachineID(machine_id as u8), invalid_input));
+                }
+                if maybe_connection.as_ref().map_or(false, |connection| connection.peer_verified) {
+                    self.events.push(NetworkEvent::PeerDisconnected(MachineID(machine_id as u8)));
+                }
+                session.lagging = false;
+                *maybe_connection = None;
+                self.disconnected_machines.push(MachineID(machine_id as u8));
+                // reconnect after a delay
+                self.reconnects[machine_id].failed(
+                    now(),
+                    self.min_reconnect_delay,
+                    self.max_reconnect_delay,
+                );
             }
         }
 
@@ -237,9 +622,10 @@ impl Networking {
             let max_n_turns = self
                 .network_connections
                 .iter()
-                .map(|maybe_connection| {
-                    if let Some(connection) = maybe_connection {
-                        connection.n_turns
+                .zip(self.sessions.iter())
+                .map(|(maybe_connection, session)| {
+                    if maybe_connection.is_some() {
+                        session.n_turns
                     } else {
                         0
                     }
@@ -253,44 +639,92 @@ impl Networking {
         }
     }
 
-    pub(crate) fn enqueue<M: Message>(
-        &mut self,
-        message_type_id: ShortTypeId,
-        mut packet: Packet<M>,
-    ) {
+    /// Enqueue a message for the machine of its recipient.
+    /// Returns false if the message type can't be sent because it isn't `Portable`.
+    pub(crate) fn enqueue<M: Message>(&mut self, message_type_id: ShortTypeId, packet: &Packet<M>) -> bool {
+        self.enqueue_local_layout(
+            packet.recipient_id.machine,
+            message_type_id,
+            packet as *const Packet<M> as *const (),
+        )
+    }
+
+    /// Get the machines whose connection closed since the last call
+    pub(crate) fn take_disconnected_machines(&mut self) -> Vec<MachineID> {
+        ::std::mem::replace(&mut self.disconnected_machines, Vec::new())
+    }
+
+    /// Get the (watcher, target) pairs of remote monitored actors
+    /// that were reported as died since the last call
+    pub(crate) fn take_remote_deaths(&mut self) -> Vec<(RawID, RawID)> {
+        ::std::mem::replace(&mut self.remote_deaths, Vec::new())
+    }
+
+    /// Get the peer lifecycle events since the last call
+    pub(crate) fn take_events(&mut self) -> Vec<NetworkEvent> {
+        ::std::mem::replace(&mut self.events, Vec::new())
+    }
+
+    /// Enqueue a message in local layout (message type followed by a compact `Packet`).
+    /// Returns false if the message type can't be sent because it isn't `Portable`.
+    pub(crate) fn enqueue_raw(&mut self, machine_id: MachineID, data: &[u8]) -> bool {
+        let message_type_id = ShortTypeId::new(LittleEndian::read_u16(data))
+            .expect("Enqueued message should have a valid type");
+        let packet = aligned_copy(&data[::std::mem::size_of::<ShortTypeId>()..]);
+        self.enqueue_local_layout(machine_id, message_type_id, packet.as_ptr() as *const ())
+    }
+
+    fn enqueue_local_layout(&mut self, machine_id: MachineID, message_type_id: ShortTypeId, packet_ptr: *const ()) -> bool {
         if self.network.len() == 1 {
-            return;
+            return true;
         }
 
-        let packet_size = Compact::total_size_bytes(&packet);
-        let total_size = ::std::mem::size_of::<ShortTypeId>() + packet_size;
-        let machine_id = packet.recipient_id.machine;
-
-        let recipients = if machine_id == broadcast_machine_id() {
-            (0..self.network.len()).into_iter().collect()
-        } else {
-            vec![machine_id.0 as usize]
+        let codec = match self.codecs.get(message_type_id) {
+            Some(codec) => *codec,
+            None => return false,
         };
 
-        for machine_id in recipients {
-            if let Some(connection) = self.network_connections[machine_id].as_mut() {
-                let data = connection.enqueue_in_batch(total_size);
-                data.write_u16::<LittleEndian>(message_type_id.into())
-                    .unwrap();
-                let packet_pos = data.len();
-                data.resize(packet_pos + packet_size, 0);
-
-                unsafe {
-                    // store packet compactly in write queue
-                    Compact::compact_behind(
-                        &mut packet,
-                        &mut data[packet_pos] as *mut u8 as *mut Packet<M>,
-                    );
+        let mut encoded = Vec::new();
+        encoded
+            .write_u16::<LittleEndian>(message_type_id.into())
+            .unwrap();
+        (codec.encode)(packet_ptr, &mut encoded);
+
+        self.enqueue_encoded(machine_id, &encoded);
+        true
+    }
+
+    /// Enqueue a message in wire encoding (message type followed by a portable `Packet`)
+    fn enqueue_encoded(&mut self, machine_id: MachineID, data: &[u8]) {
+        for machine_id in self.recipient_machines(machine_id) {
+            // keep messages for peers that were connected before, to send them when reconnecting
+            let session = &mut self.sessions[machine_id];
+            if self.network_connections[machine_id].is_some() || session.is_established() {
+                if let Some(batch) = session.enqueue_in_batch(data.len(), self.resend_buffer_bytes) {
+                    batch.extend_from_slice(data);
                 }
             }
         }
+    }
+
+    /// The number of machines currently reachable, including this one
+    pub fn n_connected_machines(&self) -> usize {
+        1 + self
+            .network_connections
+            .iter()
+            .enumerate()
+            .filter(|&(i, maybe_connection)| {
+                i != usize::from(self.machine_id.0) && maybe_connection.is_some()
+            })
+            .count()
+    }
 
-        ::std::mem::forget(packet);
+    fn recipient_machines(&self, machine_id: MachineID) -> Vec<usize> {
+        if machine_id == broadcast_machine_id() {
+            (0..self.network.len()).into_iter().collect()
+        } else {
+            vec![machine_id.0 as usize]
+        }
     }
 
     pub(crate) fn debug_all_n_turns(&self) -> HashMap<MachineID, isize> {
@@ -302,12 +736,10 @@ impl Networking {
                     MachineID(i as u8),
                     if i == usize::from(self.machine_id.0) {
                         self.n_turns as isize
+                    } else if maybe_connection.is_some() {
+                        self.sessions[i].n_turns as isize
                     } else {
-                        if let Some(connection) = maybe_connection.as_ref() {
-                            connection.n_turns as isize
-                        } else {
-                            -1
-                        }
+                        -1
                     },
                 )
             })
@@ -320,6 +752,29 @@ impl Networking {
     }
 }
 
+#[cfg(feature = "server")]
+type SocketError = ::tungstenite::Error;
+#[cfg(feature = "browser")]
+type SocketError = ::std::io::Error;
+
+/// Why a connection to a peer was closed
+pub enum ConnectionError {
+    Socket(SocketError),
+    InvalidInput(InvalidInput),
+    ResendBufferOverflow,
+}
+
+impl ::std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            ConnectionError::Socket(err) => write!(f, "{}", err),
+            ConnectionError::InvalidInput(invalid_input) => write!(f, "{}", invalid_input),
+            ConnectionError::ResendBufferOverflow => write!(f, "Too many messages were not acknowledged"),
+        }
+    }
+}
+
+#[cfg(any(feature = "browser", test))]
 fn websocket_address(address: &str) -> String  {
     let v: Vec<&str> = address.split("://").collect();
     if v.len() == 1 {
@@ -349,16 +804,19 @@ fn test_websocket_address() {
 
 #[cfg(feature = "server")]
 pub struct Connection {
-    n_turns: usize,
-    n_turns_since_own_turn: usize,
     websocket: WebSocket<TcpStream>,
-    out_batches: Vec<Vec<u8>>,
-    batch_message_bytes: usize,
+    /// The run of the peer we offered to resume our session with when connecting
+    offered_peer_incarnation: u64,
+    /// Did the peer's handshake show that it agrees with us on type IDs?
+    /// Until then, nothing is sent or dispatched.
+    peer_verified: bool,
+    received_handshake: Option<Vec<u8>>,
+    remap: Option<TypeIdRemap>,
 }
 
 #[cfg(feature = "server")]
 impl Connection {
-    pub fn new(mut websocket: WebSocket<TcpStream>, batch_message_bytes: usize) -> Connection {
+    pub fn new(mut websocket: WebSocket<TcpStream>) -> Connection {
         {
             let tcp_socket = websocket.get_mut();
             tcp_socket.set_nonblocking(true).unwrap();
@@ -367,47 +825,28 @@ impl Connection {
             tcp_socket.set_nodelay(true).unwrap();
         }
         Connection {
-            n_turns: 0,
-            n_turns_since_own_turn: 0,
             websocket,
-            out_batches: vec![Vec::with_capacity(batch_message_bytes)],
-            batch_message_bytes,
+            offered_peer_incarnation: 0,
+            peer_verified: false,
+            received_handshake: None,
+            remap: None,
         }
     }
 
-    pub fn enqueue_in_batch(&mut self, message_size: usize) -> &mut Vec<u8> {
-        // let recipient_id =
-        //     (&message[::std::mem::size_of::<ShortTypeId>()] as *const u8) as *const RawID;
-        // println!(
-        //     "Enqueueing message recipient: {:?}, data: {:?}",
-        //     unsafe{(*recipient_id)}, message
-        // );
+    /// Take the handshake the peer sent as its first message, if it just arrived
+    pub(crate) fn take_received_handshake(&mut self) -> Option<Vec<u8>> {
+        self.received_handshake.take()
+    }
 
-        if message_size > self.batch_message_bytes {
-            panic!("Message size exceeds message batch size");
+    pub(crate) fn try_send_pending(&mut self, session: &mut Session) -> Result<(), ::tungstenite::Error> {
+        if !self.peer_verified {
+            return Ok(());
         }
 
-        let batch =
-            if self.out_batches.last().unwrap().len() < self.batch_message_bytes - message_size {
-                self.out_batches.last_mut().unwrap()
-            } else {
-                self.out_batches
-                    .push(Vec::with_capacity(self.batch_message_bytes));
-                self.out_batches.last_mut().unwrap()
-            };
-
-        batch
-            .write_u32::<LittleEndian>(message_size as u32)
-            .unwrap();
-
-        batch
-    }
-
-    pub fn try_send_pending(&mut self) -> Result<(), ::tungstenite::Error> {
-        for batch in self.out_batches.drain(..) {
+        for frame in session.outgoing() {
             match self
                 .websocket
-                .write_message(WebSocketMessage::binary(batch))
+                .write_message(WebSocketMessage::binary(frame))
             {
                 Ok(_) => {}
                 Err(e) => {
@@ -418,9 +857,6 @@ impl Connection {
             }
         }
 
-        self.out_batches
-            .push(Vec::with_capacity(self.batch_message_bytes));
-
         match self.websocket.write_pending() {
             Ok(()) => Ok(()),
             Err(e) => {
@@ -433,24 +869,34 @@ impl Connection {
         }
     }
 
-    pub fn try_receive(
+    pub(crate) fn try_receive(
         &mut self,
-        classes: &mut [Option<Class>],
-        implementors: &mut [Option<Vec<ShortTypeId>>],
-    ) -> Result<(), ::tungstenite::Error> {
+        session: &mut Session,
+        ingress: &mut Ingress,
+    ) -> Result<(), ConnectionError> {
+        if !self.peer_verified && self.received_handshake.is_some() {
+            return Ok(());
+        }
+
         loop {
             let blocked = match self.websocket.read_message() {
-                Ok(WebSocketMessage::Binary(data)) => dispatch_batch(
-                    &data,
-                    classes,
-                    implementors,
-                    &mut self.n_turns,
-                    &mut self.n_turns_since_own_turn,
-                ),
-                Ok(other_message) => panic!("Got a non binary message: {:?}", other_message),
+                Ok(WebSocketMessage::Binary(mut data)) => {
+                    if self.peer_verified {
+                        receive_frame(&mut data, session, ingress, self.remap.as_ref())?
+                    } else {
+                        // the first message is the peer's handshake,
+                        // wait for it to be checked before dispatching anything
+                        self.received_handshake = Some(data);
+                        true
+                    }
+                }
+                Ok(WebSocketMessage::Ping(_)) | Ok(WebSocketMessage::Pong(_)) => false,
+                Ok(WebSocketMessage::Text(_)) => {
+                    return Err(ConnectionError::InvalidInput(InvalidInput::NotBinary))
+                }
                 Err(e) => {
                     if let Some(real_err) = e.into_non_blocking() {
-                        return Err(real_err);
+                        return Err(ConnectionError::Socket(real_err));
                     } else {
                         true
                     }
@@ -465,89 +911,211 @@ impl Connection {
     }
 }
 
+/// Dispatch the batch in a frame received from a peer, unless it was received before.
+/// Returns whether to wait before receiving more, like `dispatch_batch`.
+fn receive_frame(
+    frame: &mut [u8],
+    session: &mut Session,
+    ingress: &mut Ingress,
+    remap: Option<&TypeIdRemap>,
+) -> Result<bool, ConnectionError> {
+    match session.receive(frame).map_err(ConnectionError::InvalidInput)? {
+        Some(batch) => {
+            let one_wants_to_wait = dispatch_batch(
+                batch,
+                ingress,
+                remap,
+                &mut session.n_turns,
+                &mut session.n_turns_since_own_turn,
+            ).map_err(ConnectionError::InvalidInput)?;
+            session.received();
+            Ok(one_wants_to_wait)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Everything needed to check and deliver messages received from one peer
+pub struct Ingress<'a> {
+    classes: &'a mut DenseTypeIdMap<Class>,
+    implementors: &'a DenseTypeIdMap<Vec<ShortTypeId>>,
+    codecs: &'a DenseTypeIdMap<WireCodec>,
+    /// What the peer may send, if it is restricted
+    policy: Option<&'a InboundPolicy>,
+    rejected: &'a mut TypeIdCounts,
+    /// The type of `Down`, if it is registered
+    down_type: Option<ShortTypeId>,
+    /// Where to report `Down` messages about died remote actors
+    remote_deaths: &'a mut Vec<(RawID, RawID)>,
+}
+
+/// A message from a peer, checked and decoded into the local layout
+enum Received {
+    TurnStart(usize),
+    /// Message type followed by a compact `Packet`, and the classes it should go to
+    Message(Vec<u8>, Vec<ShortTypeId>),
+    /// A message of the given type that the peer isn't allowed to send
+    Rejected(ShortTypeId),
+}
+
 fn dispatch_batch(
-    data: &[u8],
-    classes: &mut [Option<Class>],
-    implementors: &mut [Option<Vec<ShortTypeId>>],
+    data: &mut [u8],
+    ingress: &mut Ingress,
+    remap: Option<&TypeIdRemap>,
     n_turns: &mut usize,
     n_turns_since_own_turn: &mut usize,
-) -> bool {
+) -> Result<bool, InvalidInput> {
     // let msg = format!("Got batch of len {}, {:?}", data.len(), data);
     // #[cfg(feature = "server")]
     // println!("{}", msg);
     // #[cfg(feature = "browser")]
     // console!(log, msg);
 
+    // check and decode the whole batch before putting anything into inboxes
+    let mut received = Vec::new();
     let mut pos = 0;
-    let mut one_wants_to_wait = false;
 
     while pos < data.len() {
-        let message_size = LittleEndian::read_u32(&data[pos..]);
-        pos += ::std::mem::size_of::<u32>();
-        let wants_to_wait = dispatch_message(
-            &data[pos..(pos + message_size as usize)],
-            classes,
-            implementors,
-            n_turns,
-            n_turns_since_own_turn,
-        );
-        one_wants_to_wait = one_wants_to_wait || wants_to_wait;
+        let message_start = pos + ::std::mem::size_of::<u32>();
+        let message_size = data
+            .get(pos..message_start)
+            .map(LittleEndian::read_u32)
+            .ok_or(InvalidInput::Truncated)? as usize;
+        let message_end = message_start
+            .checked_add(message_size)
+            .filter(|&message_end| message_end <= data.len())
+            .ok_or(InvalidInput::Truncated)?;
+
+        received.push(decode_message(
+            &mut data[message_start..message_end],
+            ingress,
+            remap,
+        )?);
+
+        pos = message_end;
+    }
+
+    let mut one_wants_to_wait = false;
 
-        pos += message_size as usize;
+    for message in received {
+        match message {
+            Received::TurnStart(peer_n_turns) => {
+                *n_turns = peer_n_turns;
+                *n_turns_since_own_turn += 1;
+
+                // pretend that we're blocked so we only ever process all
+                // messages of 10 incoming turns within one of our own turns,
+                // applying backpressure
+                one_wants_to_wait = one_wants_to_wait || *n_turns_since_own_turn >= 10;
+            }
+            Received::Message(data, recipient_classes) => {
+                if ShortTypeId::new(LittleEndian::read_u16(&data)) == ingress.down_type {
+                    #[allow(clippy::cast_ptr_alignment)]
+                    let down = unsafe {
+                        ::std::ptr::read_unaligned(
+                            &data[::std::mem::size_of::<ShortTypeId>()] as *const u8 as *const Packet<Down>,
+                        )
+                    };
+                    if down.message.reason == DownReason::Died {
+                        ingress.remote_deaths.push((down.recipient_id, down.message.id));
+                    }
+                }
+                for class_id in recipient_classes {
+                    if let Some(class) = ingress.classes.get_mut(class_id) {
+                        class.inbox.put_raw(&data);
+                    }
+                }
+            }
+            Received::Rejected(message_type) => ingress.rejected.increment(message_type),
+        }
     }
 
-    one_wants_to_wait
+    Ok(one_wants_to_wait)
 }
 
-fn dispatch_message(
-    data: &[u8],
-    classes: &mut [Option<Class>],
-    implementors: &mut [Option<Vec<ShortTypeId>>],
-    n_turns: &mut usize,
-    n_turns_since_own_turn: &mut usize,
-) -> bool {
-    if data[0] == 0 && data[1] == 0 {
+fn decode_message(
+    data: &mut [u8],
+    ingress: &Ingress,
+    remap: Option<&TypeIdRemap>,
+) -> Result<Received, InvalidInput> {
+    let message_type = data
+        .get(0..::std::mem::size_of::<ShortTypeId>())
+        .map(LittleEndian::read_u16)
+        .ok_or(InvalidInput::Truncated)?;
+
+    if message_type == 0 {
         // this is actually a turn start
-        *n_turns = LittleEndian::read_u32(&data[::std::mem::size_of::<ShortTypeId>()..]) as usize;
-        *n_turns_since_own_turn += 1;
+        let peer_n_turns = data
+            .get(::std::mem::size_of::<ShortTypeId>()..)
+            .filter(|rest| rest.len() == ::std::mem::size_of::<u32>())
+            .map(LittleEndian::read_u32)
+            .ok_or(InvalidInput::Truncated)?;
+        return Ok(Received::TurnStart(peer_n_turns as usize));
+    }
 
-        // pretend that we're blocked so we only ever process all
-        // messages of 10 incoming turns within one of our own turns,
-        // applying backpressure
-        *n_turns_since_own_turn >= 10
-    } else {
-        let recipient_id =
-            (&data[::std::mem::size_of::<ShortTypeId>()] as *const u8) as *const RawID;
+    if let Some(remap) = remap {
+        remap.apply(data);
+    }
 
-        unsafe {
-            if let Some(ref mut class) = classes[(*recipient_id).type_id.as_usize()] {
-                class.inbox.put_raw(&data);
+    // decode the wire encoding into the local layout
+    let message_type = LittleEndian::read_u16(data);
+    let message_type_id = ShortTypeId::new(message_type)
+        .filter(|&message_type_id| ingress.codecs.contains(message_type_id))
+        .ok_or(InvalidInput::UnknownMessageType(message_type))?;
+    let codec = ingress.codecs.get(message_type_id).unwrap();
+    let mut reader = WireReader::new(&data[::std::mem::size_of::<ShortTypeId>()..]);
+    let packet = (codec.decode)(&mut reader)
+        .and_then(|packet| {
+            if reader.remaining() == 0 {
+                Ok(packet)
             } else {
-                if let Some(implementors) =
-                    implementors[(*recipient_id).type_id.as_usize()].as_ref()
-                {
-                    for implementor_type_id in implementors {
-                        if let Some(class) = classes[implementor_type_id.as_usize()].as_mut() {
-                            class.inbox.put_raw(&data);
-                        } else {
-                            panic!(
-                                "No inbox for actor type {}, trait type {} (coming from network)",
-                                implementor_type_id.as_usize(),
-                                (*recipient_id).type_id.as_usize()
-                            );
-                        }
-                    }
-                } else {
-                    panic!(
-                        "No inbox for actor type {} - or no implementors (coming from network)",
-                        (*recipient_id).type_id.as_usize()
-                    )
-                }
+                Err(WireError::TrailingBytes)
             }
-        }
+        })
+        .map_err(|error| InvalidInput::Malformed { message_type, error })?;
+
+    let mut decoded = Vec::with_capacity(::std::mem::size_of::<ShortTypeId>() + packet.len());
+    decoded.write_u16::<LittleEndian>(message_type).unwrap();
+    decoded.extend_from_slice(&packet);
+
+    let recipient_type = unsafe {
+        ::std::ptr::read_unaligned(packet.as_ptr() as *const RawID).type_id
+    };
 
-        false
+    let mut recipient_classes = if ingress.classes.contains(recipient_type) {
+        vec![recipient_type]
+    } else {
+        ingress
+            .implementors
+            .get(recipient_type)
+            .map(|implementors| {
+                implementors
+                    .iter()
+                    .cloned()
+                    .filter(|&implementor| ingress.classes.contains(implementor))
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new)
+    };
+
+    if recipient_classes.is_empty() {
+        return Err(InvalidInput::UnknownRecipientType {
+            message_type,
+            recipient_type: recipient_type.as_u16(),
+        });
     }
+
+    if let Some(policy) = ingress.policy {
+        // messages to an actor trait are allowed either for the whole trait or per implementor
+        if !policy.allows(recipient_type, message_type_id) {
+            recipient_classes.retain(|&class_id| policy.allows(class_id, message_type_id));
+            if recipient_classes.is_empty() {
+                return Ok(Received::Rejected(message_type_id));
+            }
+        }
+    }
+
+    Ok(Received::Message(decoded, recipient_classes))
 }
 
 #[cfg(feature = "browser")]
@@ -559,13 +1127,21 @@ use std::rc::Rc;
 
 #[cfg(feature = "browser")]
 pub struct Connection {
-    n_turns: usize,
-    n_turns_since_own_turn: usize,
     websocket: WebSocket,
     in_queue: Rc<RefCell<VecDeque<Vec<u8>>>>,
-    got_machine_id: Rc<RefCell<bool>>,
-    out_batches: Vec<Vec<u8>>,
-    batch_message_bytes: usize,
+    /// Sent before anything else once the websocket is open
+    own_hello: Option<Vec<u8>>,
+    authentication: Option<Authentication>,
+    received_challenge: Rc<RefCell<Option<Vec<u8>>>>,
+    /// Sent together with the response to the peer's challenge
+    own_handshake: Option<Vec<u8>>,
+    /// The run of the peer we offered to resume our session with when connecting
+    offered_peer_incarnation: u64,
+    /// Did the peer's handshake show that it agrees with us on type IDs?
+    /// Until then, nothing else is sent or dispatched.
+    peer_verified: bool,
+    received_handshake: Rc<RefCell<Option<Vec<u8>>>>,
+    remap: Option<TypeIdRemap>,
 }
 
 #[cfg(feature = "browser")]
@@ -573,90 +1149,105 @@ use stdweb::web::event::SocketMessageEvent;
 
 #[cfg(feature = "browser")]
 impl Connection {
-    pub fn new(websocket: WebSocket, batch_message_bytes: usize) -> Connection {
+    pub fn new(websocket: WebSocket) -> Connection {
         let in_queue = Rc::new(RefCell::new(VecDeque::new()));
         let in_queue_for_listener = in_queue.clone();
-        let got_machine_id = Rc::new(RefCell::new(false));
-        let got_machine_id_for_listener = got_machine_id.clone();
+        let received_challenge = Rc::new(RefCell::new(None));
+        let received_challenge_for_listener = received_challenge.clone();
+        let received_handshake = Rc::new(RefCell::new(None));
+        let received_handshake_for_listener = received_handshake.clone();
+        let n_received = Rc::new(RefCell::new(0));
 
         websocket.set_binary_type(SocketBinaryType::ArrayBuffer);
         websocket.add_event_listener(move |event: SocketMessageEvent| {
-            let mut got_machine_id = got_machine_id_for_listener.borrow_mut();
-            if *got_machine_id {
-                in_queue_for_listener.borrow_mut().push_back({
-                    let typed_array: TypedArray<u8> =
-                        event.data().into_array_buffer().unwrap().into();
-                    typed_array.to_vec()
-                })
-            } else {
-                // ignore first packet
-                *got_machine_id = true;
+            let data = {
+                let typed_array: TypedArray<u8> =
+                    event.data().into_array_buffer().unwrap().into();
+                typed_array.to_vec()
+            };
+            let mut n_received = n_received.borrow_mut();
+            // the first packet is the peer's challenge, the second its handshake
+            match *n_received {
+                0 => *received_challenge_for_listener.borrow_mut() = Some(data),
+                1 => *received_handshake_for_listener.borrow_mut() = Some(data),
+                _ => in_queue_for_listener.borrow_mut().push_back(data),
+            }
+            if *n_received < 2 {
+                *n_received += 1;
             }
         });
 
         Connection {
-            n_turns: 0,
-            n_turns_since_own_turn: 0,
             websocket,
             in_queue,
-            got_machine_id,
-            out_batches: vec![Vec::with_capacity(batch_message_bytes)],
-            batch_message_bytes,
+            own_hello: None,
+            authentication: None,
+            received_challenge,
+            own_handshake: None,
+            offered_peer_incarnation: 0,
+            peer_verified: false,
+            received_handshake,
+            remap: None,
         }
     }
 
-    pub fn enqueue_in_batch(&mut self, message_size: usize) -> &mut Vec<u8> {
-        // let recipient_id =
-        //     (&message[::std::mem::size_of::<ShortTypeId>()] as *const u8) as *const RawID;
-        // println!(
-        //     "Enqueueing message recipient: {:?}, data: {:?}",
-        //     unsafe{(*recipient_id)}, message
-        // );
-
-        let batch =
-            if self.out_batches.last().unwrap().len() < self.batch_message_bytes - message_size {
-                self.out_batches.last_mut().unwrap()
-            } else {
-                self.out_batches
-                    .push(Vec::with_capacity(self.batch_message_bytes));
-                self.out_batches.last_mut().unwrap()
-            };
-
-        batch
-            .write_u32::<LittleEndian>(message_size as u32)
-            .unwrap();
-
-        batch
+    /// Take the handshake the peer sent as its first message, if it just arrived
+    pub(crate) fn take_received_handshake(&mut self) -> Option<Vec<u8>> {
+        self.received_handshake.borrow_mut().take()
     }
 
-    pub fn try_send_pending(&mut self) -> Result<(), ::std::io::Error> {
+    pub(crate) fn try_send_pending(&mut self, session: &mut Session) -> Result<(), ::std::io::Error> {
+        if self.websocket.ready_state() == SocketReadyState::Closed {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::ConnectionAborted,
+                "WebSocket closed",
+            ));
+        }
+
         if self.websocket.ready_state() == SocketReadyState::Open {
-            for batch in self.out_batches.drain(..) {
-                self.websocket.send_bytes(&batch).unwrap();
+            if let Some(own_hello) = self.own_hello.take() {
+                send_bytes(&self.websocket, &own_hello)?;
             }
 
-            self.out_batches
-                .push(Vec::with_capacity(self.batch_message_bytes));
+            let challenge = self.received_challenge.borrow_mut().take();
+            if let Some(challenge) = challenge {
+                let mut response = self
+                    .authentication
+                    .take()
+                    .and_then(|authentication| authentication.respond(&challenge))
+                    .ok_or_else(|| {
+                        ::std::io::Error::new(
+                            ::std::io::ErrorKind::PermissionDenied,
+                            "Peer failed to authenticate",
+                        )
+                    })?;
+                response.extend_from_slice(&self.own_handshake.take().unwrap_or_default());
+                send_bytes(&self.websocket, &response)?;
+            }
+        }
+
+        if self.peer_verified && self.websocket.ready_state() == SocketReadyState::Open {
+            for frame in session.outgoing() {
+                send_bytes(&self.websocket, &frame)?;
+            }
         }
         Ok(())
     }
 
-    pub fn try_receive(
+    pub(crate) fn try_receive(
         &mut self,
-        classes: &mut [Option<Class>],
-        implementors: &mut [Option<Vec<ShortTypeId>>],
-    ) -> Result<(), ::std::io::Error> {
+        session: &mut Session,
+        ingress: &mut Ingress,
+    ) -> Result<(), ConnectionError> {
+        if !self.peer_verified {
+            return Ok(());
+        }
+
         if let Ok(mut in_queue) = self.in_queue.try_borrow_mut() {
             //console!(log, "Before drain!");
-            for batch in in_queue.drain(..) {
+            for mut frame in in_queue.drain(..) {
                 //console!(log, "Before dispatch!");
-                dispatch_batch(
-                    &batch,
-                    classes,
-                    implementors,
-                    &mut self.n_turns,
-                    &mut self.n_turns_since_own_turn,
-                );
+                receive_frame(&mut frame, session, ingress, self.remap.as_ref())?;
                 //console!(log, "After dispatch!")
             }
         } else {
@@ -669,3 +1260,401 @@ impl Connection {
         self.in_queue.borrow().len()
     }
 }
+
+/// Send binary data, reporting failure as an error that closes the connection
+#[cfg(feature = "browser")]
+fn send_bytes(websocket: &WebSocket, data: &[u8]) -> Result<(), ::std::io::Error> {
+    websocket.send_bytes(data).map_err(|e| {
+        ::std::io::Error::new(
+            ::std::io::ErrorKind::ConnectionAborted,
+            format!("Error while sending: {:?}", e),
+        )
+    })
+}
+
+/// Receiving end of `dispatch_batch`, with a class for `Bob` (type 1)
+/// that can receive `Note` (type 2) and `Down` (type 3)
+#[cfg(test)]
+struct TestIngress {
+    classes: DenseTypeIdMap<Class>,
+    implementors: DenseTypeIdMap<Vec<ShortTypeId>>,
+    codecs: DenseTypeIdMap<WireCodec>,
+    policy: Option<InboundPolicy>,
+    rejected: TypeIdCounts,
+    remote_deaths: Vec<(RawID, RawID)>,
+}
+
+#[cfg(test)]
+impl TestIngress {
+    fn new() -> Self {
+        use crate::class::ActorVTable;
+        use crate::testing::{Bob, Note};
+        use crate::tuning::Tuning;
+
+        let mut classes = DenseTypeIdMap::new();
+        classes.insert(
+            Self::bob_type(),
+            Class::new(ActorVTable::new_for_actor_type::<Bob>(), &(Arc::new(chunky::HeapStorage) as Arc<dyn crate::ThreadSafeStorage>), &Tuning::default()),
+        );
+        let mut codecs = DenseTypeIdMap::new();
+        codecs.insert(ShortTypeId::new(2).unwrap(), WireCodec::of::<Note>());
+        codecs.insert(ShortTypeId::new(3).unwrap(), WireCodec::of::<Down>());
+        TestIngress {
+            classes,
+            implementors: DenseTypeIdMap::new(),
+            codecs,
+            policy: None,
+            rejected: TypeIdCounts::new(),
+            remote_deaths: Vec::new(),
+        }
+    }
+
+    fn bob_type() -> ShortTypeId {
+        ShortTypeId::new(1).unwrap()
+    }
+
+    fn dispatch(&mut self, batch: &mut [u8]) -> Result<bool, InvalidInput> {
+        let mut ingress = Ingress {
+            classes: &mut self.classes,
+            implementors: &self.implementors,
+            codecs: &self.codecs,
+            policy: self.policy.as_ref(),
+            rejected: &mut self.rejected,
+            down_type: ShortTypeId::new(3),
+            remote_deaths: &mut self.remote_deaths,
+        };
+        dispatch_batch(batch, &mut ingress, None, &mut 0, &mut 0)
+    }
+}
+
+/// Encode a message like `Networking::enqueue`, prefixed with its size like in a batch
+#[cfg(test)]
+fn test_frame<M: Message + crate::wire::Portable>(message_type: u16, packet: Packet<M>) -> Vec<u8> {
+    use crate::wire::Portable;
+
+    let mut message = Vec::new();
+    message.write_u16::<LittleEndian>(message_type).unwrap();
+    packet.encode(&mut message);
+    let mut frame = Vec::new();
+    frame.write_u32::<LittleEndian>(message.len() as u32).unwrap();
+    frame.extend_from_slice(&message);
+    frame
+}
+
+#[test]
+fn test_down_from_peer_reports_remote_death() {
+    let mut ingress = TestIngress::new();
+    let watcher = RawID::new(TestIngress::bob_type(), 0, MachineID(0), 0);
+    let target = RawID::new(TestIngress::bob_type(), 5, MachineID(1), 0);
+    let mut batch = test_frame(3, Packet {
+        recipient_id: watcher,
+        message: Down { id: target, reason: DownReason::Died },
+    });
+    batch.extend(test_frame(3, Packet {
+        recipient_id: watcher,
+        message: Down { id: target, reason: DownReason::MachineDisconnected },
+    }));
+
+    assert!(ingress.dispatch(&mut batch).is_ok());
+    assert_eq!(ingress.remote_deaths, vec![(watcher, target)]);
+}
+
+#[test]
+fn test_truncated_frames_are_invalid() {
+    use crate::testing::Note;
+
+    let mut ingress = TestIngress::new();
+    let recipient = RawID::new(TestIngress::bob_type(), 0, MachineID(0), 0);
+
+    // cut off length prefix
+    assert_eq!(ingress.dispatch(&mut [4, 0]), Err(InvalidInput::Truncated));
+
+    // cut off message
+    let mut batch = test_frame(2, Packet { recipient_id: recipient, message: Note(1) });
+    batch.pop();
+    assert_eq!(ingress.dispatch(&mut batch), Err(InvalidInput::Truncated));
+
+    // message shorter than its type
+    assert_eq!(ingress.dispatch(&mut [1, 0, 0, 0, 2]), Err(InvalidInput::Truncated));
+}
+
+#[test]
+fn test_oversized_lengths_are_invalid() {
+    use crate::testing::Note;
+
+    let mut ingress = TestIngress::new();
+    let recipient = RawID::new(TestIngress::bob_type(), 0, MachineID(0), 0);
+    let mut batch = test_frame(2, Packet { recipient_id: recipient, message: Note(1) });
+    batch[..4].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(ingress.dispatch(&mut batch), Err(InvalidInput::Truncated));
+}
+
+#[test]
+fn test_unknown_types_are_invalid() {
+    use crate::testing::Note;
+
+    let mut ingress = TestIngress::new();
+    let recipient = RawID::new(TestIngress::bob_type(), 0, MachineID(0), 0);
+    let mut batch = test_frame(9, Packet { recipient_id: recipient, message: Note(1) });
+    assert_eq!(ingress.dispatch(&mut batch), Err(InvalidInput::UnknownMessageType(9)));
+
+    let unknown_recipient = RawID::new(ShortTypeId::new(8).unwrap(), 0, MachineID(0), 0);
+    let mut batch = test_frame(2, Packet { recipient_id: unknown_recipient, message: Note(1) });
+    assert_eq!(
+        ingress.dispatch(&mut batch),
+        Err(InvalidInput::UnknownRecipientType { message_type: 2, recipient_type: 8 })
+    );
+}
+
+#[test]
+fn test_malformed_messages_are_invalid_and_nothing_of_the_batch_is_dispatched() {
+    use crate::testing::Note;
+    use crate::wire::Portable;
+
+    #[derive(Copy, Clone, Portable)]
+    struct Flag(bool);
+
+    let mut ingress = TestIngress::new();
+    ingress.codecs.insert(ShortTypeId::new(4).unwrap(), WireCodec::of::<Flag>());
+    let watcher = RawID::new(TestIngress::bob_type(), 0, MachineID(0), 0);
+    let target = RawID::new(TestIngress::bob_type(), 5, MachineID(1), 0);
+
+    let mut batch = test_frame(3, Packet {
+        recipient_id: watcher,
+        message: Down { id: target, reason: DownReason::Died },
+    });
+    batch.extend(test_frame(4, Packet { recipient_id: watcher, message: Flag(true) }));
+    *batch.last_mut().unwrap() = 2;
+    assert_eq!(
+        ingress.dispatch(&mut batch),
+        Err(InvalidInput::Malformed { message_type: 4, error: WireError::Invalid("bool") })
+    );
+    assert!(ingress.remote_deaths.is_empty());
+
+    let mut message = Vec::new();
+    message.write_u16::<LittleEndian>(2).unwrap();
+    Packet { recipient_id: watcher, message: Note(1) }.encode(&mut message);
+    message.push(0);
+    let mut batch = Vec::new();
+    batch.write_u32::<LittleEndian>(message.len() as u32).unwrap();
+    batch.extend_from_slice(&message);
+    assert_eq!(
+        ingress.dispatch(&mut batch),
+        Err(InvalidInput::Malformed { message_type: 2, error: WireError::TrailingBytes })
+    );
+}
+
+#[test]
+fn test_disallowed_messages_of_restricted_peers_are_dropped_and_counted() {
+    use crate::testing::Note;
+
+    let mut ingress = TestIngress::new();
+    let mut policy = InboundPolicy::default();
+    policy.allow(TestIngress::bob_type(), ShortTypeId::new(3).unwrap());
+    ingress.policy = Some(policy);
+
+    let bob = RawID::new(TestIngress::bob_type(), 0, MachineID(0), 0);
+    let target = RawID::new(TestIngress::bob_type(), 5, MachineID(1), 0);
+    let mut batch = test_frame(2, Packet { recipient_id: bob, message: Note(1) });
+    batch.extend(test_frame(2, Packet { recipient_id: bob, message: Note(2) }));
+    batch.extend(test_frame(3, Packet {
+        recipient_id: bob,
+        message: Down { id: target, reason: DownReason::Died },
+    }));
+
+    assert_eq!(ingress.dispatch(&mut batch), Ok(false));
+    assert_eq!(ingress.rejected.get(ShortTypeId::new(2).unwrap()), Some(&2));
+    assert_eq!(ingress.rejected.get(ShortTypeId::new(3).unwrap()), None);
+    assert_eq!(ingress.classes.get(TestIngress::bob_type()).unwrap().inbox.len(), 1);
+}
+
+#[test]
+fn test_refused_peer_is_accepted_once_types_match() {
+    use crate::handshake::TypeKind;
+
+    let mut networking = Networking::new(0, vec!["127.0.0.1:0".to_owned(), "127.0.0.1:0".to_owned()], 4096, 30, 10);
+    let mut local_actors = TypeRegistry::new();
+    local_actors.register_new::<crate::testing::Alice>().unwrap_or_else(|_| unreachable!());
+    networking.set_local_types(&local_actors, &TypeRegistry::new());
+
+    let mismatching = Handshake::new(MachineID(1), &{
+        let mut other = TypeRegistry::new();
+        other.pin(::std::intrinsics::type_name::<crate::testing::Alice>(), ShortTypeId::new(5).unwrap()).unwrap();
+        other.register_new::<crate::testing::Alice>().unwrap_or_else(|_| unreachable!());
+        other
+    }, &TypeRegistry::new());
+    assert!(networking.check_peer_types(&mismatching).is_err());
+    assert!(networking.check_peer_types(&mismatching).is_err());
+    assert_eq!(networking.type_mismatches().len(), 1);
+    assert_eq!(networking.type_mismatches()[0].kind, TypeKind::Actor);
+    match networking.connection_states()[&MachineID(1)] {
+        ConnectionState::Refused { .. } => {}
+        state => panic!("Unexpected state {:?}", state),
+    }
+
+    let matching = Handshake::new(MachineID(1), &local_actors, &TypeRegistry::new());
+    assert!(networking.check_peer_types(&matching).is_ok());
+    assert_eq!(networking.connection_states()[&MachineID(1)], ConnectionState::AwaitingPeer);
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_silent_peer_does_not_block_accepting() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let address = format!("127.0.0.1:{}", port);
+    let mut networking = Networking::new(0, vec![address.clone(), "127.0.0.1:0".to_owned()], 4096, 30, 10);
+
+    // connects, but never shakes hands
+    let _silent_peer = TcpStream::connect(&address).unwrap();
+    ::std::thread::sleep(Duration::from_millis(50));
+    let started = Instant::now();
+    networking.connect();
+
+    // the peer is only waited for in later turns, without blocking any of them
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert_eq!(networking.incoming_peers.len(), 1);
+    assert_eq!(networking.connection_states()[&MachineID(1)], ConnectionState::AwaitingPeer);
+
+    let started = Instant::now();
+    networking.connect();
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert_eq!(networking.incoming_peers.len(), 1);
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_silent_peer_does_not_block_connecting() {
+    // accepts connections, but never shakes hands
+    let silent_peer = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = silent_peer.local_addr().unwrap().to_string();
+    let mut networking = Networking::new(1, vec![address, "127.0.0.1:0".to_owned()], 4096, 30, 10);
+
+    let started = Instant::now();
+    networking.connect();
+    networking.connect();
+
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert_eq!(networking.connection_states()[&MachineID(0)], ConnectionState::Handshaking);
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_unreachable_peer_does_not_block_connecting() {
+    use crate::pending_peer::AUTHENTICATION_TIMEOUT;
+
+    // not routed anywhere, so opening a TCP connection hangs until it times out (or fails right away)
+    let mut networking = Networking::new(1, vec!["10.255.255.1:9".to_owned(), "127.0.0.1:0".to_owned()], 4096, 30, 10);
+
+    let started = Instant::now();
+    networking.connect();
+    assert!(started.elapsed() < Duration::from_millis(500));
+
+    while networking.connection_states()[&MachineID(0)] == ConnectionState::Handshaking {
+        assert!(started.elapsed() < AUTHENTICATION_TIMEOUT + Duration::from_secs(5), "Timed out");
+        let turn_started = Instant::now();
+        networking.connect();
+        assert!(turn_started.elapsed() < Duration::from_millis(500));
+        ::std::thread::sleep(Duration::from_millis(5));
+    }
+    match networking.connection_states()[&MachineID(0)] {
+        ConnectionState::Reconnecting { n_failed_attempts: 1, .. } => {}
+        state => panic!("Unexpected state {:?}", state),
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+fn loopback_systems(secrets: [&[u8]; 2]) -> Vec<crate::actor_system::ActorSystem> {
+    use crate::actor_system::ActorSystem;
+    use crate::tuning::Tuning;
+
+    let free_address = || TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let network = vec![free_address(), free_address()];
+    secrets
+        .iter()
+        .enumerate()
+        .map(|(machine_id, secret)| {
+            let networking = Networking::new(machine_id as u8, network.clone(), 4096, 30, 10)
+                .with_shared_secret(secret);
+            let mut system = ActorSystem::new(networking, Tuning::default());
+            crate::testing::Alice::register(&mut system);
+            system
+        })
+        .collect()
+}
+
+/// Let all systems talk to each other in turns, on this thread, until `done`
+#[cfg(all(test, feature = "server"))]
+fn exchange_in_turns_until<F: FnMut(&mut [crate::actor_system::ActorSystem]) -> bool>(
+    systems: &mut [crate::actor_system::ActorSystem],
+    mut done: F,
+) {
+    let started = Instant::now();
+    while !done(systems) {
+        assert!(started.elapsed() < Duration::from_secs(10), "Timed out");
+        for system in systems.iter_mut() {
+            system.networking_connect();
+            system.networking_send_and_receive();
+        }
+        ::std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_peers_with_the_shared_secret_connect_and_exchange_messages() {
+    use crate::id::TypedID;
+    use crate::testing::{Alice, Note};
+
+    let mut systems = loopback_systems([b"open sesame", b"open sesame"]);
+    let alice = Alice::spawn(&mut systems[0]);
+    exchange_in_turns_until(&mut systems, |systems| {
+        systems.iter().all(|system| {
+            system
+                .networking_connection_states()
+                .values()
+                .all(|state| *state == ConnectionState::Connected)
+        })
+    });
+
+    systems[1].send(alice.as_raw(), Note(7));
+    exchange_in_turns_until(&mut systems, |systems| {
+        systems[0].process_all_messages();
+        Alice::received(&mut systems[0], alice) == vec![7]
+    });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_peers_with_a_wrong_secret_are_rejected() {
+    let mut systems = loopback_systems([b"open sesame", b"open barley"]);
+    exchange_in_turns_until(&mut systems, |systems| {
+        match systems[1].networking_connection_states()[&MachineID(0)] {
+            ConnectionState::Reconnecting { n_failed_attempts, .. } => n_failed_attempts > 0,
+            ConnectionState::Connected => panic!("Connected with a wrong secret"),
+            _ => false,
+        }
+    });
+    assert_eq!(systems[0].networking_connection_states()[&MachineID(1)], ConnectionState::AwaitingPeer);
+}
+
+#[test]
+fn test_session_is_restarted_if_it_cant_be_resumed() {
+    let mut networking = Networking::new(0, vec!["127.0.0.1:0".to_owned(), "127.0.0.1:0".to_owned()], 4096, 30, 10);
+    let peer_incarnation = 7;
+    networking.sessions[1].restart(peer_incarnation);
+    networking.sessions[1].enqueue_in_batch(1, 1024).unwrap().push(1);
+    networking.sessions[1].outgoing();
+
+    // the peer claims to have received more than was sent
+    let peer_resume = Resume {
+        incarnation: peer_incarnation,
+        peer_incarnation: networking.incarnation,
+        n_received: 3,
+    };
+    networking.start_session(1, &peer_resume, peer_incarnation);
+
+    // the unacknowledged batch is dropped and numbering starts over
+    let frames = networking.sessions[1].outgoing();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0], vec![0; 16]);
+}
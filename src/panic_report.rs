@@ -0,0 +1,37 @@
+use compact::CString;
+use crate::id::RawID;
+use crate::type_registry::ShortTypeId;
+use std::any::Any;
+
+/// Describes a panic that happened inside a message handler,
+/// see `ActorSystem::panic_reports` and `ActorSystem::set_panic_observer`
+#[derive(Compact, Clone, Portable)]
+pub struct PanicReport {
+    /// The actor instance that panicked, or the broadcast/spawn ID the message was sent to
+    pub recipient: RawID,
+    /// The name of the actor class of the recipient
+    pub actor_name: CString,
+    /// The name of the message type that was being handled
+    pub message_name: CString,
+    /// The local networking turn in which the panic happened
+    pub turn: usize,
+    /// The panic message, if it was a string
+    pub payload: CString,
+}
+
+/// A panic caught by a class, before names are looked up for its report
+pub(crate) struct CaughtPanic {
+    pub recipient: RawID,
+    pub message_type: ShortTypeId,
+    pub payload: String,
+}
+
+pub(crate) fn payload_to_string(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "(non-string panic payload)".to_owned()
+    }
+}
@@ -0,0 +1,310 @@
+use crate::auth::{Authentication, Challenge, SharedSecret};
+use crate::id::MachineID;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::handshake::client::ClientHandshake;
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::MidHandshake;
+use tungstenite::util::NonBlockingError;
+use tungstenite::{
+    accept as websocket_accept, client as websocket_client, HandshakeError,
+    Message as WebSocketMessage, WebSocket,
+};
+use url::Url;
+
+/// How long connecting, authenticating and exchanging handshakes with a peer may take
+pub(crate) const AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What became of a pending peer after advancing it
+pub(crate) enum Step<P, T> {
+    /// Still waiting for the peer
+    Waiting(P),
+    /// Done authenticating
+    Done(T),
+}
+
+/// A peer that connected to us, but didn't authenticate yet.
+///
+/// It is advanced as far as possible whenever `Networking::connect` is called,
+/// but never waits for the peer, so a peer that stops talking can't block us.
+/// It is given up on after `AUTHENTICATION_TIMEOUT`.
+pub(crate) struct IncomingPeer {
+    pub address: SocketAddr,
+    deadline: Instant,
+    stage: IncomingStage,
+}
+
+enum IncomingStage {
+    /// Answering the peer's websocket upgrade request
+    WebSocketHandshake(MidHandshake<ServerHandshake<TcpStream, NoCallback>>),
+    /// Waiting for the peer to tell us which machine it claims to be
+    AwaitingHello(WebSocket<TcpStream>),
+    /// Sent our challenge, waiting for the peer to answer it
+    AwaitingResponse(WebSocket<TcpStream>, Box<Challenge>),
+}
+
+/// A peer that connected to us and proved it belongs to our network
+pub(crate) struct AuthenticatedPeer {
+    pub websocket: WebSocket<TcpStream>,
+    pub machine_id: MachineID,
+    /// What the peer sent after its proof (its resume offer and type handshake)
+    pub handshake: Vec<u8>,
+}
+
+impl IncomingPeer {
+    pub fn new(stream: TcpStream, address: SocketAddr, now: Instant) -> Result<IncomingPeer, String> {
+        stream
+            .set_nonblocking(true)
+            .map_err(|e| format!("Error while accepting connection: {}", e))?;
+        let stage = match websocket_accept(stream) {
+            Ok(websocket) => IncomingStage::AwaitingHello(websocket),
+            Err(HandshakeError::Interrupted(handshake)) => IncomingStage::WebSocketHandshake(handshake),
+            Err(HandshakeError::Failure(e)) => return Err(format!("Error while shaking hands: {}", e)),
+        };
+        Ok(IncomingPeer {
+            address,
+            deadline: now + AUTHENTICATION_TIMEOUT,
+            stage,
+        })
+    }
+
+    /// Get as far as possible with what the peer sent so far
+    pub fn advance(
+        self,
+        secret: &SharedSecret,
+        machine_id: MachineID,
+        n_machines: usize,
+        now: Instant,
+    ) -> Result<Step<IncomingPeer, AuthenticatedPeer>, String> {
+        let IncomingPeer { address, deadline, mut stage } = self;
+
+        let waiting_stage = loop {
+            stage = match stage {
+                IncomingStage::WebSocketHandshake(handshake) => match handshake.handshake() {
+                    Ok(websocket) => IncomingStage::AwaitingHello(websocket),
+                    Err(HandshakeError::Interrupted(handshake)) => break IncomingStage::WebSocketHandshake(handshake),
+                    Err(HandshakeError::Failure(e)) => return Err(format!("Error while shaking hands: {}", e)),
+                },
+                IncomingStage::AwaitingHello(mut websocket) => {
+                    let hello = match try_read_binary(&mut websocket)
+                        .map_err(|e| format!("Error while expecting hello: {}", e))?
+                    {
+                        Some(hello) => hello,
+                        None => break IncomingStage::AwaitingHello(websocket),
+                    };
+                    let challenge = Challenge::new(secret, machine_id, &hello, n_machines)
+                        .ok_or_else(|| "Got a malformed hello".to_owned())?;
+                    queue_binary(&mut websocket, challenge.message().to_vec())
+                        .map_err(|e| format!("Error while sending challenge: {}", e))?;
+                    IncomingStage::AwaitingResponse(websocket, Box::new(challenge))
+                }
+                IncomingStage::AwaitingResponse(mut websocket, challenge) => {
+                    let response = match try_read_binary(&mut websocket)
+                        .map_err(|e| format!("Error while expecting response: {}", e))?
+                    {
+                        Some(response) => response,
+                        None => break IncomingStage::AwaitingResponse(websocket, challenge),
+                    };
+                    let handshake = challenge.verify(&response).ok_or_else(|| {
+                        format!("Claimed machine ID {} failed to authenticate", challenge.peer.0)
+                    })?;
+                    return Ok(Step::Done(AuthenticatedPeer {
+                        handshake: handshake.to_vec(),
+                        websocket,
+                        machine_id: challenge.peer,
+                    }));
+                }
+            }
+        };
+
+        if now >= deadline {
+            Err("Timed out while authenticating".to_owned())
+        } else {
+            Ok(Step::Waiting(IncomingPeer { address, deadline, stage: waiting_stage }))
+        }
+    }
+}
+
+/// A peer we are connecting to, that didn't authenticate yet.
+/// Advanced like an `IncomingPeer`.
+pub(crate) struct OutgoingPeer {
+    pub machine_id: usize,
+    offered_peer_incarnation: u64,
+    authentication: Authentication,
+    /// Our resume offer and type handshake, sent together with our proof
+    handshake: Vec<u8>,
+    deadline: Instant,
+    stage: OutgoingStage,
+}
+
+enum OutgoingStage {
+    /// Waiting for a thread to open a TCP connection, which can block for a while
+    Connecting(Url, Receiver<::std::io::Result<TcpStream>>),
+    /// Waiting for the peer to accept our websocket upgrade request
+    WebSocketHandshake(MidHandshake<ClientHandshake<TcpStream>>),
+    /// Sent our hello, waiting for the peer's challenge
+    AwaitingChallenge(WebSocket<TcpStream>),
+}
+
+/// A peer we connected to, which proved it belongs to our network
+pub(crate) struct ConnectedPeer {
+    pub websocket: WebSocket<TcpStream>,
+    pub machine_id: usize,
+    /// The run of the peer we offered to resume our session with
+    pub offered_peer_incarnation: u64,
+}
+
+impl OutgoingPeer {
+    pub fn new(
+        machine_id: usize,
+        address: &str,
+        authentication: Authentication,
+        handshake: Vec<u8>,
+        offered_peer_incarnation: u64,
+        now: Instant,
+    ) -> Result<OutgoingPeer, String> {
+        let url = Url::parse(&format!("ws://{}", address))
+            .map_err(|e| format!("Invalid address {}: {}", address, e))?;
+        let (connected, connecting) = channel();
+        let address = address.to_owned();
+        thread::Builder::new()
+            .name(format!("kay_connect_{}", machine_id))
+            .spawn(move || {
+                // if we gave up on the peer in the meantime, nobody is listening anymore
+                let _ = connected.send(connect_with_timeout(&address));
+            })
+            .map_err(|e| format!("Error while connecting: {}", e))?;
+        Ok(OutgoingPeer {
+            machine_id,
+            offered_peer_incarnation,
+            authentication,
+            handshake,
+            deadline: now + AUTHENTICATION_TIMEOUT,
+            stage: OutgoingStage::Connecting(url, connecting),
+        })
+    }
+
+    /// Get as far as possible with what the peer sent so far. Once the peer proved
+    /// that it belongs to our network, we prove the same and send our handshake.
+    pub fn advance(self, now: Instant) -> Result<Step<OutgoingPeer, ConnectedPeer>, String> {
+        let OutgoingPeer {
+            machine_id,
+            offered_peer_incarnation,
+            authentication,
+            handshake,
+            deadline,
+            mut stage,
+        } = self;
+
+        let waiting_stage = loop {
+            stage = match stage {
+                OutgoingStage::Connecting(url, connecting) => match connecting.try_recv() {
+                    Ok(connected) => {
+                        let stream = connected
+                            .and_then(|stream| {
+                                stream.set_nonblocking(true)?;
+                                Ok(stream)
+                            })
+                            .map_err(|e| format!("Error while connecting: {}", e))?;
+                        match websocket_client(url, stream) {
+                            Ok((websocket, _)) => OutgoingStage::send_hello(websocket, &authentication)?,
+                            Err(HandshakeError::Interrupted(mid_handshake)) => {
+                                OutgoingStage::WebSocketHandshake(mid_handshake)
+                            }
+                            Err(HandshakeError::Failure(e)) => {
+                                return Err(format!("Error while shaking hands: {}", e))
+                            }
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break OutgoingStage::Connecting(url, connecting),
+                    Err(TryRecvError::Disconnected) => return Err("Connecting thread died".to_owned()),
+                },
+                OutgoingStage::WebSocketHandshake(mid_handshake) => match mid_handshake.handshake() {
+                    Ok((websocket, _)) => OutgoingStage::send_hello(websocket, &authentication)?,
+                    Err(HandshakeError::Interrupted(mid_handshake)) => {
+                        break OutgoingStage::WebSocketHandshake(mid_handshake)
+                    }
+                    Err(HandshakeError::Failure(e)) => return Err(format!("Error while shaking hands: {}", e)),
+                },
+                OutgoingStage::AwaitingChallenge(mut websocket) => {
+                    let challenge = match try_read_binary(&mut websocket)
+                        .map_err(|e| format!("Error while expecting challenge: {}", e))?
+                    {
+                        Some(challenge) => challenge,
+                        None => break OutgoingStage::AwaitingChallenge(websocket),
+                    };
+                    let mut response = authentication
+                        .respond(&challenge)
+                        .ok_or_else(|| "Peer failed to authenticate".to_owned())?;
+                    response.extend_from_slice(&handshake);
+                    queue_binary(&mut websocket, response)
+                        .map_err(|e| format!("Error while sending response: {}", e))?;
+                    return Ok(Step::Done(ConnectedPeer {
+                        websocket,
+                        machine_id,
+                        offered_peer_incarnation,
+                    }));
+                }
+            }
+        };
+
+        if now >= deadline {
+            Err("Timed out while authenticating".to_owned())
+        } else {
+            Ok(Step::Waiting(OutgoingPeer {
+                machine_id,
+                offered_peer_incarnation,
+                authentication,
+                handshake,
+                deadline,
+                stage: waiting_stage,
+            }))
+        }
+    }
+}
+
+impl OutgoingStage {
+    fn send_hello(mut websocket: WebSocket<TcpStream>, authentication: &Authentication) -> Result<OutgoingStage, String> {
+        queue_binary(&mut websocket, authentication.hello())
+            .map_err(|e| format!("Error while sending hello: {}", e))?;
+        Ok(OutgoingStage::AwaitingChallenge(websocket))
+    }
+}
+
+/// Get the next binary message from a non-blocking socket, if it arrived already
+fn try_read_binary(websocket: &mut WebSocket<TcpStream>) -> Result<Option<Vec<u8>>, ::tungstenite::Error> {
+    loop {
+        match websocket.read_message() {
+            Ok(WebSocketMessage::Binary(data)) => return Ok(Some(data)),
+            Ok(_) => {}
+            Err(e) => return e.into_non_blocking().map_or(Ok(None), Err),
+        }
+    }
+}
+
+/// Send a binary message on a non-blocking socket. What can't be sent right away
+/// stays queued and is sent whenever the socket is read from or written to next.
+pub(crate) fn queue_binary(websocket: &mut WebSocket<TcpStream>, data: Vec<u8>) -> Result<(), ::tungstenite::Error> {
+    websocket
+        .write_message(WebSocketMessage::binary(data))
+        .or_else(|e| e.into_non_blocking().map_or(Ok(()), Err))
+}
+
+/// Open a TCP connection to the first reachable address `address` resolves to,
+/// giving up on each after `AUTHENTICATION_TIMEOUT`
+fn connect_with_timeout(address: &str) -> ::std::io::Result<TcpStream> {
+    use std::net::ToSocketAddrs;
+
+    let mut last_error = None;
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, AUTHENTICATION_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        ::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "Address resolved to nothing")
+    }))
+}
@@ -0,0 +1,82 @@
+use crate::loop_detector::MessageLoop;
+use std::time::Duration;
+
+/// Limits how long `ActorSystem::process_messages_with_budget` keeps handling messages
+#[derive(Copy, Clone, Debug)]
+pub enum ProcessingBudget {
+    /// Handle at most this many cycles, where each cycle handles all
+    /// messages that are in the inboxes of each class at the start of the cycle
+    MaxCycles(usize),
+    /// Start no new cycle after this much time has passed
+    MaxDuration(Duration),
+}
+
+/// The outcome of `ActorSystem::process_messages_with_budget`
+#[derive(Clone, Debug)]
+pub struct ProcessingReport {
+    /// How many cycles were handled
+    pub n_cycles: usize,
+    /// Were all inboxes empty when processing stopped?
+    pub quiescent: bool,
+    /// The actor classes that still had messages in their inboxes,
+    /// with the number of queued messages
+    pub busy_classes: Vec<(String, usize)>,
+    /// Message loops detected while processing, if loop detection is enabled
+    /// (see `Tuning::message_loop_threshold`)
+    pub message_loops: Vec<MessageLoop>,
+}
+
+/// A system in which Bob has a `Forward` to Alice queued, which takes two cycles to handle
+#[cfg(test)]
+fn system_with_forward() -> crate::actor_system::ActorSystem {
+    use crate::id::TypedID;
+    use crate::testing::{test_system, Alice, Bob, Forward};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+    system.send(bob.as_raw(), Forward(1, alice.as_raw()));
+    system
+}
+
+#[test]
+fn test_processing_stops_after_max_cycles() {
+    let mut system = system_with_forward();
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxCycles(1));
+
+    assert_eq!(report.n_cycles, 1);
+    assert!(!report.quiescent);
+    assert_eq!(report.busy_classes.len(), 1);
+    assert!(report.busy_classes[0].0.ends_with("Alice"));
+    assert_eq!(report.busy_classes[0].1, 1);
+
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxCycles(1));
+    assert_eq!(report.n_cycles, 1);
+    assert!(report.quiescent);
+    assert!(report.busy_classes.is_empty());
+}
+
+#[test]
+fn test_processing_stops_when_quiescent() {
+    let mut system = system_with_forward();
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxCycles(10));
+
+    assert_eq!(report.n_cycles, 2);
+    assert!(report.quiescent);
+}
+
+#[test]
+fn test_processing_stops_after_max_duration() {
+    let mut system = system_with_forward();
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxDuration(Duration::from_secs(0)));
+
+    assert_eq!(report.n_cycles, 0);
+    assert!(!report.quiescent);
+    assert!(report.busy_classes[0].0.ends_with("Bob"));
+
+    let report = system.process_messages_with_budget(ProcessingBudget::MaxDuration(Duration::from_secs(10)));
+    assert_eq!(report.n_cycles, 2);
+    assert!(report.quiescent);
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+/// The state of the connection to a peer, see `ActorSystem::networking_connection_states`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConnectionState {
+    /// Connected and exchanging messages
+    Connected,
+    /// Connected, but the peer's type handshake wasn't checked yet
+    Handshaking,
+    /// Not connected, waiting for the peer to connect to us
+    AwaitingPeer,
+    /// Not connected, trying to connect to the peer again after a delay
+    Reconnecting {
+        /// How many connection attempts failed in a row
+        n_failed_attempts: u32,
+        /// How long until the next attempt
+        next_attempt_in: Duration,
+    },
+    /// Refused because the peer disagrees with us on type IDs (see `TypeMismatch`).
+    /// Connecting is still retried, in case the peer restarts with matching types
+    Refused {
+        /// How long until the next attempt (if this machine connects to the peer)
+        next_attempt_in: Duration,
+    },
+}
+
+/// A point in time, only used to measure delays
+#[cfg(feature = "server")]
+pub(crate) type Timestamp = ::std::time::Instant;
+
+/// A point in time, only used to measure delays
+#[cfg(feature = "browser")]
+pub(crate) type Timestamp = Duration;
+
+/// Exponential backoff of connection attempts to one peer
+#[derive(Copy, Clone, Default)]
+pub(crate) struct Reconnect {
+    pub n_failed_attempts: u32,
+    /// `None` if an attempt can be made right away
+    next_attempt: Option<Timestamp>,
+}
+
+impl Reconnect {
+    pub fn is_due(&self, now: Timestamp) -> bool {
+        self.next_attempt.map_or(true, |next_attempt| now >= next_attempt)
+    }
+
+    pub fn next_attempt_in(&self, now: Timestamp) -> Duration {
+        self.next_attempt
+            .map_or(Duration::default(), |next_attempt| time_between(now, next_attempt))
+    }
+
+    /// Delay the next attempt, twice as long as the last time, returns the delay
+    pub fn failed(&mut self, now: Timestamp, min_delay: Duration, max_delay: Duration) -> Duration {
+        let delay = min_delay
+            .checked_mul(1 << self.n_failed_attempts.min(16))
+            .map_or(max_delay, |delay| delay.min(max_delay));
+        self.n_failed_attempts += 1;
+        self.next_attempt = Some(now + delay);
+        delay
+    }
+
+    pub fn succeeded(&mut self) {
+        *self = Reconnect::default();
+    }
+}
+
+/// The current time, from a clock that never goes backwards
+#[cfg(feature = "server")]
+pub(crate) fn now() -> Timestamp {
+    ::std::time::Instant::now()
+}
+
+#[cfg(feature = "server")]
+fn time_between(earlier: Timestamp, later: Timestamp) -> Duration {
+    later.saturating_duration_since(earlier)
+}
+
+/// The current time, from the page's monotonic clock,
+/// which (unlike the wall clock) doesn't jump when the system time is changed
+#[cfg(feature = "browser")]
+pub(crate) fn now() -> Timestamp {
+    use stdweb::unstable::TryInto;
+
+    let milliseconds: f64 = js!(return performance.now();).try_into().unwrap_or(0.0);
+    Duration::from_micros((milliseconds * 1000.0) as u64)
+}
+
+#[cfg(feature = "browser")]
+fn time_between(earlier: Timestamp, later: Timestamp) -> Duration {
+    later.checked_sub(earlier).unwrap_or_default()
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_reconnect_backoff() {
+    let (min_delay, max_delay) = (Duration::from_millis(100), Duration::from_millis(300));
+    let start = now();
+    let mut reconnect = Reconnect::default();
+    assert!(reconnect.is_due(start));
+
+    assert_eq!(reconnect.failed(start, min_delay, max_delay), min_delay);
+    assert!(!reconnect.is_due(start));
+    assert_eq!(reconnect.next_attempt_in(start), min_delay);
+    assert!(reconnect.is_due(start + min_delay));
+
+    assert_eq!(reconnect.failed(start, min_delay, max_delay), min_delay * 2);
+    assert_eq!(reconnect.failed(start, min_delay, max_delay), max_delay);
+    assert_eq!(reconnect.n_failed_attempts, 3);
+    assert_eq!(reconnect.next_attempt_in(start + max_delay * 2), Duration::default());
+
+    reconnect.succeeded();
+    assert!(reconnect.is_due(start));
+    assert_eq!(reconnect.n_failed_attempts, 0);
+}
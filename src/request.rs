@@ -0,0 +1,352 @@
+use compact::COption;
+use crate::actor_system::World;
+use crate::id::{MachineID, RawID};
+use crate::messaging::{Fate, Message};
+use std::collections::HashMap;
+
+/// Identifies a request across all machines of a networking topology
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Portable)]
+pub struct RequestID {
+    /// The machine the request was made on
+    pub machine: MachineID,
+    /// The type ID of the actor class whose handler made the request
+    /// (0 for requests made outside of message handlers)
+    pub origin: u16,
+    /// The sequence number of the request among those of its origin on that machine.
+    /// Counting per class keeps request IDs the same, no matter which threads
+    /// the classes are handled on (see `Tuning::n_worker_threads`)
+    pub sequence: u32,
+}
+
+/// Passed to a request continuation if no response arrived in time
+#[derive(Copy, Clone, Debug, Portable)]
+pub struct RequestTimeout(pub RequestID);
+
+#[derive(Compact, Clone, Portable)]
+pub(crate) struct Request<Q: Message> {
+    pub request_id: RequestID,
+    pub requester: RawID,
+    pub query: Q,
+}
+
+#[derive(Compact, Clone, Portable)]
+pub(crate) struct Response<R: Message> {
+    pub request_id: RequestID,
+    /// `None` signals a timeout
+    pub value: COption<R>,
+}
+
+/// A query that is broadcast to all instances of a class,
+/// answered by a mapper added with `ActorSystem::add_mapper`
+#[derive(Compact, Clone, Portable)]
+pub(crate) struct Gather<Q: Message> {
+    pub request_id: RequestID,
+    pub requester: RawID,
+    pub query: Q,
+}
+
+/// The reduced value of all local instances of a class on one machine
+#[derive(Compact, Clone, Portable)]
+pub(crate) struct Gathered<V: Message> {
+    pub request_id: RequestID,
+    /// `None` if there were no instances
+    pub value: COption<V>,
+    pub timed_out: bool,
+    /// The machine the instances live on
+    pub machine: MachineID,
+    /// How many classes on that machine send a `Gathered` for the query
+    pub n_parts: u32,
+}
+
+/// Keeps track of which machines sent all their parts of a map-reduce result
+pub(crate) struct GatheredParts {
+    n_machines_left: usize,
+    parts_left: HashMap<MachineID, u32>,
+}
+
+impl GatheredParts {
+    pub fn new(n_machines: usize) -> GatheredParts {
+        GatheredParts {
+            n_machines_left: n_machines,
+            parts_left: HashMap::new(),
+        }
+    }
+
+    /// Count one part sent by `machine`, which sends `n_parts` in total.
+    /// Returns whether all expected machines sent all their parts.
+    pub fn add(&mut self, machine: MachineID, n_parts: u32) -> bool {
+        let parts_left = self.parts_left.entry(machine).or_insert(n_parts);
+        *parts_left = parts_left.saturating_sub(1);
+        if *parts_left == 0 {
+            self.parts_left.remove(&machine);
+            self.n_machines_left = self.n_machines_left.saturating_sub(1);
+        }
+        self.n_machines_left == 0
+    }
+}
+
+/// The value reduced so far from the results of a map-reduce query.
+/// Messages already travel between threads as compact bytes, so a reduced value
+/// can be used on whichever thread handles the requester next, too.
+pub(crate) struct Reduced<V>(pub Option<V>);
+
+unsafe impl<V: Message> Send for Reduced<V> {}
+
+/// Called with a pointer to each arriving response,
+/// returns whether the request got all responses it was waiting for
+pub(crate) type Accumulator = Box<dyn FnMut(*const ()) -> bool + Send>;
+/// Called with a pointer to the last response and a pointer to the requesting actor instance
+pub(crate) type Continuation = Box<dyn FnOnce(*const (), *mut (), &mut World) -> Fate + Send>;
+/// Called once a request timed out, to let the requester know
+pub(crate) type TimeoutAction = Box<dyn FnOnce(&mut World) + Send>;
+
+struct PendingRequest {
+    deadline_turn: usize,
+    accumulate: Accumulator,
+    continuation: Continuation,
+    on_timeout: Option<TimeoutAction>,
+}
+
+/// The requests made on this machine that are still waiting for a response.
+/// Continuations are called on whichever thread handles the class of the requester.
+pub(crate) struct PendingRequests {
+    machine: MachineID,
+    next_sequences: HashMap<u16, u32>,
+    pending: HashMap<RequestID, PendingRequest>,
+}
+
+impl PendingRequests {
+    pub fn new(machine: MachineID) -> PendingRequests {
+        PendingRequests {
+            machine,
+            next_sequences: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn next_id(&mut self, origin: u16) -> RequestID {
+        let next_sequence = self.next_sequences.entry(origin).or_insert(0);
+        let id = RequestID {
+            machine: self.machine,
+            origin,
+            sequence: *next_sequence,
+        };
+        *next_sequence = next_sequence.wrapping_add(1);
+        id
+    }
+
+    pub fn add(
+        &mut self,
+        id: RequestID,
+        deadline_turn: usize,
+        accumulate: Accumulator,
+        continuation: Continuation,
+        on_timeout: TimeoutAction,
+    ) {
+        self.pending.insert(
+            id,
+            PendingRequest {
+                deadline_turn,
+                accumulate,
+                continuation,
+                on_timeout: Some(on_timeout),
+            },
+        );
+    }
+
+    /// Let a pending request accumulate a response. Returns the continuation of the request
+    /// if this was the last response it was waiting for, or if the request timed out.
+    pub fn respond(&mut self, id: RequestID, response_ptr: *const (), timed_out: bool) -> Option<Continuation> {
+        let done = match self.pending.get_mut(&id) {
+            Some(ref mut pending) if !timed_out => (pending.accumulate)(response_ptr),
+            Some(_) => true,
+            // the request already timed out or was completed
+            None => false,
+        };
+
+        if done {
+            self.pending.remove(&id).map(|pending| pending.continuation)
+        } else {
+            None
+        }
+    }
+
+    /// Get the timeout actions of all requests whose deadline passed.
+    /// Requests that already timed out during an earlier call are forgotten,
+    /// since their timeout response was delivered by now.
+    pub fn expire(&mut self, n_turns: usize) -> Vec<TimeoutAction> {
+        self.pending.retain(|_, pending| pending.on_timeout.is_some());
+
+        self.pending
+            .values_mut()
+            .filter(|pending| pending.deadline_turn <= n_turns)
+            .filter_map(|pending| pending.on_timeout.take())
+            .collect()
+    }
+}
+
+#[test]
+fn test_responses_after_the_last_expected_one_are_ignored() {
+    let mut requests = PendingRequests::new(MachineID(0));
+    let id = requests.next_id(0);
+    requests.add(
+        id,
+        10,
+        Box::new(|_| true),
+        Box::new(|_, _, _| Fate::Live),
+        Box::new(|_| {}),
+    );
+
+    assert!(requests.respond(id, ::std::ptr::null(), false).is_some());
+    assert!(requests.respond(id, ::std::ptr::null(), false).is_none());
+}
+
+#[test]
+fn test_gathered_parts_are_counted_per_machine() {
+    // machines can have a different number of classes implementing an actor trait
+    let mut parts = GatheredParts::new(2);
+    assert!(!parts.add(MachineID(0), 2));
+    assert!(!parts.add(MachineID(1), 1));
+    assert!(parts.add(MachineID(0), 2));
+}
+
+#[test]
+fn test_request_gets_response_or_times_out() {
+    use crate::id::TypedID;
+    use crate::testing::{test_system, Alice, Bob, Note};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.add_requester::<Alice, Note, Note>(false);
+    system.add_responder::<Bob, Note, Note, _>(|query, _bob, _world| Note(query.0 * 2), false);
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+
+    let on_response = |response: Result<&Note, RequestTimeout>, alice: &mut Alice, _: &mut World| {
+        alice.received.push(response.map(|note| note.0).unwrap_or(0));
+        Fate::Live
+    };
+    let mut world = system.world();
+    world.request::<Alice, Note, Note, _>(alice, bob.as_raw(), Note(21), 1, on_response).unwrap();
+    // nobody answers requests sent to a dead instance
+    system.send(bob.as_raw(), crate::testing::Die);
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![42]);
+
+    world.request::<Alice, Note, Note, _>(alice, bob.as_raw(), Note(1), 1, on_response).unwrap();
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![42]);
+
+    system.networking_finish_turn();
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![42, 0]);
+}
+
+#[test]
+fn test_map_reduce_without_anybody_to_ask_completes_right_away() {
+    use crate::testing::{test_system, Alice, Bob, Note};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    system.add_map_reduce_requester::<Alice, Note, Note>(false);
+    let alice = Alice::spawn(&mut system);
+
+    let mut world = system.world();
+    let nobody = world.local_broadcast::<Bob>();
+    world
+        .map_reduce::<Alice, Note, Note, _, _>(
+            alice,
+            nobody,
+            Note(0),
+            |a, b| Note(a.0 + b.0),
+            10,
+            |result, alice: &mut Alice, _| {
+                alice.received.push(match result {
+                    Ok(None) => 1,
+                    Ok(Some(_)) => 2,
+                    Err(_) => 3,
+                });
+                Fate::Live
+            },
+        )
+        .unwrap();
+    system.process_all_messages();
+
+    assert_eq!(Alice::received(&mut system, alice), vec![1]);
+}
+
+#[test]
+fn test_map_reduce_reduces_all_instances() {
+    use crate::testing::{test_system, Alice, Bob, Note};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.add_map_reduce_requester::<Alice, Note, Note>(false);
+    system.add_mapper::<Bob, Note, Note, _, _>(
+        |query, _bob, _world| Note(query.0),
+        |a, b| Note(a.0 + b.0),
+        false,
+    );
+    let alice = Alice::spawn(&mut system);
+    for _ in 0..3 {
+        Bob::spawn(&mut system);
+    }
+
+    let mut world = system.world();
+    let bobs = world.local_broadcast::<Bob>();
+    world
+        .map_reduce::<Alice, Note, Note, _, _>(
+            alice,
+            bobs,
+            Note(5),
+            |a, b| Note(a.0 + b.0),
+            10,
+            |result, alice: &mut Alice, _| {
+                alice.received.push(result.unwrap().unwrap().0);
+                Fate::Live
+            },
+        )
+        .unwrap();
+    system.process_all_messages();
+
+    assert_eq!(Alice::received(&mut system, alice), vec![15]);
+}
+
+#[test]
+fn test_map_reduce_over_actor_trait_waits_for_all_implementors() {
+    use crate::network_observer::NetworkObserverID;
+    use crate::testing::{test_system, Alice, Bob, Note};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.register_implementor::<Alice, NetworkObserverID>();
+    system.register_implementor::<Bob, NetworkObserverID>();
+    system.add_map_reduce_requester::<Alice, Note, Note>(false);
+    system.add_mapper::<Alice, Note, Note, _, _>(|query, _, _| Note(query.0), |a, b| Note(a.0 + b.0), false);
+    system.add_mapper::<Bob, Note, Note, _, _>(|query, _, _| Note(query.0), |a, b| Note(a.0 + b.0), false);
+    let alice = Alice::spawn(&mut system);
+    Bob::spawn(&mut system);
+    Bob::spawn(&mut system);
+
+    let mut world = system.world();
+    let observers = world.local_broadcast::<NetworkObserverID>();
+    world
+        .map_reduce::<Alice, Note, Note, _, _>(
+            alice,
+            observers,
+            Note(1),
+            |a, b| Note(a.0 + b.0),
+            10,
+            |result, alice: &mut Alice, _| {
+                alice.received.push(result.unwrap().unwrap().0);
+                Fate::Live
+            },
+        )
+        .unwrap();
+    system.process_all_messages();
+
+    assert_eq!(Alice::received(&mut system, alice), vec![3]);
+}
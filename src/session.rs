@@ -0,0 +1,423 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use crate::id::MachineID;
+use crate::wire::InvalidInput;
+use std::collections::VecDeque;
+
+/// What a machine tells a peer when connecting, so both can tell whether to resume their session
+pub(crate) struct Resume {
+    /// Identifies the current run of the machine
+    pub incarnation: u64,
+    /// The run of the peer that the machine has an intact session with, 0 if none
+    pub peer_incarnation: u64,
+    /// How many batches the machine received from the peer in that session
+    pub n_received: u64,
+}
+
+const RESUME_SIZE: usize = 3 * ::std::mem::size_of::<u64>();
+
+impl Resume {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(RESUME_SIZE);
+        data.write_u64::<LittleEndian>(self.incarnation).unwrap();
+        data.write_u64::<LittleEndian>(self.peer_incarnation).unwrap();
+        data.write_u64::<LittleEndian>(self.n_received).unwrap();
+        data
+    }
+
+    /// Decode what a peer sent, returning the rest of the data, `None` if it is too short
+    pub fn decode(data: &[u8]) -> Option<(Resume, &[u8])> {
+        if data.len() < RESUME_SIZE {
+            return None;
+        }
+        let resume = Resume {
+            incarnation: LittleEndian::read_u64(&data[0..8]),
+            peer_incarnation: LittleEndian::read_u64(&data[8..16]),
+            n_received: LittleEndian::read_u64(&data[16..24]),
+        };
+        Some((resume, &data[RESUME_SIZE..]))
+    }
+
+    /// Do both machines have an intact session with each other's current run?
+    pub fn agrees_with(&self, own_incarnation: u64, offered_peer_incarnation: u64) -> bool {
+        offered_peer_incarnation != 0
+            && offered_peer_incarnation == self.incarnation
+            && self.peer_incarnation == own_incarnation
+    }
+}
+
+/// Sequence number and acknowledgement in front of each batch
+const FRAME_HEADER_SIZE: usize = 2 * ::std::mem::size_of::<u64>();
+
+/// Everything that is sent to and received from a peer, across reconnects.
+///
+/// Batches of messages are numbered in the order they are sent, and each
+/// batch sent carries the number of batches received so far, acknowledging them.
+/// Batches are kept until they are acknowledged. When reconnecting to the same
+/// run of a peer, unacknowledged batches are sent again and batches that were
+/// already received are skipped, so each batch is delivered exactly once and in order.
+pub(crate) struct Session {
+    peer: MachineID,
+    /// The run of the peer this session is with, `None` before the first connection
+    peer_incarnation: Option<u64>,
+    /// Unacknowledged batches had to be dropped, so the session can't be resumed
+    broken: bool,
+    /// The peer sent invalid input, so the session is started over instead of resumed,
+    /// which drops the batches the peer would otherwise send again
+    invalid_input_received: bool,
+    /// Batches being filled, not sent yet
+    out_batches: Vec<Vec<u8>>,
+    batch_message_bytes: usize,
+    /// Sent batches that weren't acknowledged yet, the first one has the sequence number `n_acknowledged`
+    unacknowledged: VecDeque<Vec<u8>>,
+    n_acknowledged: u64,
+    /// How many of the unacknowledged batches were sent over the current connection
+    n_sent: usize,
+    /// Size of all batches that weren't acknowledged yet, sent or not
+    buffered_bytes: usize,
+    /// How many batches were received and dispatched
+    n_received: u64,
+    /// The progress of networking turns of the peer
+    pub n_turns: usize,
+    pub n_turns_since_own_turn: usize,
+    /// Whether the peer was last reported as lagging behind
+    pub lagging: bool,
+}
+
+impl Session {
+    pub fn new(peer: MachineID, batch_message_bytes: usize) -> Session {
+        Session {
+            peer,
+            peer_incarnation: None,
+            broken: false,
+            invalid_input_received: false,
+            out_batches: Vec::new(),
+            batch_message_bytes,
+            unacknowledged: VecDeque::new(),
+            n_acknowledged: 0,
+            n_sent: 0,
+            buffered_bytes: 0,
+            n_received: 0,
+            n_turns: 0,
+            n_turns_since_own_turn: 0,
+            lagging: false,
+        }
+    }
+
+    /// Was there ever a connection to the peer?
+    pub fn is_established(&self) -> bool {
+        self.peer_incarnation.is_some()
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// What to tell the peer when connecting
+    pub fn resume_offer(&self, own_incarnation: u64) -> Resume {
+        Resume {
+            incarnation: own_incarnation,
+            peer_incarnation: if self.broken || self.invalid_input_received {
+                0
+            } else {
+                self.peer_incarnation.unwrap_or(0)
+            },
+            n_received: self.n_received,
+        }
+    }
+
+    /// Continue the session over a new connection, `false` if that's not possible
+    pub fn resume(&mut self, peer_n_received: u64) -> bool {
+        if self.broken
+            || self.invalid_input_received
+            || peer_n_received < self.n_acknowledged
+            || !self.acknowledge(peer_n_received)
+        {
+            return false;
+        }
+        // anything not acknowledged yet might not have made it
+        self.n_sent = 0;
+        true
+    }
+
+    /// Start over with a (possibly new) run of the peer.
+    /// Batches that were sent but not acknowledged are lost, batches not sent yet are kept.
+    pub fn restart(&mut self, peer_incarnation: u64) {
+        if !self.unacknowledged.is_empty() {
+            println!(
+                "Starting a new session with Machine ID {}, {} sent batches might have been lost",
+                self.peer.0,
+                self.unacknowledged.len()
+            );
+        }
+        for batch in self.unacknowledged.drain(..) {
+            self.buffered_bytes -= batch.len();
+        }
+        self.peer_incarnation = Some(peer_incarnation);
+        self.broken = false;
+        self.invalid_input_received = false;
+        self.n_acknowledged = 0;
+        self.n_sent = 0;
+        self.n_received = 0;
+    }
+
+    /// Make room for a message in the current batch, `None` if the session is broken
+    /// or the message is too large for a batch (in which case it is dropped)
+    pub fn enqueue_in_batch(&mut self, message_size: usize, max_buffered_bytes: usize) -> Option<&mut Vec<u8>> {
+        if message_size > self.batch_message_bytes {
+            println!(
+                "Dropping message of {} bytes to Machine ID {}, larger than the batch size of {} bytes",
+                message_size, self.peer.0, self.batch_message_bytes
+            );
+            return None;
+        }
+
+        if self.broken {
+            return None;
+        }
+
+        self.buffered_bytes += ::std::mem::size_of::<u32>() + message_size;
+        if self.buffered_bytes > max_buffered_bytes {
+            println!(
+                "Resend buffer for Machine ID {} overflowed, dropping messages to it until a new session starts",
+                self.peer.0
+            );
+            self.broken = true;
+            self.out_batches.clear();
+            self.unacknowledged.clear();
+            self.buffered_bytes = 0;
+            return None;
+        }
+
+        let batch_message_bytes = self.batch_message_bytes;
+        if self
+            .out_batches
+            .last()
+            .map_or(true, |batch| batch.len() >= batch_message_bytes - message_size)
+        {
+            self.out_batches.push(Vec::with_capacity(batch_message_bytes));
+        }
+
+        let batch = self.out_batches.last_mut().unwrap();
+        batch
+            .write_u32::<LittleEndian>(message_size as u32)
+            .unwrap();
+
+        Some(batch)
+    }
+
+    /// Number the batches filled so far and get everything to send over the current
+    /// connection: batches that weren't sent over it yet, or else just an acknowledgement
+    pub fn outgoing(&mut self) -> Vec<Vec<u8>> {
+        for batch in self.out_batches.drain(..) {
+            if !batch.is_empty() {
+                self.unacknowledged.push_back(batch);
+            }
+        }
+
+        let mut frames: Vec<Vec<u8>> = self
+            .unacknowledged
+            .iter()
+            .enumerate()
+            .skip(self.n_sent)
+            .map(|(i, batch)| self.frame(self.n_acknowledged + i as u64, batch))
+            .collect();
+        if frames.is_empty() {
+            frames.push(self.frame(self.n_acknowledged + self.unacknowledged.len() as u64, &[]));
+        }
+
+        self.n_sent = self.unacknowledged.len();
+        frames
+    }
+
+    fn frame(&self, sequence_number: u64, batch: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + batch.len());
+        frame.write_u64::<LittleEndian>(sequence_number).unwrap();
+        frame.write_u64::<LittleEndian>(self.n_received).unwrap();
+        frame.extend_from_slice(batch);
+        frame
+    }
+
+    /// Process the acknowledgement of a frame received from the peer and get the batch
+    /// it contains, `None` if it contains no batch or one that was already received
+    pub fn receive<'a>(&mut self, frame: &'a mut [u8]) -> Result<Option<&'a mut [u8]>, InvalidInput> {
+        if frame.len() < FRAME_HEADER_SIZE {
+            return Err(InvalidInput::Truncated);
+        }
+        let sequence_number = LittleEndian::read_u64(&frame[0..8]);
+        let n_acknowledged = LittleEndian::read_u64(&frame[8..16]);
+
+        if !self.acknowledge(n_acknowledged) {
+            return Err(InvalidInput::InvalidAcknowledgement(n_acknowledged));
+        }
+
+        let batch = &mut frame[FRAME_HEADER_SIZE..];
+        if batch.is_empty() || sequence_number < self.n_received {
+            Ok(None)
+        } else if sequence_number > self.n_received {
+            Err(InvalidInput::OutOfSequence {
+                expected: self.n_received,
+                received: sequence_number,
+            })
+        } else {
+            Ok(Some(batch))
+        }
+    }
+
+    /// Count a batch returned by `receive` as received, once it is dispatched
+    pub fn received(&mut self) {
+        self.n_received += 1;
+    }
+
+    /// Don't resume the session after the peer sent invalid input,
+    /// since it would just send the same input again
+    pub fn received_invalid_input(&mut self) {
+        self.invalid_input_received = true;
+    }
+
+    /// Forget batches that the peer received, `false` if it claims to have received unsent ones
+    fn acknowledge(&mut self, n_acknowledged: u64) -> bool {
+        if n_acknowledged > self.n_acknowledged + self.n_sent as u64 {
+            return false;
+        }
+        while self.n_acknowledged < n_acknowledged {
+            let batch = self.unacknowledged.pop_front().unwrap();
+            self.buffered_bytes -= batch.len();
+            self.n_acknowledged += 1;
+            self.n_sent -= 1;
+        }
+        true
+    }
+}
+
+/// Put a message consisting of a single byte into the current batch
+#[cfg(test)]
+fn enqueue_test_message(session: &mut Session, message: u8, max_buffered_bytes: usize) -> bool {
+    match session.enqueue_in_batch(1, max_buffered_bytes) {
+        Some(batch) => {
+            batch.push(message);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The single byte messages of a received batch
+#[cfg(test)]
+fn test_messages(batch: Option<&mut [u8]>) -> Vec<u8> {
+    batch
+        .map(|batch| batch.chunks(5).map(|message| message[4]).collect())
+        .unwrap_or_else(Vec::new)
+}
+
+#[cfg(test)]
+fn test_sessions() -> (Session, Session) {
+    let mut sender = Session::new(MachineID(1), 64);
+    let mut receiver = Session::new(MachineID(0), 64);
+    sender.restart(20);
+    receiver.restart(10);
+    (sender, receiver)
+}
+
+#[test]
+fn test_resume_resends_unacknowledged_batches_and_skips_received_ones() {
+    let (mut sender, mut receiver) = test_sessions();
+    enqueue_test_message(&mut sender, 1, 1024);
+    let mut first = sender.outgoing();
+    enqueue_test_message(&mut sender, 2, 1024);
+    let mut second = sender.outgoing();
+    assert_eq!((first.len(), second.len()), (1, 1));
+
+    // the second batch gets lost when the connection drops
+    assert_eq!(test_messages(receiver.receive(&mut first[0]).unwrap()), vec![1]);
+    receiver.received();
+    drop(second.pop());
+
+    assert!(sender.resume(receiver.resume_offer(10).n_received));
+    let mut resent = sender.outgoing();
+    assert_eq!(resent.len(), 1);
+
+    // receiving the first batch again changes nothing
+    let mut first_again = first[0].clone();
+    assert_eq!(test_messages(receiver.receive(&mut first_again).unwrap()), vec![]);
+    assert_eq!(test_messages(receiver.receive(&mut resent[0]).unwrap()), vec![2]);
+    receiver.received();
+
+    // the acknowledgement of the receiver frees both batches
+    let mut acknowledgement = receiver.outgoing();
+    assert!(sender.receive(&mut acknowledgement[0]).unwrap().is_none());
+    assert!(sender.unacknowledged.is_empty());
+    assert_eq!(sender.buffered_bytes, 0);
+}
+
+#[test]
+fn test_out_of_sequence_frames_are_invalid() {
+    let (mut sender, mut receiver) = test_sessions();
+    enqueue_test_message(&mut sender, 1, 1024);
+    sender.outgoing();
+    enqueue_test_message(&mut sender, 2, 1024);
+    let mut second = sender.outgoing();
+
+    assert_eq!(
+        receiver.receive(&mut second[0]).err(),
+        Some(InvalidInput::OutOfSequence { expected: 0, received: 1 })
+    );
+    assert_eq!(receiver.receive(&mut [0; 4]).err(), Some(InvalidInput::Truncated));
+}
+
+#[test]
+fn test_acknowledging_unsent_batches_is_invalid() {
+    let (mut sender, mut receiver) = test_sessions();
+    enqueue_test_message(&mut sender, 1, 1024);
+    sender.outgoing();
+
+    receiver.received();
+    receiver.received();
+    let mut acknowledgement = receiver.outgoing();
+    assert_eq!(
+        sender.receive(&mut acknowledgement[0]).err(),
+        Some(InvalidInput::InvalidAcknowledgement(2))
+    );
+    assert!(!sender.resume(2));
+}
+
+#[test]
+fn test_overflow_breaks_session_until_restart() {
+    let (mut sender, _) = test_sessions();
+    assert!(enqueue_test_message(&mut sender, 1, 8));
+    assert!(!enqueue_test_message(&mut sender, 2, 8));
+
+    assert!(sender.is_broken());
+    assert_eq!(sender.resume_offer(1).peer_incarnation, 0);
+    assert!(!sender.resume(0));
+    assert!(!enqueue_test_message(&mut sender, 3, 8));
+
+    sender.restart(30);
+    assert!(!sender.is_broken());
+    assert!(enqueue_test_message(&mut sender, 4, 8));
+    assert_eq!(sender.outgoing().len(), 1);
+}
+
+#[test]
+fn test_too_large_message_is_dropped() {
+    let (mut sender, mut receiver) = test_sessions();
+    assert!(sender.enqueue_in_batch(65, 1024).is_none());
+    assert!(enqueue_test_message(&mut sender, 1, 1024));
+
+    assert!(!sender.is_broken());
+    let mut batches = sender.outgoing();
+    assert_eq!(batches.len(), 1);
+    assert_eq!(test_messages(receiver.receive(&mut batches[0]).unwrap()), vec![1]);
+}
+
+#[test]
+fn test_session_is_not_resumed_after_invalid_input() {
+    let (mut sender, _) = test_sessions();
+    sender.received_invalid_input();
+
+    assert_eq!(sender.resume_offer(1).peer_incarnation, 0);
+    assert!(!sender.resume(0));
+
+    sender.restart(20);
+    assert_eq!(sender.resume_offer(1).peer_incarnation, 20);
+    assert!(sender.resume(0));
+}
@@ -0,0 +1,157 @@
+use crate::actor_system::World;
+use crate::class::{ActorVTable, InstanceStore};
+use crate::id::RawID;
+use std::collections::VecDeque;
+
+/// Sent to the supervisor of an actor class (see `ActorSystem::supervise`)
+/// when instances of the class panicked more often than allowed
+/// and the failed instance was not restarted
+#[derive(Compact, Clone, Portable)]
+pub struct RestartIntensityExceeded {
+    /// The failed instance that was not restarted
+    pub failed: RawID,
+    /// The maximum number of restarts allowed...
+    pub max_restarts: usize,
+    /// ...within this number of turns
+    pub within_turns: usize,
+}
+
+pub(crate) type RestartFn = Box<dyn Fn(RawID, &mut World, &mut InstanceStore, &ActorVTable) + Send>;
+
+/// How panicked instances of a class are restarted
+pub(crate) struct Supervision {
+    pub restart: RestartFn,
+    pub max_restarts: usize,
+    pub within_turns: usize,
+    pub supervisor: Option<RawID>,
+    recent_restart_turns: VecDeque<usize>,
+}
+
+impl Supervision {
+    pub fn new(restart: RestartFn, max_restarts: usize, within_turns: usize, supervisor: Option<RawID>) -> Self {
+        Supervision {
+            restart,
+            max_restarts,
+            within_turns,
+            supervisor,
+            recent_restart_turns: VecDeque::new(),
+        }
+    }
+
+    /// Check whether another restart in `turn` stays within the restart intensity,
+    /// and if so, count it
+    pub fn allow_restart(&mut self, turn: usize) -> bool {
+        while let Some(&oldest) = self.recent_restart_turns.front() {
+            if oldest + self.within_turns <= turn {
+                self.recent_restart_turns.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.recent_restart_turns.len() < self.max_restarts {
+            self.recent_restart_turns.push_back(turn);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn test_panicked_instance_is_restarted_under_next_version() {
+    use crate::id::TypedID;
+    use crate::testing::{test_system, Alice, Explode, Note};
+    use compact::CVec;
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    system.supervise::<Alice, _>(|id, _world| Alice { id, received: CVec::new() }, 1, 10, None);
+    let alice = Alice::spawn(&mut system);
+    system.send(alice.as_raw(), Note(1));
+    system.send(alice.as_raw(), Explode);
+    system.process_all_messages();
+
+    let restarted = crate::testing::AliceID::from_raw(RawID {
+        version: alice.as_raw().version + 1,
+        ..alice.as_raw()
+    });
+    assert!(!system.panic_happened);
+    assert_eq!(system.get_instance_counts()["Alice"], 1);
+    assert_eq!(Alice::received(&mut system, restarted), vec![]);
+}
+
+#[test]
+fn test_exceeded_restart_intensity_escalates_to_supervisor() {
+    use crate::id::TypedID;
+    use crate::testing::{test_system, Alice, Bob, BobID, Explode};
+    use compact::CVec;
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+    system.supervise::<Alice, _>(|id, _world| Alice { id, received: CVec::new() }, 0, 10, Some(bob.as_raw()));
+    system.supervise::<Bob, _>(|id, _world| Bob { id, received: CVec::new() }, 1, 10, None);
+    system.send(alice.as_raw(), Explode);
+    system.process_all_messages();
+
+    let restarted_bob = BobID::from_raw(RawID {
+        version: bob.as_raw().version + 1,
+        ..bob.as_raw()
+    });
+    assert!(!system.panic_happened);
+    assert_eq!(system.get_instance_counts()["Alice"], 0);
+    assert_eq!(system.get_instance_counts()["Bob"], 1);
+    assert_eq!(Bob::received(&mut system, restarted_bob), vec![]);
+    assert_eq!(system.panic_reports().len(), 2);
+}
+
+#[test]
+fn test_panicking_restart_counts_as_exceeded_restart_intensity() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::testing::{test_system, Alice, Bob, Explode};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    Bob::register(&mut system);
+    system.add_handler::<Bob, RestartIntensityExceeded, _>(
+        |exceeded, instance, _world| {
+            instance.received.push(exceeded.failed.instance_id);
+            Fate::Live
+        },
+        false,
+    );
+    let alice = Alice::spawn(&mut system);
+    let bob = Bob::spawn(&mut system);
+    system.supervise::<Alice, _>(|_id, _world| panic!("Restart failed"), 1, 10, Some(bob.as_raw()));
+    system.send(alice.as_raw(), Explode);
+    system.process_all_messages();
+
+    assert!(!system.panic_happened);
+    assert_eq!(system.get_instance_counts()["Alice"], 0);
+    assert_eq!(Bob::received(&mut system, bob), vec![alice.as_raw().instance_id]);
+}
+
+#[test]
+fn test_panicked_instance_is_restarted_via_spawner() {
+    use crate::id::TypedID;
+    use crate::testing::{test_system, Alice, AliceID, Explode, Spawn};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    system.supervise_via_spawner::<Alice, Spawn, _>(|id: AliceID| Spawn(id.as_raw()), 1, 10, None);
+    let alice = Alice::spawn(&mut system);
+    system.send(alice.as_raw(), Explode);
+    system.process_all_messages();
+
+    let restarted = AliceID::from_raw(RawID {
+        version: alice.as_raw().version + 1,
+        ..alice.as_raw()
+    });
+    assert!(!system.panic_happened);
+    assert_eq!(system.get_instance_counts()["Alice"], 1);
+    assert_eq!(Alice::received(&mut system, restarted), vec![]);
+}
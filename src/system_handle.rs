@@ -0,0 +1,340 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use compact::Compact;
+use crate::actor_system::World;
+use crate::class::inbox::multi_writer_queue::MultiWriterQueue;
+use crate::id::{MachineID, RawID};
+use crate::messaging::{Message, Packet};
+use crate::tuning::Tuning;
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A snapshot of the type registries of an `ActorSystem`
+#[derive(Clone)]
+pub(crate) struct Registries {
+    pub actors: Arc<TypeRegistry>,
+    pub messages: Arc<TypeRegistry>,
+}
+
+/// Returned by the methods of a `World` obtained from `SystemHandle::world`
+/// that are only possible within the actor system
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutsideOfActorSystem;
+
+impl ::std::fmt::Display for OutsideOfActorSystem {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Only possible within the actor system, not through a SystemHandle")
+    }
+}
+
+impl ::std::error::Error for OutsideOfActorSystem {}
+
+/// Returned by `SystemHandle::send` for messages of a type that wasn't registered
+/// in the `ActorSystem` yet when it last got a handle or processed messages
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownMessageType {
+    /// The name of the message type
+    pub message_name: String,
+}
+
+impl ::std::fmt::Display for UnknownMessageType {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{} is not a registered message type of the actor system", self.message_name)
+    }
+}
+
+impl ::std::error::Error for UnknownMessageType {}
+
+/// The part of an `ActorSystem` that is shared with its `SystemHandle`s
+pub(crate) struct SharedState {
+    machine_id: MachineID,
+    n_turns: AtomicUsize,
+    // entries are the turn they are due at (0 for right away),
+    // followed by an entry encoded like `Outbox` entries.
+    // Always on the heap, since it is emptied at the start of each message cycle
+    staging: MultiWriterQueue,
+    // (watcher, target) pairs of monitors of actors on other machines
+    staged_remote_monitors: Mutex<Vec<(RawID, RawID)>>,
+    registries: RwLock<Registries>,
+}
+
+impl SharedState {
+    pub fn new(machine_id: MachineID, tuning: &Tuning) -> SharedState {
+        SharedState {
+            machine_id,
+            n_turns: AtomicUsize::new(0),
+            staging: MultiWriterQueue::new(
+                &"staging".into(),
+                Arc::new(chunky::HeapStorage),
+                tuning.inbox_queue_chunk_size,
+            ),
+            staged_remote_monitors: Mutex::new(Vec::new()),
+            registries: RwLock::new(Registries {
+                actors: Arc::new(TypeRegistry::new()),
+                messages: Arc::new(TypeRegistry::new()),
+            }),
+        }
+    }
+
+    pub fn registries(&self) -> Registries {
+        self.registries.read().unwrap().clone()
+    }
+
+    pub fn publish_registries(&self, actors: &TypeRegistry, messages: &TypeRegistry) {
+        *self.registries.write().unwrap() = Registries {
+            actors: Arc::new(actors.clone()),
+            messages: Arc::new(messages.clone()),
+        };
+    }
+
+    pub fn publish_n_turns(&self, n_turns: usize) {
+        self.n_turns.store(n_turns, Ordering::SeqCst);
+    }
+
+    /// Pass all currently staged messages (message type followed by a compact `Packet`)
+    /// to `deliver`, together with the turn they are due at.
+    /// Must only be called by the `ActorSystem` itself.
+    pub unsafe fn deliver_staged<F: FnMut(usize, &[u8])>(&self, mut deliver: F) {
+        let n_staged = self.staging.len();
+        let turn_size = ::std::mem::size_of::<u64>();
+        let header_size = turn_size + ::std::mem::size_of::<u32>();
+
+        for _ in 0..n_staged {
+            let entry_ptr = self
+                .staging
+                .dequeue()
+                .expect("should have something left for sure");
+            let header = ::std::slice::from_raw_parts(entry_ptr, header_size);
+            let turn = LittleEndian::read_u64(header) as usize;
+            let entry_size = LittleEndian::read_u32(&header[turn_size..]) as usize;
+            deliver(
+                turn,
+                ::std::slice::from_raw_parts(entry_ptr.add(header_size), entry_size),
+            );
+        }
+
+        self.staging.drop_old_chunks();
+    }
+
+    /// Take out all monitors of actors on other machines made through a `SystemHandle`
+    pub fn take_staged_remote_monitors(&self) -> Vec<(RawID, RawID)> {
+        ::std::mem::replace(&mut *self.staged_remote_monitors.lock().unwrap(), Vec::new())
+    }
+}
+
+/// A cloneable handle that can be used to send messages into an `ActorSystem`
+/// from any thread - for example from network front-ends, file watchers or audio threads.
+///
+/// Messages sent through a handle are staged and only delivered
+/// at the start of the next `ActorSystem::process_all_messages`, or of the next turn.
+#[derive(Clone)]
+pub struct SystemHandle {
+    shared: Arc<SharedState>,
+}
+
+impl SystemHandle {
+    pub(crate) fn new(shared: Arc<SharedState>) -> SystemHandle {
+        SystemHandle { shared }
+    }
+
+    /// Send a message to a RawID.
+    /// Fails if the message type isn't known to the actor system yet, dropping the message.
+    pub fn send<M: Message + Send>(&self, recipient: RawID, message: M) -> Result<(), UnknownMessageType> {
+        let message_type = self.message_type::<M>(&self.shared.registries())?;
+        self.stage(
+            message_type,
+            Packet {
+                recipient_id: recipient,
+                message,
+            },
+        );
+        Ok(())
+    }
+
+    /// Look up a message type in `registries`, or in the ones
+    /// the actor system published since, if it's not in there
+    pub(crate) fn message_type<M: Message>(&self, registries: &Registries) -> Result<ShortTypeId, UnknownMessageType> {
+        registries
+            .messages
+            .get_if_known::<M>()
+            .or_else(|| self.shared.registries().messages.get_if_known::<M>())
+            .ok_or_else(|| UnknownMessageType {
+                message_name: ::std::any::type_name::<M>().to_owned(),
+            })
+    }
+
+    /// Get a `World` that sends all messages through this handle, so the
+    /// methods of typed IDs (like `SomeActorID::some_message(..., world)`)
+    /// can be used from other threads.
+    ///
+    /// Sending (also at later turns), monitoring and getting IDs work like within
+    /// the actor system. Requests and map-reduce queries need a requesting actor
+    /// instance and fail with `OutsideOfActorSystem`. Spawning actors
+    /// (which needs `World::allocate_instance_id`) is not possible this way.
+    /// Messages of types unknown to the actor system are dropped with a report.
+    pub fn world(&self) -> World {
+        World::external(self.clone(), self.shared.registries())
+    }
+
+    /// Get the machine ID of the actor system in the network
+    pub fn machine_id(&self) -> MachineID {
+        self.shared.machine_id
+    }
+
+    /// Get the local number of networking turns of the actor system,
+    /// as of the last time it sent and received or finished a networking turn
+    pub fn current_turn(&self) -> usize {
+        self.shared.n_turns.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn stage<M: Message>(&self, message_type: ShortTypeId, packet: Packet<M>) {
+        self.stage_at_turn(0, message_type, packet)
+    }
+
+    pub(crate) fn stage_at_turn<M: Message>(&self, turn: usize, message_type: ShortTypeId, mut packet: Packet<M>) {
+        let packet_size = packet.total_size_bytes();
+        let entry_size = ::std::mem::size_of::<ShortTypeId>() + packet_size;
+        let header_size = ::std::mem::size_of::<u64>()
+            + ::std::mem::size_of::<u32>()
+            + ::std::mem::size_of::<ShortTypeId>();
+
+        self.shared.staging.enqueue(
+            header_size - ::std::mem::size_of::<ShortTypeId>() + entry_size,
+            |entry_ptr| unsafe {
+                let mut header = ::std::slice::from_raw_parts_mut(entry_ptr, header_size);
+                header.write_u64::<LittleEndian>(turn as u64).unwrap();
+                header.write_u32::<LittleEndian>(entry_size as u32).unwrap();
+                header.write_u16::<LittleEndian>(message_type.into()).unwrap();
+
+                // store packet compactly in the staging queue
+                Compact::compact_behind(
+                    &mut packet,
+                    entry_ptr.add(header_size) as *mut Packet<M>,
+                );
+            },
+        );
+
+        ::std::mem::forget(packet);
+    }
+
+    pub(crate) fn stage_remote_monitor(&self, watcher: RawID, target: RawID) {
+        self.shared
+            .staged_remote_monitors
+            .lock()
+            .unwrap()
+            .push((watcher, target));
+    }
+}
+
+#[allow(dead_code)]
+fn assert_handle_is_send_and_sync() {
+    fn is_send_and_sync<T: Send + Sync>() {}
+    is_send_and_sync::<SystemHandle>();
+}
+
+#[test]
+fn test_handles_send_from_other_threads() {
+    use crate::id::TypedID;
+    use crate::testing::{test_system, Alice, Note};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    let alice = Alice::spawn(&mut system);
+
+    let senders: Vec<_> = (0..4)
+        .map(|i| {
+            let handle = system.handle();
+            ::std::thread::spawn(move || handle.send(alice.as_raw(), Note(i)))
+        })
+        .collect();
+    for sender in senders {
+        assert_eq!(sender.join().unwrap(), Ok(()));
+    }
+
+    system.process_all_messages();
+    let mut received = Alice::received(&mut system, alice);
+    received.sort_unstable();
+    assert_eq!(received, vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn test_handle_refuses_unknown_message_types() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::testing::{test_system, Alice};
+
+    #[derive(Copy, Clone, Portable)]
+    struct Late(u32);
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    let alice = Alice::spawn(&mut system);
+    let handle = system.handle();
+    system.add_handler::<Alice, Late, _>(
+        |late, instance, _world| {
+            instance.received.push(late.0);
+            Fate::Live
+        },
+        false,
+    );
+
+    // not known to the handle yet
+    let result = handle.send(alice.as_raw(), Late(1));
+    assert!(result.unwrap_err().message_name.ends_with("Late"));
+    handle.world().send(alice.as_raw(), Late(2));
+
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![]);
+    assert_eq!(handle.send(alice.as_raw(), Late(3)), Ok(()));
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![3]);
+}
+
+#[test]
+fn test_world_of_handle_stages_messages() {
+    use crate::id::TypedID;
+    use crate::testing::{test_system, Alice, Note};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    let alice = Alice::spawn(&mut system);
+    let mut world = system.handle().world();
+
+    world.send(alice.as_raw(), Note(1));
+    world.send_after(alice.as_raw(), Note(2), 2);
+    assert_eq!(Alice::received(&mut system, alice), vec![]);
+
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![1]);
+
+    system.networking_finish_turn();
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![1]);
+
+    system.networking_finish_turn();
+    assert_eq!(world.current_turn(), 2);
+    system.process_all_messages();
+    assert_eq!(Alice::received(&mut system, alice), vec![1, 2]);
+}
+
+#[test]
+fn test_world_of_handle_refuses_requests() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::testing::{test_system, Alice, Note};
+
+    let mut system = test_system();
+    Alice::register(&mut system);
+    system.add_requester::<Alice, Note, Note>(false);
+    let alice = Alice::spawn(&mut system);
+    let mut world = system.handle().world();
+
+    let result = world.request::<Alice, Note, Note, _>(
+        alice,
+        alice.as_raw(),
+        Note(1),
+        10,
+        |_response, _instance: &mut Alice, _world| Fate::Live,
+    );
+    assert_eq!(result.err(), Some(OutsideOfActorSystem));
+}
@@ -0,0 +1,142 @@
+//! Actor classes and messages shared by the tests of different modules
+
+// not every test uses all of these
+#![allow(dead_code)]
+
+use crate::actor_system::ActorSystem;
+use crate::id::{RawID, TypedID};
+use crate::messaging::Fate;
+use crate::networking::Networking;
+use crate::tuning::Tuning;
+use compact::CVec;
+
+/// Spawns an instance with a previously allocated ID
+#[derive(Copy, Clone, Portable)]
+pub struct Spawn(pub RawID);
+
+/// Gets recorded by the receiving instance
+#[derive(Copy, Clone, Portable)]
+pub struct Note(pub u32);
+
+/// Gets recorded by the receiving instance, which then sends `Note` to another actor
+#[derive(Copy, Clone, Portable)]
+pub struct Forward(pub u32, pub RawID);
+
+/// Makes the receiving instance die
+#[derive(Copy, Clone, Portable)]
+pub struct Die;
+
+/// Makes the receiving instance panic
+#[derive(Copy, Clone, Portable)]
+pub struct Explode;
+
+pub fn test_system() -> ActorSystem {
+    test_system_with_tuning(Tuning::default())
+}
+
+pub fn test_system_with_tuning(tuning: Tuning) -> ActorSystem {
+    let networking = Networking::new(0, vec!["127.0.0.1:0".to_owned()], 4096, 30, 10);
+    ActorSystem::new(networking, tuning)
+}
+
+macro_rules! test_actor {
+    ($actor:ident, $id:ident) => {
+        #[derive(Compact, Clone)]
+        pub struct $actor {
+            pub id: $id,
+            pub received: CVec<u32>,
+        }
+
+        #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+        pub struct $id {
+            _raw_id: RawID,
+        }
+
+        impl TypedID for $id {
+            type Target = $actor;
+
+            fn as_raw(&self) -> RawID {
+                self._raw_id
+            }
+
+            fn from_raw(raw: RawID) -> Self {
+                $id { _raw_id: raw }
+            }
+        }
+
+        impl crate::actor::Actor for $actor {
+            type ID = $id;
+
+            fn id(&self) -> $id {
+                self.id
+            }
+
+            unsafe fn set_id(&mut self, id: RawID) {
+                self.id = $id::from_raw(id);
+            }
+        }
+
+        impl $actor {
+            /// Register the class with all handlers for the test messages
+            pub fn register(system: &mut ActorSystem) {
+                system.register::<$actor>();
+                system.add_spawner::<$actor, Spawn, _>(
+                    |spawn, _world| $actor {
+                        id: $id::from_raw(spawn.0),
+                        received: CVec::new(),
+                    },
+                    false,
+                );
+                system.add_handler::<$actor, Note, _>(
+                    |note, instance, _world| {
+                        instance.received.push(note.0);
+                        Fate::Live
+                    },
+                    false,
+                );
+                system.add_handler::<$actor, Forward, _>(
+                    |forward, instance, world| {
+                        instance.received.push(forward.0);
+                        world.send(forward.1, Note(forward.0));
+                        Fate::Live
+                    },
+                    false,
+                );
+                system.add_handler::<$actor, Die, _>(|_, _, _| Fate::Die, false);
+                system.add_handler::<$actor, Explode, _>(|_, _, _| panic!("Exploded"), false);
+            }
+
+            /// Spawn an instance and wait until it exists
+            pub fn spawn(system: &mut ActorSystem) -> $id {
+                let id = system.world().allocate_instance_id::<$actor>();
+                system.send(id, Spawn(id));
+                system.process_all_messages();
+                $id::from_raw(id)
+            }
+
+            /// Get what an instance recorded, without changing it
+            pub fn received(system: &mut ActorSystem, id: $id) -> Vec<u32> {
+                let recorded = ::std::sync::Arc::new(::std::sync::Mutex::new(None));
+                let recorded_in_handler = ::std::sync::Arc::clone(&recorded);
+                system.add_handler::<$actor, Inspect, _>(
+                    move |_, instance, _world| {
+                        *recorded_in_handler.lock().unwrap() = Some(instance.received.to_vec());
+                        Fate::Live
+                    },
+                    true,
+                );
+                system.send(id.as_raw(), Inspect);
+                system.process_messages_of::<$actor>();
+                let received = recorded.lock().unwrap().take();
+                received.expect("Instance should exist")
+            }
+        }
+    };
+}
+
+/// Lets tests look at the state of an instance
+#[derive(Copy, Clone, Portable)]
+pub struct Inspect;
+
+test_actor!(Alice, AliceID);
+test_actor!(Bob, BobID);
@@ -0,0 +1,47 @@
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A `chunky::ChunkStorage` that actor systems can keep their classes in,
+/// even if classes are handled on worker threads (see `Tuning::n_worker_threads`).
+///
+/// # Safety
+/// Chunks created by the storage can be used and dropped on other threads
+/// than the one that created them, which implementors have to allow
+/// (like `chunky::HeapStorage` and `chunky::MmapStorage` do).
+pub unsafe trait ThreadSafeStorage: chunky::ChunkStorage + Send + Sync {}
+
+unsafe impl ThreadSafeStorage for chunky::HeapStorage {}
+
+#[cfg(feature = "server")]
+unsafe impl ThreadSafeStorage for chunky::MmapStorage {}
+
+/// Lets chunky collections, which expect an `Rc`, use a `ThreadSafeStorage`.
+///
+/// Everything that might be moved to another thread as a whole gets its own `Rc`
+/// (see `LocalStorage::new_rc`), which is only cloned by the collections it owns -
+/// so no reference count is ever shared between threads.
+pub(crate) struct LocalStorage(Arc<dyn ThreadSafeStorage>);
+
+impl LocalStorage {
+    pub fn new_rc(storage: &Arc<dyn ThreadSafeStorage>) -> Rc<dyn chunky::ChunkStorage> {
+        Rc::new(LocalStorage(Arc::clone(storage)))
+    }
+}
+
+impl chunky::ChunkStorage for LocalStorage {
+    fn create_chunk(&self, ident: chunky::Ident, size: usize) -> chunky::Chunk {
+        self.0.create_chunk(ident, size)
+    }
+
+    fn load_or_create_chunk(&self, ident: chunky::Ident, size: usize) -> (chunky::Chunk, bool) {
+        self.0.load_or_create_chunk(ident, size)
+    }
+
+    fn load_chunk(&self, ident: chunky::Ident) -> chunky::Chunk {
+        self.0.load_chunk(ident)
+    }
+
+    fn forget_chunk(&self, chunk: chunky::Chunk) {
+        self.0.forget_chunk(chunk)
+    }
+}
@@ -0,0 +1,117 @@
+use crate::class::outbox::Outbox;
+use std::collections::BTreeMap;
+
+/// Parks encoded messages until the networking turn they are scheduled for.
+///
+/// Turns within `n_slots` of the next due turn go into a slot of the wheel,
+/// turns further in the future are kept sorted by turn until they are due.
+/// All messages for one turn share one buffer, and buffers of delivered
+/// turns are reused for later turns.
+pub(crate) struct TimerWheel {
+    slots: Vec<Outbox>,
+    far_future: BTreeMap<usize, Outbox>,
+    next_due_turn: usize,
+    spare_buffers: Vec<Vec<u8>>,
+}
+
+impl TimerWheel {
+    pub fn new(n_slots: usize) -> TimerWheel {
+        assert!(n_slots > 0, "Timer wheel needs at least one slot");
+        TimerWheel {
+            slots: (0..n_slots).map(|_| Outbox::new()).collect(),
+            far_future: BTreeMap::new(),
+            next_due_turn: 0,
+            spare_buffers: Vec::new(),
+        }
+    }
+
+    /// Get the outbox collecting messages for `turn`, which has to be in the future
+    pub fn outbox_for(&mut self, turn: usize) -> &mut Outbox {
+        debug_assert!(turn >= self.next_due_turn);
+        if turn < self.next_due_turn + self.slots.len() {
+            let n_slots = self.slots.len();
+            &mut self.slots[turn % n_slots]
+        } else {
+            self.far_future.entry(turn).or_insert_with(Outbox::new)
+        }
+    }
+
+    /// Take out the buffers of messages for all turns up to and including `turn`,
+    /// in the order of their turns and then in the order they were scheduled in.
+    pub fn take_due(&mut self, turn: usize) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+
+        while self.next_due_turn <= turn {
+            // messages for this turn that were scheduled when it was still far away
+            // were scheduled before the ones in its slot
+            if let Some(mut outbox) = self.far_future.remove(&self.next_due_turn) {
+                due.push(outbox.take());
+            }
+
+            let n_slots = self.slots.len();
+            let slot = &mut self.slots[self.next_due_turn % n_slots];
+            if !slot.is_empty() {
+                due.push(slot.take_replacing(self.spare_buffers.pop().unwrap_or_default()));
+            }
+
+            self.next_due_turn += 1;
+
+            // skip ahead over turns for which nothing can be scheduled
+            if self.far_future.is_empty() && self.slots.iter().all(Outbox::is_empty) {
+                self.next_due_turn = turn + 1;
+            }
+        }
+
+        due
+    }
+
+    /// Give back a buffer obtained by `take_due` once its messages were delivered
+    pub fn recycle(&mut self, mut buffer: Vec<u8>) {
+        if self.spare_buffers.len() < self.slots.len() {
+            buffer.clear();
+            self.spare_buffers.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+fn due_entries(wheel: &mut TimerWheel, turn: usize) -> Vec<u8> {
+    let mut entries = Vec::new();
+    for buffer in wheel.take_due(turn) {
+        entries.extend(crate::class::outbox::entries(&buffer).map(|entry| entry[0]));
+        wheel.recycle(buffer);
+    }
+    entries
+}
+
+#[test]
+fn test_messages_are_due_in_turn_order() {
+    let mut wheel = TimerWheel::new(4);
+    // the last one is too far away for a slot
+    for &(turn, entry) in &[(2, 1), (1, 2), (2, 3), (10, 4), (3, 5)] {
+        wheel.outbox_for(turn).put_raw(&[entry]);
+    }
+
+    assert_eq!(due_entries(&mut wheel, 0), vec![]);
+    assert_eq!(due_entries(&mut wheel, 2), vec![2, 1, 3]);
+    // scheduled later, but still for a turn that is not due yet
+    wheel.outbox_for(10).put_raw(&[6]);
+    assert_eq!(due_entries(&mut wheel, 9), vec![5]);
+    assert_eq!(due_entries(&mut wheel, 10), vec![4, 6]);
+}
+
+#[test]
+fn test_buffers_of_delivered_turns_are_reused() {
+    let mut wheel = TimerWheel::new(4);
+    wheel.outbox_for(1).put_raw(&[0; 100]);
+    let delivered = wheel.take_due(1).pop().unwrap();
+    let capacity = delivered.capacity();
+    wheel.recycle(delivered);
+
+    // taking out turn 2 hands the recycled buffer to its slot...
+    wheel.outbox_for(2).put_raw(&[0; 10]);
+    assert!(wheel.take_due(2).pop().unwrap().capacity() < capacity);
+    // ...which collects the messages of turn 6
+    wheel.outbox_for(6).put_raw(&[0; 10]);
+    assert_eq!(wheel.take_due(6).pop().unwrap().capacity(), capacity);
+}
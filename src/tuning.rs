@@ -1,9 +1,25 @@
+/// Sizes and limits used when setting up an actor system
 pub struct Tuning {
+    /// Chunk size of the storage for actor instance states
     pub instance_chunk_size: usize,
+    /// Chunk size of the table mapping instance IDs to their storage slots
     pub instance_entry_chunk_size: usize,
+    /// Chunk size of the table of instance ID versions
     pub instance_versions_chunk_size: usize,
+    /// Chunk size of the list of freed instance IDs
     pub instance_free_chunk_size: usize,
-    pub inbox_queue_chunk_size: usize
+    /// Chunk size of the inbox queue of each class
+    pub inbox_queue_chunk_size: usize,
+    /// Number of slots of the timer wheel for messages scheduled in the future
+    pub timer_wheel_slots: usize,
+    /// After how many message cycles in a row a chain of messages between classes
+    /// is reported as a message loop (0, the default, disables loop detection)
+    pub message_loop_threshold: usize,
+    /// How many threads handle the messages of different classes at the same time.
+    /// With more than one, messages sent by handlers are only delivered once all
+    /// classes are done with a message cycle (see `ActorSystem::process_all_messages`),
+    /// and handlers can only get IDs of actor classes and traits that are registered already
+    pub n_worker_threads: usize
 }
 
 impl ::std::default::Default for Tuning {
@@ -13,7 +29,10 @@ impl ::std::default::Default for Tuning {
             instance_entry_chunk_size: 1024 * 1024,
             instance_versions_chunk_size: 512 * 1024,
             instance_free_chunk_size: 8 * 1024,
-            inbox_queue_chunk_size: 1024 * 1024
+            inbox_queue_chunk_size: 1024 * 1024,
+            timer_wheel_slots: 256,
+            message_loop_threshold: 0,
+            n_worker_threads: 1
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,185 @@
+use crate::type_registry::ShortTypeId;
+
+/// Maps `ShortTypeId`s to values, with constant-time lookup.
+/// Used for things that exist for most types, like classes.
+///
+/// Values are stored contiguously in insertion order, next to a lookup
+/// table of positions that is split into pages, which are only allocated
+/// once an ID in their range is inserted - this keeps the map small
+/// even though IDs are spread over the whole `u16` range.
+#[derive(Clone)]
+pub struct DenseTypeIdMap<T> {
+    /// Position in `values` plus one for each ID, zero if there is no value
+    pages: Vec<Option<Box<[u16; PAGE_SIZE]>>>,
+    values: Vec<(ShortTypeId, T)>,
+}
+
+/// How many IDs each page of the lookup table of a `DenseTypeIdMap` covers
+const PAGE_SIZE: usize = 256;
+
+impl<T> DenseTypeIdMap<T> {
+    pub fn new() -> Self {
+        DenseTypeIdMap {
+            pages: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    fn position(&self, id: ShortTypeId) -> Option<usize> {
+        let page = self.pages.get(id.as_usize() / PAGE_SIZE)?.as_ref()?;
+        match page[id.as_usize() % PAGE_SIZE] {
+            0 => None,
+            position => Some(position as usize - 1),
+        }
+    }
+
+    pub fn get(&self, id: ShortTypeId) -> Option<&T> {
+        self.position(id).map(|position| &self.values[position].1)
+    }
+
+    pub fn get_mut(&mut self, id: ShortTypeId) -> Option<&mut T> {
+        match self.position(id) {
+            Some(position) => Some(&mut self.values[position].1),
+            None => None,
+        }
+    }
+
+    pub fn contains(&self, id: ShortTypeId) -> bool {
+        self.position(id).is_some()
+    }
+
+    pub fn insert(&mut self, id: ShortTypeId, value: T) {
+        match self.position(id) {
+            Some(position) => self.values[position].1 = value,
+            None => {
+                self.get_or_insert_with(id, || value);
+            }
+        }
+    }
+
+    pub fn get_or_insert_with<F: FnOnce() -> T>(&mut self, id: ShortTypeId, default: F) -> &mut T {
+        let position = match self.position(id) {
+            Some(position) => position,
+            None => {
+                let page_index = id.as_usize() / PAGE_SIZE;
+                if page_index >= self.pages.len() {
+                    self.pages.resize_with(page_index + 1, || None);
+                }
+                self.values.push((id, default()));
+                let page = self.pages[page_index].get_or_insert_with(|| Box::new([0; PAGE_SIZE]));
+                page[id.as_usize() % PAGE_SIZE] = self.values.len() as u16;
+                self.values.len() - 1
+            }
+        };
+        &mut self.values[position].1
+    }
+
+    /// Iterate over all IDs and values, in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = (ShortTypeId, &T)> {
+        self.values.iter().map(|(id, value)| (*id, value))
+    }
+
+    /// Iterate over all IDs and mutable values, in insertion order
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (ShortTypeId, &mut T)> {
+        self.values.iter_mut().map(|(id, value)| (*id, value))
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.values.iter_mut().map(|(_, value)| value)
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.values.clear();
+    }
+}
+
+impl<T> Default for DenseTypeIdMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maps `ShortTypeId`s to values, stored sparsely in a `Vec` sorted by ID.
+/// Used for things that exist only for a few types, like the message handlers of a class.
+pub struct SparseTypeIdMap<T> {
+    entries: Vec<(ShortTypeId, T)>,
+}
+
+impl<T> SparseTypeIdMap<T> {
+    pub fn new() -> Self {
+        SparseTypeIdMap {
+            entries: Vec::new(),
+        }
+    }
+
+    fn position(&self, id: ShortTypeId) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by_key(&id.as_u16(), |&(entry_id, _)| entry_id.as_u16())
+    }
+
+    pub fn get(&self, id: ShortTypeId) -> Option<&T> {
+        self.position(id).ok().map(|i| &self.entries[i].1)
+    }
+
+    pub fn insert(&mut self, id: ShortTypeId, value: T) {
+        match self.position(id) {
+            Ok(i) => self.entries[i].1 = value,
+            Err(i) => self.entries.insert(i, (id, value)),
+        }
+    }
+}
+
+impl<T> Default for SparseTypeIdMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts of something (like sent messages) per type
+pub type TypeIdCounts = DenseTypeIdMap<usize>;
+
+impl TypeIdCounts {
+    pub fn increment(&mut self, id: ShortTypeId) {
+        *self.get_or_insert_with(id, || 0) += 1;
+    }
+
+    /// Add all counts of `other` to the counts of the same types
+    pub fn add(&mut self, other: &TypeIdCounts) {
+        for (id, count) in other.iter() {
+            *self.get_or_insert_with(id, || 0) += count;
+        }
+    }
+}
+
+#[test]
+fn test_dense_map_only_allocates_pages_of_inserted_ids() {
+    let mut map = DenseTypeIdMap::new();
+    for &id in &[1, 2, 40_000, 65_535] {
+        map.insert(ShortTypeId::new(id).unwrap(), id);
+    }
+
+    assert_eq!(map.pages.iter().filter(|page| page.is_some()).count(), 3);
+    for &id in &[1, 2, 40_000, 65_535] {
+        assert_eq!(map.get(ShortTypeId::new(id).unwrap()), Some(&id));
+    }
+    assert_eq!(map.get(ShortTypeId::new(3).unwrap()), None);
+    assert_eq!(map.get(ShortTypeId::new(40_001).unwrap()), None);
+    assert_eq!(
+        map.iter().map(|(id, _)| id.as_u16()).collect::<Vec<_>>(),
+        vec![1, 2, 40_000, 65_535]
+    );
+}
+
+#[test]
+fn test_counts_are_kept_per_type() {
+    let mut counts = TypeIdCounts::new();
+    counts.increment(ShortTypeId::new(7).unwrap());
+    counts.increment(ShortTypeId::new(60_000).unwrap());
+    counts.increment(ShortTypeId::new(7).unwrap());
+
+    assert_eq!(counts.get(ShortTypeId::new(7).unwrap()), Some(&2));
+    assert_eq!(counts.get(ShortTypeId::new(60_000).unwrap()), Some(&1));
+    counts.clear();
+    assert_eq!(counts.get(ShortTypeId::new(7).unwrap()), None);
+}
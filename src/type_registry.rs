@@ -30,50 +30,249 @@ impl From<ShortTypeId> for u16 {
     }
 }
 
+/// Why a type manifest couldn't be applied to a `TypeRegistry`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestError {
+    /// A line of the manifest is not of the form `<id> <type name>`
+    Malformed {
+        /// The offending line
+        line: String,
+    },
+    /// Two different types claim the same ID
+    Collision {
+        /// The contested ID
+        id: u16,
+        /// The type that already has the ID
+        existing: String,
+        /// The type that also claimed it
+        claimed_by: String,
+    },
+    /// A type that is already registered has a different ID than the manifest says
+    AlreadyRegistered {
+        /// The type
+        name: String,
+        /// The ID it was registered with
+        registered: u16,
+        /// The ID the manifest pins it to
+        pinned: u16,
+    },
+}
+
+impl ::std::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            ManifestError::Malformed { line } => write!(f, "Malformed manifest line: {:?}", line),
+            ManifestError::Collision {
+                id,
+                existing,
+                claimed_by,
+            } => write!(f, "Type ID {} of {} is also claimed by {}", id, existing, claimed_by),
+            ManifestError::AlreadyRegistered {
+                name,
+                registered,
+                pinned,
+            } => write!(
+                f,
+                "{} is already registered with ID {}, but pinned to {}",
+                name, registered, pinned
+            ),
+        }
+    }
+}
+
+/// Why a type couldn't be given an ID when registering it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistrationError {
+    /// The ID pinned for the type already belongs to another type
+    PinnedIdTaken {
+        /// The type
+        name: String,
+        /// The ID pinned for it
+        id: u16,
+        /// The type that has the ID
+        existing: String,
+    },
+    /// All IDs are taken
+    NoFreeId {
+        /// The type
+        name: String,
+    },
+}
+
+impl ::std::fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            RegistrationError::PinnedIdTaken { name, id, existing } => {
+                write!(f, "Type ID {} pinned for {} is already taken by {}", id, name, existing)
+            }
+            RegistrationError::NoFreeId { name } => write!(f, "No type ID left for {}", name),
+        }
+    }
+}
+
+/// Assigns `ShortTypeId`s to types.
+///
+/// IDs are derived from a hash of the type name, so they don't depend on
+/// the order types are registered in and usually agree between different builds
+/// (for example server and browser) that register different sets of types.
+/// Hash collisions are resolved by probing for the next free ID, which *does*
+/// depend on registration order - such IDs, or any others, can be fixed
+/// using `pin` or a manifest (see `manifest` and `pin_from_manifest`).
+///
+/// Type names (from `type_name`) are not guaranteed to be the same for different
+/// compiler versions or targets, so builds that need to understand each other's
+/// messages can only rely on the same IDs if they pin them from the same manifest.
+#[derive(Clone)]
 pub struct TypeRegistry {
-    next_short_id: ShortTypeId,
     long_to_short_ids: HashMap<u64, ShortTypeId>,
     pub short_ids_to_names: HashMap<ShortTypeId, String>,
+    pinned_ids: HashMap<String, ShortTypeId>,
+    pinned_names: HashMap<ShortTypeId, String>,
 }
 
 impl TypeRegistry {
     pub fn new() -> TypeRegistry {
         TypeRegistry {
-            next_short_id: ShortTypeId::new(1).unwrap(), // Non nullable optimization
             long_to_short_ids: HashMap::new(),
             short_ids_to_names: HashMap::new(),
+            pinned_ids: HashMap::new(),
+            pinned_names: HashMap::new(),
         }
     }
 
-    pub fn register_new<T: 'static>(&mut self) -> ShortTypeId {
-        let short_id = self.next_short_id;
-        let long_id = unsafe { type_id::<T>() };
+    pub fn register_new<T: 'static>(&mut self) -> Result<ShortTypeId, RegistrationError> {
+        let long_id = type_id::<T>();
         assert!(self.long_to_short_ids.get(&long_id).is_none());
+        let name: String = type_name::<T>().into();
+        let short_id = self.free_id_for(&name)?;
         self.long_to_short_ids.insert(long_id, short_id);
-        self.short_ids_to_names
-            .insert(short_id, unsafe { type_name::<T>() }.into());
-        self.next_short_id = ShortTypeId::new(u16::from(self.next_short_id) + 1).unwrap();
-        short_id
+        self.short_ids_to_names.insert(short_id, name);
+        Ok(short_id)
+    }
+
+    /// The pinned ID of a type, or the first ID starting from its name hash
+    /// that is neither registered nor pinned to another type
+    fn free_id_for(&self, name: &str) -> Result<ShortTypeId, RegistrationError> {
+        if let Some(&pinned_id) = self.pinned_ids.get(name) {
+            if let Some(existing) = self.short_ids_to_names.get(&pinned_id) {
+                return Err(RegistrationError::PinnedIdTaken {
+                    name: name.to_owned(),
+                    id: pinned_id.as_u16(),
+                    existing: existing.clone(),
+                });
+            }
+            return Ok(pinned_id);
+        }
+
+        let mut candidate = stable_id(name);
+        // try every non-zero ID at most once
+        for _ in 0..u16::max_value() {
+            if !self.short_ids_to_names.contains_key(&candidate) && !self.pinned_names.contains_key(&candidate) {
+                return Ok(candidate);
+            }
+            candidate = ShortTypeId::new(candidate.as_u16().wrapping_add(1))
+                .unwrap_or_else(|| ShortTypeId::new(1).unwrap());
+        }
+
+        Err(RegistrationError::NoFreeId { name: name.to_owned() })
     }
 
     pub fn get<T: 'static>(&self) -> ShortTypeId {
-        if let Some(&short_id) = self.long_to_short_ids.get(&unsafe { type_id::<T>() }) {
+        if let Some(short_id) = self.get_if_known::<T>() {
             short_id
         } else {
-            panic!("{:?} not known.", &unsafe { type_name::<T>() })
+            panic!("{:?} not known.", type_name::<T>())
         }
     }
 
+    pub fn get_if_known<T: 'static>(&self) -> Option<ShortTypeId> {
+        self.long_to_short_ids.get(&type_id::<T>()).cloned()
+    }
+
+    /// Get the ID of a type, registering it if needed.
+    /// Panics if it can't be registered (see `RegistrationError`).
     pub fn get_or_register<T: 'static>(&mut self) -> ShortTypeId {
         self.long_to_short_ids
-            .get(&unsafe { type_id::<T>() })
+            .get(&type_id::<T>())
             .cloned()
-            .unwrap_or_else(|| self.register_new::<T>())
+            .unwrap_or_else(|| self.register_new::<T>().unwrap_or_else(|error| panic!("{}", error)))
     }
 
     pub fn get_name(&self, short_id: ShortTypeId) -> &String {
         &self.short_ids_to_names[&short_id]
     }
+
+    pub fn n_types(&self) -> usize {
+        self.short_ids_to_names.len()
+    }
+
+    /// Fix the ID of the type with the given name, which has to happen before it is registered
+    pub fn pin(&mut self, name: &str, id: ShortTypeId) -> Result<(), ManifestError> {
+        if let Some(existing) = self.pinned_names.get(&id).or_else(|| self.short_ids_to_names.get(&id)) {
+            if existing != name {
+                return Err(ManifestError::Collision {
+                    id: id.as_u16(),
+                    existing: existing.clone(),
+                    claimed_by: name.to_owned(),
+                });
+            }
+        }
+
+        if let Some((registered, _)) = self.short_ids_to_names.iter().find(|&(_, registered_name)| registered_name == name) {
+            if *registered != id {
+                return Err(ManifestError::AlreadyRegistered {
+                    name: name.to_owned(),
+                    registered: registered.as_u16(),
+                    pinned: id.as_u16(),
+                });
+            }
+        }
+
+        if let Some(previous_id) = self.pinned_ids.insert(name.to_owned(), id) {
+            self.pinned_names.remove(&previous_id);
+        }
+        self.pinned_names.insert(id, name.to_owned());
+        Ok(())
+    }
+
+    /// All registered types as a manifest: one `<id> <type name>` line per type, ordered by ID
+    pub fn manifest(&self) -> String {
+        let mut entries: Vec<_> = self.short_ids_to_names.iter().collect();
+        entries.sort_by_key(|&(short_id, _)| short_id.as_u16());
+        entries
+            .into_iter()
+            .map(|(short_id, name)| format!("{} {}\n", short_id.as_u16(), name))
+            .collect()
+    }
+
+    /// Pin the IDs of all types listed in a manifest created by `manifest`.
+    /// Nothing is pinned if the manifest is malformed or contradicts itself or the registry.
+    pub fn pin_from_manifest(&mut self, manifest: &str) -> Result<(), ManifestError> {
+        let mut pinned = self.clone();
+
+        for line in manifest.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, ' ');
+            let id = parts.next().and_then(|id| id.parse().ok()).and_then(ShortTypeId::new);
+            let name = parts.next().map(str::trim);
+
+            match (id, name) {
+                (Some(id), Some(name)) if !name.is_empty() => pinned.pin(name, id)?,
+                _ => return Err(ManifestError::Malformed { line: line.to_owned() }),
+            }
+        }
+
+        *self = pinned;
+        Ok(())
+    }
+}
+
+/// Derive a non-zero ID from a type name (using 32-bit FNV-1a, folded to 16 bits)
+fn stable_id(name: &str) -> ShortTypeId {
+    let hash = name.bytes().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    let folded = ((hash >> 16) ^ (hash & 0xffff)) as u16;
+    ShortTypeId::new(folded).unwrap_or_else(|| ShortTypeId::new(1).unwrap())
 }
 
 impl Default for TypeRegistry {
@@ -81,3 +280,79 @@ impl Default for TypeRegistry {
         Self::new()
     }
 }
+
+#[test]
+fn test_manifest_pins_ids_regardless_of_registration_order() {
+    struct First;
+    struct Second;
+
+    let mut original = TypeRegistry::new();
+    original.pin(type_name::<First>(), ShortTypeId::new(5).unwrap()).unwrap();
+    let first = original.register_new::<First>().unwrap();
+    let second = original.register_new::<Second>().unwrap();
+    assert_eq!(first.as_u16(), 5);
+
+    let mut pinned = TypeRegistry::new();
+    pinned.pin_from_manifest(&original.manifest()).unwrap();
+    assert!(pinned.register_new::<Second>().unwrap() == second);
+    assert!(pinned.register_new::<First>().unwrap() == first);
+    assert_eq!(pinned.manifest(), original.manifest());
+}
+
+#[test]
+fn test_contradicting_manifest_is_rejected() {
+    struct First;
+
+    let mut registry = TypeRegistry::new();
+    let first = registry.register_new::<First>().unwrap();
+    let other_id = first.as_u16() % 1000 + 1;
+    let manifest = format!("{} {}\n", other_id, type_name::<First>());
+
+    assert_eq!(
+        registry.pin_from_manifest(&manifest),
+        Err(ManifestError::AlreadyRegistered {
+            name: type_name::<First>().to_owned(),
+            registered: first.as_u16(),
+            pinned: other_id,
+        })
+    );
+    assert_eq!(
+        registry.pin_from_manifest("five First"),
+        Err(ManifestError::Malformed { line: "five First".to_owned() })
+    );
+}
+
+#[test]
+fn test_taken_pinned_id_is_an_error() {
+    struct First;
+    struct Second;
+
+    let mut registry = TypeRegistry::new();
+    let first = registry.register_new::<First>().unwrap();
+    // circumvents the check of `pin`
+    registry.pinned_ids.insert(type_name::<Second>().to_owned(), first);
+
+    assert_eq!(
+        registry.register_new::<Second>().err(),
+        Some(RegistrationError::PinnedIdTaken {
+            name: type_name::<Second>().to_owned(),
+            id: first.as_u16(),
+            existing: type_name::<First>().to_owned(),
+        })
+    );
+}
+
+#[test]
+fn test_running_out_of_ids_is_an_error() {
+    struct First;
+
+    let mut registry = TypeRegistry::new();
+    for id in 1..=u16::max_value() {
+        registry.short_ids_to_names.insert(ShortTypeId::new(id).unwrap(), id.to_string());
+    }
+
+    assert_eq!(
+        registry.register_new::<First>().err(),
+        Some(RegistrationError::NoFreeId { name: type_name::<First>().to_owned() })
+    );
+}
@@ -0,0 +1,589 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use compact::{COption, CString, CVec, Compact};
+use crate::id::{MachineID, RawID};
+use crate::messaging::{Message, Packet};
+use crate::type_registry::ShortTypeId;
+
+/// Why data received from the network couldn't be decoded
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum WireError {
+    /// The data ended before the value was complete
+    UnexpectedEnd,
+    /// The data doesn't describe a valid value
+    Invalid(&'static str),
+    /// There is data left after the value
+    TrailingBytes,
+}
+
+impl ::std::fmt::Display for WireError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            WireError::UnexpectedEnd => write!(f, "Data ended unexpectedly"),
+            WireError::Invalid(what) => write!(f, "Invalid {}", what),
+            WireError::TrailingBytes => write!(f, "Unexpected data after the end"),
+        }
+    }
+}
+
+/// Why data received from a peer was rejected (which closes the connection to it)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum InvalidInput {
+    /// The peer sent something other than a binary batch of messages
+    NotBinary,
+    /// A message or its length prefix is cut off
+    Truncated,
+    /// The message type is not registered on this machine
+    UnknownMessageType(u16),
+    /// The recipient is neither a local actor class nor an actor trait with local implementors
+    UnknownRecipientType {
+        /// The type of the message
+        message_type: u16,
+        /// The type of the recipient
+        recipient_type: u16,
+    },
+    /// The message couldn't be decoded
+    Malformed {
+        /// The type of the message
+        message_type: u16,
+        /// What was wrong with it
+        error: WireError,
+    },
+    /// The peer skipped or repeated batches of messages
+    OutOfSequence {
+        /// The sequence number of the next batch
+        expected: u64,
+        /// The sequence number the peer sent
+        received: u64,
+    },
+    /// The peer acknowledged batches that weren't sent to it
+    InvalidAcknowledgement(u64),
+}
+
+impl ::std::fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            InvalidInput::NotBinary => write!(f, "Got a non binary message"),
+            InvalidInput::Truncated => write!(f, "Got a truncated message"),
+            InvalidInput::UnknownMessageType(message_type) => {
+                write!(f, "Got a message of unknown type {}", message_type)
+            }
+            InvalidInput::UnknownRecipientType {
+                message_type,
+                recipient_type,
+            } => write!(
+                f,
+                "Got a message of type {} for unknown actor type {}",
+                message_type, recipient_type
+            ),
+            InvalidInput::Malformed {
+                message_type,
+                error,
+            } => write!(f, "Got a malformed message of type {}: {}", message_type, error),
+            InvalidInput::OutOfSequence { expected, received } => write!(
+                f,
+                "Got batch number {} while expecting batch number {}",
+                received, expected
+            ),
+            InvalidInput::InvalidAcknowledgement(n_acknowledged) => {
+                write!(f, "Got an acknowledgement of {} batches that weren't all sent", n_acknowledged)
+            }
+        }
+    }
+}
+
+/// Reads values from portably encoded data, see `Portable`
+pub struct WireReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> WireReader<'a> {
+    /// Start reading at the beginning of `data`
+    pub fn new(data: &'a [u8]) -> WireReader<'a> {
+        WireReader { data, pos: 0 }
+    }
+
+    /// Take the next `n_bytes` bytes
+    pub fn take(&mut self, n_bytes: usize) -> Result<&'a [u8], WireError> {
+        let end = self.pos.checked_add(n_bytes).ok_or(WireError::UnexpectedEnd)?;
+        let bytes = self.data.get(self.pos..end).ok_or(WireError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Read a length prefix, which can't be larger than the remaining data
+    pub fn length(&mut self) -> Result<usize, WireError> {
+        let length = u32::decode(self)? as usize;
+        if length > self.remaining() {
+            Err(WireError::UnexpectedEnd)
+        } else {
+            Ok(length)
+        }
+    }
+
+    /// The number of bytes that haven't been read yet
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+}
+
+/// A defined, architecture-independent encoding of a type,
+/// used for messages sent between machines.
+///
+/// Numbers are encoded as fixed-width little-endian values (`usize` and `isize` as 64 bit),
+/// dynamically-sized parts like `CVec`s and `CString`s are prefixed with their length.
+/// The receiver decodes messages into the `Compact` layout of its own architecture.
+///
+/// Message types that aren't `Portable` can only be sent to actors on the same machine -
+/// sending them to other machines results in a `DeadLetter` (see `DeadLetterReason::NotPortable`).
+/// Structs and enums can derive it with `#[derive(Portable)]` if all their fields are `Portable`.
+pub trait Portable: Compact {
+    /// A lower bound of how many bytes the encoding of a value takes up,
+    /// which limits how many items a received `CVec` can claim to have
+    const MIN_ENCODED_SIZE: usize = 0;
+    /// Append the portable encoding of `self` to `out`
+    fn encode(&self, out: &mut Vec<u8>);
+    /// Decode a value from its portable encoding
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError>;
+}
+
+macro_rules! portable_numbers {
+    ($($number:ty, $write:ident, $read:ident);*) => {
+        $(
+            impl Portable for $number {
+                const MIN_ENCODED_SIZE: usize = ::std::mem::size_of::<$number>();
+
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.$write::<LittleEndian>(*self).unwrap();
+                }
+
+                fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+                    Ok(LittleEndian::$read(reader.take(::std::mem::size_of::<$number>())?))
+                }
+            }
+        )*
+    };
+}
+
+portable_numbers!(
+    u16, write_u16, read_u16;
+    u32, write_u32, read_u32;
+    u64, write_u64, read_u64;
+    i16, write_i16, read_i16;
+    i32, write_i32, read_i32;
+    i64, write_i64, read_i64;
+    f32, write_f32, read_f32;
+    f64, write_f64, read_f64
+);
+
+impl Portable for u8 {
+    const MIN_ENCODED_SIZE: usize = 1;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        Ok(reader.take(1)?[0])
+    }
+}
+
+impl Portable for i8 {
+    const MIN_ENCODED_SIZE: usize = 1;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        Ok(reader.take(1)?[0] as i8)
+    }
+}
+
+impl Portable for usize {
+    const MIN_ENCODED_SIZE: usize = 8;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        let value = u64::decode(reader)?;
+        if value > usize::max_value() as u64 {
+            Err(WireError::Invalid("usize"))
+        } else {
+            Ok(value as usize)
+        }
+    }
+}
+
+impl Portable for isize {
+    const MIN_ENCODED_SIZE: usize = 8;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as i64).encode(out);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        let value = i64::decode(reader)?;
+        if value > isize::max_value() as i64 || value < isize::min_value() as i64 {
+            Err(WireError::Invalid("isize"))
+        } else {
+            Ok(value as isize)
+        }
+    }
+}
+
+impl Portable for bool {
+    const MIN_ENCODED_SIZE: usize = 1;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(WireError::Invalid("bool")),
+        }
+    }
+}
+
+impl Portable for char {
+    const MIN_ENCODED_SIZE: usize = 4;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u32).encode(out);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        ::std::char::from_u32(u32::decode(reader)?).ok_or(WireError::Invalid("char"))
+    }
+}
+
+impl Portable for () {
+    fn encode(&self, _out: &mut Vec<u8>) {}
+
+    fn decode(_reader: &mut WireReader) -> Result<Self, WireError> {
+        Ok(())
+    }
+}
+
+impl<T: Portable> Portable for COption<T> {
+    const MIN_ENCODED_SIZE: usize = 1;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self.0 {
+            Some(ref value) => {
+                out.push(1);
+                value.encode(out);
+            }
+            None => out.push(0),
+        }
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        match u8::decode(reader)? {
+            0 => Ok(COption(None)),
+            1 => Ok(COption(Some(T::decode(reader)?))),
+            _ => Err(WireError::Invalid("option tag")),
+        }
+    }
+}
+
+/// How many zero-sized items a received `CVec` can have at most
+const MAX_ZERO_SIZED_ITEMS: usize = 1 << 16;
+
+impl<T: Portable> Portable for CVec<T> {
+    const MIN_ENCODED_SIZE: usize = 4;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.write_u32::<LittleEndian>(self.len() as u32).unwrap();
+        for item in self.iter() {
+            item.encode(out);
+        }
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        let len = u32::decode(reader)? as usize;
+        if ::std::mem::size_of::<T>() == 0 {
+            // zero-sized items don't need memory, but decoding too many still takes forever
+            if len > MAX_ZERO_SIZED_ITEMS {
+                return Err(WireError::Invalid("number of zero-sized items"));
+            }
+        } else if len > reader.remaining() / ::std::cmp::max(T::MIN_ENCODED_SIZE, 1) {
+            // every other item takes up at least one byte
+            return Err(WireError::UnexpectedEnd);
+        }
+        let mut items = CVec::with_capacity(len);
+        for _ in 0..len {
+            items.push(T::decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Portable for CString {
+    const MIN_ENCODED_SIZE: usize = 4;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.write_u32::<LittleEndian>(self.len() as u32).unwrap();
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        let len = reader.length()?;
+        let string = ::std::str::from_utf8(reader.take(len)?)
+            .map_err(|_| WireError::Invalid("UTF-8 string"))?;
+        Ok(CString::from(string.to_owned()))
+    }
+}
+
+impl Portable for ShortTypeId {
+    const MIN_ENCODED_SIZE: usize = 2;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_u16().encode(out);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        ShortTypeId::new(u16::decode(reader)?).ok_or(WireError::Invalid("type ID"))
+    }
+}
+
+impl Portable for MachineID {
+    const MIN_ENCODED_SIZE: usize = 1;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.0);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        Ok(MachineID(u8::decode(reader)?))
+    }
+}
+
+impl Portable for RawID {
+    const MIN_ENCODED_SIZE: usize = 8;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.instance_id.encode(out);
+        self.type_id.encode(out);
+        self.machine.encode(out);
+        self.version.encode(out);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        let instance_id = u32::decode(reader)?;
+        let type_id = ShortTypeId::decode(reader)?;
+        let machine = MachineID::decode(reader)?;
+        let version = u8::decode(reader)?;
+        Ok(RawID::new(type_id, instance_id, machine, version))
+    }
+}
+
+impl<M: Message + Portable> Portable for Packet<M> {
+    const MIN_ENCODED_SIZE: usize = RawID::MIN_ENCODED_SIZE + M::MIN_ENCODED_SIZE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.recipient_id.encode(out);
+        self.message.encode(out);
+    }
+
+    fn decode(reader: &mut WireReader) -> Result<Self, WireError> {
+        Ok(Packet {
+            recipient_id: RawID::decode(reader)?,
+            message: M::decode(reader)?,
+        })
+    }
+}
+
+/// Converts packets of one message type between the local `Compact`
+/// layout and the portable wire encoding, without knowing the message type statically
+#[derive(Clone, Copy)]
+pub struct WireCodec {
+    /// Append the portable encoding of a packet in local layout
+    pub encode: fn(*const (), &mut Vec<u8>),
+    /// Decode a packet into a buffer in local layout
+    pub decode: fn(&mut WireReader) -> Result<Vec<u8>, WireError>,
+}
+
+impl WireCodec {
+    pub fn of<M: Message + Portable>() -> WireCodec {
+        WireCodec {
+            encode: encode_packet::<M>,
+            decode: decode_packet::<M>,
+        }
+    }
+}
+
+/// Gets the `WireCodec` of message types that are `Portable`,
+/// so messages that aren't can still be sent locally
+pub(crate) trait MaybePortable {
+    fn wire_codec() -> Option<WireCodec>;
+}
+
+impl<M: Message> MaybePortable for M {
+    default fn wire_codec() -> Option<WireCodec> {
+        None
+    }
+}
+
+impl<M: Message + Portable> MaybePortable for M {
+    fn wire_codec() -> Option<WireCodec> {
+        Some(WireCodec::of::<M>())
+    }
+}
+
+fn encode_packet<M: Message + Portable>(packet_ptr: *const (), out: &mut Vec<u8>) {
+    let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+    packet.encode(out);
+}
+
+fn decode_packet<M: Message + Portable>(reader: &mut WireReader) -> Result<Vec<u8>, WireError> {
+    let mut packet = Packet::<M>::decode(reader)?;
+    let size = packet.total_size_bytes();
+    let mut aligned = vec![0u64; (size + 7) / 8];
+    unsafe {
+        Compact::compact_behind(&mut packet, aligned.as_mut_ptr() as *mut Packet<M>);
+    }
+    ::std::mem::forget(packet);
+    let bytes = unsafe { ::std::slice::from_raw_parts(aligned.as_ptr() as *const u8, size) };
+    Ok(bytes.to_vec())
+}
+
+/// Copy a packet in local layout (which might not be aligned, for example
+/// right after its message type) so it can be read in place
+pub(crate) fn aligned_copy(packet: &[u8]) -> Vec<u64> {
+    let mut aligned = vec![0u64; (packet.len() + 7) / 8];
+    unsafe {
+        ::std::ptr::copy_nonoverlapping(packet.as_ptr(), aligned.as_mut_ptr() as *mut u8, packet.len());
+    }
+    aligned
+}
+
+#[cfg(test)]
+fn round_trip<T: Portable>(value: &T) -> T {
+    let mut encoded = Vec::new();
+    value.encode(&mut encoded);
+    let mut reader = WireReader::new(&encoded);
+    let decoded = T::decode(&mut reader).unwrap();
+    assert_eq!(reader.remaining(), 0);
+    decoded
+}
+
+#[test]
+fn test_numbers_and_primitives_round_trip() {
+    assert_eq!(round_trip(&0x1234u16), 0x1234);
+    assert_eq!(round_trip(&-7i64), -7);
+    assert_eq!(round_trip(&1.5f32).to_bits(), 1.5f32.to_bits());
+    assert_eq!(round_trip(&usize::max_value()), usize::max_value());
+    assert_eq!(round_trip(&true), true);
+    assert_eq!(round_trip(&'ä'), 'ä');
+
+    let mut encoded = Vec::new();
+    0x0102_0304u32.encode(&mut encoded);
+    assert_eq!(encoded, vec![4, 3, 2, 1]);
+}
+
+#[test]
+fn test_invalid_primitives_are_rejected() {
+    assert_eq!(bool::decode(&mut WireReader::new(&[2])).err(), Some(WireError::Invalid("bool")));
+    assert_eq!(
+        char::decode(&mut WireReader::new(&[0x00, 0xD8, 0, 0])).err(),
+        Some(WireError::Invalid("char"))
+    );
+    assert_eq!(u32::decode(&mut WireReader::new(&[1, 2, 3])).err(), Some(WireError::UnexpectedEnd));
+    assert_eq!(
+        COption::<u8>::decode(&mut WireReader::new(&[7])).err(),
+        Some(WireError::Invalid("option tag"))
+    );
+}
+
+#[test]
+fn test_dynamic_types_round_trip() {
+    let numbers: CVec<u32> = vec![1, 2, 3].into();
+    assert_eq!(round_trip(&numbers).to_vec(), vec![1, 2, 3]);
+
+    let text = CString::from("Grüße".to_owned());
+    assert_eq!(&*round_trip(&text), "Grüße");
+
+    let nested: CVec<CString> = vec![CString::from("a".to_owned()), CString::from("bc".to_owned())].into();
+    let decoded = round_trip(&nested);
+    assert_eq!(decoded.iter().map(|string| string.to_string()).collect::<Vec<_>>(), vec!["a", "bc"]);
+
+    assert_eq!(round_trip(&COption(Some(5u8))).0, Some(5));
+    assert_eq!(round_trip(&COption::<u8>(None)).0, None);
+}
+
+#[test]
+fn test_lengths_beyond_the_data_are_rejected() {
+    // claims 1000 items, but only has one
+    let data = [0xE8, 0x03, 0, 0, 1, 0, 0, 0];
+    assert_eq!(CVec::<u32>::decode(&mut WireReader::new(&data)).err(), Some(WireError::UnexpectedEnd));
+    // claims 2 items of at least 4 bytes, but only has 5 bytes
+    let two_items = [2, 0, 0, 0, 1, 0, 0, 0, 2];
+    assert_eq!(CVec::<u32>::decode(&mut WireReader::new(&two_items)).err(), Some(WireError::UnexpectedEnd));
+    assert_eq!(CVec::<u8>::decode(&mut WireReader::new(&two_items)).map(|items| items.to_vec()), Ok(vec![1, 0]));
+    // zero-sized items take up no data at all
+    assert_eq!(CVec::<()>::decode(&mut WireReader::new(&[3, 0, 0, 0])).map(|items| items.len()), Ok(3));
+    assert_eq!(
+        CVec::<()>::decode(&mut WireReader::new(&[0xFF, 0xFF, 0xFF, 0xFF])).err(),
+        Some(WireError::Invalid("number of zero-sized items"))
+    );
+    assert_eq!(CString::decode(&mut WireReader::new(&data)).err(), Some(WireError::UnexpectedEnd));
+    assert_eq!(
+        CString::decode(&mut WireReader::new(&[2, 0, 0, 0, 0xC3, 0x28])).err(),
+        Some(WireError::Invalid("UTF-8 string"))
+    );
+}
+
+#[test]
+fn test_derived_enums_round_trip() {
+    #[derive(Copy, Clone, PartialEq, Debug, Portable)]
+    enum Shape {
+        Point,
+        Circle(f32),
+        Rectangle { width: u16, height: u16 },
+    }
+
+    for shape in &[Shape::Point, Shape::Circle(2.5), Shape::Rectangle { width: 3, height: 4 }] {
+        assert_eq!(round_trip(shape), *shape);
+    }
+    assert_eq!(
+        Shape::decode(&mut WireReader::new(&[3])).err(),
+        Some(WireError::Invalid("Shape variant"))
+    );
+}
+
+#[test]
+fn test_packets_with_dynamic_parts_decode_into_local_layout() {
+    #[derive(Compact, Clone, Portable)]
+    struct Route {
+        name: CString,
+        stops: CVec<u32>,
+        express: bool,
+    }
+
+    let recipient = RawID::new(ShortTypeId::new(3).unwrap(), 7, MachineID(1), 2);
+    let packet = Packet {
+        recipient_id: recipient,
+        message: Route {
+            name: CString::from("Ring".to_owned()),
+            stops: vec![4, 8, 15].into(),
+            express: true,
+        },
+    };
+
+    let codec = WireCodec::of::<Route>();
+    let mut encoded = Vec::new();
+    (codec.encode)(&packet as *const Packet<Route> as *const (), &mut encoded);
+    let buffer = aligned_copy(&(codec.decode)(&mut WireReader::new(&encoded)).unwrap());
+
+    let decoded = unsafe { &*(buffer.as_ptr() as *const Packet<Route>) };
+    assert!(decoded.recipient_id == recipient);
+    assert_eq!(&*decoded.message.name, "Ring");
+    assert_eq!(decoded.message.stops.to_vec(), vec![4, 8, 15]);
+    assert!(decoded.message.express);
+}
@@ -0,0 +1,167 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads that take on jobs as they come in
+pub struct WorkerPool {
+    job_sender: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(n_threads: usize) -> WorkerPool {
+        let (job_sender, job_receiver) = channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let threads = (0..n_threads)
+            .map(|i| {
+                let job_receiver = Arc::clone(&job_receiver);
+                thread::Builder::new()
+                    .name(format!("kay_worker_{}", i))
+                    .spawn(move || loop {
+                        // the lock is only held while waiting for the next job
+                        let next_job = job_receiver.lock().unwrap().recv();
+                        match next_job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("Couldn't spawn worker thread")
+            })
+            .collect();
+
+        WorkerPool {
+            job_sender: Some(job_sender),
+            threads,
+        }
+    }
+
+    /// Run all `jobs` on the worker threads and block until every one of them is done.
+    /// Since that is also the case if some of them panic, jobs can borrow from the caller.
+    /// Returns the payload of one of the panics, if any happened.
+    pub fn scope<'a>(&self, jobs: Vec<Box<dyn FnOnce() + Send + 'a>>) -> thread::Result<()> {
+        let (done_sender, done_receiver) = channel();
+        let mut running = RunningJobs {
+            done_receiver,
+            n_running: 0,
+            result: Ok(()),
+        };
+
+        for job in jobs {
+            // Safety: `running` waits for the job to finish before this function is left,
+            // even when unwinding, so the job can't outlive anything it borrows
+            let job = unsafe { ::std::mem::transmute::<Box<dyn FnOnce() + Send + 'a>, Job>(job) };
+            let done_sender = done_sender.clone();
+
+            self.job_sender
+                .as_ref()
+                .expect("Worker pool should still be running")
+                .send(Box::new(move || {
+                    let result = catch_unwind(AssertUnwindSafe(job));
+                    let _ = done_sender.send(result);
+                }))
+                .expect("Worker threads should still be running");
+            running.n_running += 1;
+        }
+
+        running.wait()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // closing the job channel makes the worker threads leave their loops
+        self.job_sender = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Waits for jobs given to the worker threads in `WorkerPool::scope`,
+/// at the latest when it is dropped
+struct RunningJobs {
+    done_receiver: Receiver<thread::Result<()>>,
+    n_running: usize,
+    result: thread::Result<()>,
+}
+
+impl RunningJobs {
+    fn wait(mut self) -> thread::Result<()> {
+        self.wait_for_all();
+        ::std::mem::replace(&mut self.result, Ok(()))
+    }
+
+    fn wait_for_all(&mut self) {
+        while self.n_running > 0 {
+            let result = self
+                .done_receiver
+                .recv()
+                .expect("Jobs always report back once they are done");
+            self.n_running -= 1;
+            if self.result.is_ok() {
+                self.result = result;
+            }
+        }
+    }
+}
+
+impl Drop for RunningJobs {
+    fn drop(&mut self) {
+        self.wait_for_all();
+    }
+}
+
+#[test]
+fn test_scoped_jobs_run_on_several_threads_and_borrow_from_the_caller() {
+    use std::sync::Barrier;
+
+    let pool = WorkerPool::new(2);
+    // both jobs only finish once they run at the same time
+    let barrier = Barrier::new(2);
+    let mut thread_ids = vec![None, None];
+
+    let jobs = thread_ids
+        .iter_mut()
+        .map(|thread_id| {
+            let barrier = &barrier;
+            Box::new(move || {
+                barrier.wait();
+                *thread_id = Some(thread::current().id());
+            }) as Box<dyn FnOnce() + Send + '_>
+        })
+        .collect();
+
+    assert!(pool.scope(jobs).is_ok());
+    assert!(thread_ids[0].is_some());
+    assert_ne!(thread_ids[0], thread_ids[1]);
+    assert_ne!(thread_ids[0], Some(thread::current().id()));
+}
+
+#[test]
+fn test_panicking_jobs_are_reported_after_all_jobs_are_done() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let pool = WorkerPool::new(2);
+    let n_done = AtomicUsize::new(0);
+
+    let jobs = (0..4)
+        .map(|i| {
+            let n_done = &n_done;
+            Box::new(move || {
+                if i == 1 {
+                    panic!("Job failed");
+                }
+                n_done.fetch_add(1, Ordering::SeqCst);
+            }) as Box<dyn FnOnce() + Send + '_>
+        })
+        .collect();
+
+    assert!(pool.scope(jobs).is_err());
+    assert_eq!(n_done.load(Ordering::SeqCst), 3);
+    // the pool stays usable
+    assert!(pool.scope(vec![Box::new(|| {}) as Box<dyn FnOnce() + Send>]).is_ok());
+}